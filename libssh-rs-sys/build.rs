@@ -65,6 +65,7 @@ fn main() {
     cfg.define("WITH_ZLIB", Some("1"));
     cfg.define("WITH_GEX", Some("1"));
     cfg.define("WITH_SFTP", Some("1"));
+    cfg.define("WITH_SERVER", Some("1"));
 
     if target.contains("windows") {
         cfg.define("HAVE_IO_H", Some("1"));
@@ -166,6 +167,8 @@ fn main() {
         "agent.c",
         "auth.c",
         "base64.c",
+        "bind.c",
+        "bind_config.c",
         "bignum.c",
         "buffer.c",
         "callbacks.c",
@@ -220,8 +223,10 @@ fn main() {
         "pki_ed25519_common.c",
         "poll.c",
         "scp.c",
+        "server.c",
         "session.c",
         "sftp.c",
+        "sftpserver.c",
         "socket.c",
         "string.c",
         "threads.c",
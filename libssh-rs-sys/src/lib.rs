@@ -2735,6 +2735,12 @@ extern "C" {
 extern "C" {
     pub fn sftp_server_version(sftp: sftp_session) -> ::std::os::raw::c_int;
 }
+extern "C" {
+    pub fn sftp_server_new(session: ssh_session, chan: ssh_channel) -> sftp_session;
+}
+extern "C" {
+    pub fn sftp_server_init(sftp: sftp_session) -> ::std::os::raw::c_int;
+}
 extern "C" {
     pub fn sftp_get_client_message(sftp: sftp_session) -> sftp_client_message;
 }
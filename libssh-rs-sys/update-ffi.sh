@@ -31,6 +31,7 @@ bindgen \
   --allowlist-var 'SSH.*' \
   --verbose \
   -- \
-  -Ivendored/include 
+  -Ivendored/include \
+  -DWITH_SERVER
 
 rm vendored/include/libssh/libssh_version.h
@@ -0,0 +1,273 @@
+use crate::cert::{put_string, Reader};
+use crate::{AuthStatus, Channel, Error, Session, SshKey, SshResult};
+use libssh_rs_sys as sys;
+use std::io::{Read, Write};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH2_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+/// Agents limit messages to 256KiB
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// A key held by an ssh agent
+#[derive(Debug, Clone)]
+pub struct AgentIdentity {
+    key: SshKey,
+    blob: Vec<u8>,
+    comment: String,
+}
+
+impl AgentIdentity {
+    /// Returns the public key, or certificate, of the identity
+    pub fn key(&self) -> &SshKey {
+        &self.key
+    }
+
+    /// Returns the comment, which is typically the
+    /// file name that the key was loaded from
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+}
+
+/// A client for the ssh agent protocol, used to select a specific
+/// agent identity for authentication.
+pub struct Agent {
+    path: PathBuf,
+    stream: Mutex<UnixStream>,
+}
+
+impl Agent {
+    /// Connects to the agent at the path named by the
+    /// `SSH_AUTH_SOCK` environment variable
+    pub fn connect() -> SshResult<Self> {
+        let path = std::env::var_os("SSH_AUTH_SOCK")
+            .ok_or_else(|| Error::fatal("SSH_AUTH_SOCK is not set"))?;
+        Self::connect_path(path)
+    }
+
+    /// Connects to the agent listening on the unix socket `path`
+    pub fn connect_path<P: AsRef<Path>>(path: P) -> SshResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let stream = UnixStream::connect(&path).map_err(|err| {
+            Error::Fatal(format!(
+                "failed to connect to agent at {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        Ok(Self {
+            path,
+            stream: Mutex::new(stream),
+        })
+    }
+
+    /// Lists the identities held by the agent.
+    /// Keys of types that are not supported by libssh are omitted.
+    pub fn identities(&self) -> SshResult<Vec<AgentIdentity>> {
+        let mut stream = self.stream.lock().unwrap();
+        write_message(&mut *stream, &[SSH2_AGENTC_REQUEST_IDENTITIES])?;
+        let reply = read_message(&mut *stream)?
+            .ok_or_else(|| Error::fatal("the agent closed the connection"))?;
+        parse_identities(&reply)
+    }
+
+    /// Authenticates `session` using only `identity`, rather than
+    /// trying each of the agent identities in turn as
+    /// [Session::userauth_agent](struct.Session.html#method.userauth_agent)
+    /// does, which can exceed the server's `MaxAuthTries`.
+    ///
+    /// This replaces the agent used by the session with one that
+    /// only offers `identity`, for the lifetime of the session.
+    pub fn authenticate(
+        &self,
+        session: &Session,
+        username: Option<&str>,
+        identity: &AgentIdentity,
+    ) -> SshResult<AuthStatus> {
+        let agent = UnixStream::connect(&self.path)?;
+        let (ours, theirs) = UnixStream::pair()?;
+        let blob = identity.blob.clone();
+        let comment = identity.comment.clone();
+        std::thread::spawn(move || filter_agent(ours, agent, &blob, &comment));
+
+        session.set_agent_socket(theirs.into_raw_fd())?;
+        session.userauth_agent(username)
+    }
+}
+
+impl Session {
+    /// Use the agent connected to `fd` for agent authentication,
+    /// rather than the one named by `SSH_AUTH_SOCK` or the
+    /// `IdentityAgent` option.
+    /// The session takes ownership of `fd`, and closes it when freed.
+    pub fn set_agent_socket(&self, fd: RawFd) -> SshResult<()> {
+        let sess = self.lock_session();
+        let res = unsafe { sys::ssh_set_agent_socket(**sess, fd) };
+        if res == sys::SSH_OK as i32 {
+            Ok(())
+        } else if let Some(err) = sess.last_error() {
+            Err(err)
+        } else {
+            Err(Error::fatal("failed to set agent socket"))
+        }
+    }
+
+    /// Use the agent that is reachable through `channel` for agent
+    /// authentication, such as an agent forwarded to a server.
+    /// `channel` must remain open for as long as this session may
+    /// use the agent, and the session that it belongs to must not
+    /// be used by another thread while this one is authenticating.
+    pub fn set_agent_channel(&self, channel: &Channel) -> SshResult<()> {
+        let sess = self.lock_session();
+        let res = unsafe { sys::ssh_set_agent_channel(**sess, channel.chan_inner) };
+        if res == sys::SSH_OK as i32 {
+            Ok(())
+        } else if let Some(err) = sess.last_error() {
+            Err(err)
+        } else {
+            Err(Error::fatal("failed to set agent channel"))
+        }
+    }
+}
+
+/// Relays agent requests from `client` to `agent`, answering
+/// identity requests with just the identity `blob`.
+/// Exits when either side closes its connection.
+fn filter_agent(mut client: UnixStream, mut agent: UnixStream, blob: &[u8], comment: &str) {
+    let mut answer = vec![SSH2_AGENT_IDENTITIES_ANSWER];
+    answer.extend_from_slice(&1u32.to_be_bytes());
+    put_string(&mut answer, blob);
+    put_string(&mut answer, comment.as_bytes());
+
+    loop {
+        let request = match read_message(&mut client) {
+            Ok(Some(request)) => request,
+            _ => return,
+        };
+        let reply = if request.first() == Some(&SSH2_AGENTC_REQUEST_IDENTITIES) {
+            answer.clone()
+        } else {
+            if write_message(&mut agent, &request).is_err() {
+                return;
+            }
+            match read_message(&mut agent) {
+                Ok(Some(reply)) => reply,
+                _ => vec![SSH_AGENT_FAILURE],
+            }
+        };
+        if write_message(&mut client, &reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads a length prefixed agent message, returning `None`
+/// if the connection was closed before the message started
+fn read_message<R: Read>(stream: &mut R) -> SshResult<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(Error::Fatal(format!(
+            "invalid agent message length {}",
+            len
+        )));
+    }
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &[u8]) -> SshResult<()> {
+    let mut data = Vec::with_capacity(message.len() + 4);
+    put_string(&mut data, message);
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+fn parse_identity_blobs(reply: &[u8]) -> SshResult<Vec<(Vec<u8>, String)>> {
+    match reply.split_first() {
+        Some((&SSH2_AGENT_IDENTITIES_ANSWER, body)) => {
+            let mut reader = Reader::new(body);
+            let count = reader.read_u32()?;
+            let mut identities = vec![];
+            for _ in 0..count {
+                let blob = reader.read_string()?.to_vec();
+                let comment = String::from_utf8_lossy(reader.read_string()?).to_string();
+                identities.push((blob, comment));
+            }
+            Ok(identities)
+        }
+        Some((&SSH_AGENT_FAILURE, _)) => Err(Error::fatal("the agent refused to list identities")),
+        _ => Err(Error::fatal("unexpected reply from the agent")),
+    }
+}
+
+fn parse_identities(reply: &[u8]) -> SshResult<Vec<AgentIdentity>> {
+    Ok(parse_identity_blobs(reply)?
+        .into_iter()
+        .filter_map(|(blob, comment)| {
+            let key = SshKey::from_pubkey_blob(&blob).ok()?;
+            Some(AgentIdentity { key, blob, comment })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identities_answer() {
+        let mut reply = vec![SSH2_AGENT_IDENTITIES_ANSWER];
+        reply.extend_from_slice(&2u32.to_be_bytes());
+        put_string(&mut reply, b"first key");
+        put_string(&mut reply, b"id_ed25519");
+        put_string(&mut reply, b"second key");
+        put_string(&mut reply, b"");
+        assert_eq!(
+            parse_identity_blobs(&reply).unwrap(),
+            vec![
+                (b"first key".to_vec(), "id_ed25519".to_string()),
+                (b"second key".to_vec(), String::new()),
+            ]
+        );
+        assert!(parse_identity_blobs(&reply[..reply.len() - 1]).is_err());
+        assert!(parse_identity_blobs(&[SSH_AGENT_FAILURE]).is_err());
+    }
+
+    #[test]
+    fn filtering() {
+        let (mut client, filter_client) = UnixStream::pair().unwrap();
+        let (filter_agent_end, mut agent) = UnixStream::pair().unwrap();
+        let thread = std::thread::spawn(move || {
+            filter_agent(filter_client, filter_agent_end, b"chosen", "comment")
+        });
+
+        write_message(&mut client, &[SSH2_AGENTC_REQUEST_IDENTITIES]).unwrap();
+        let reply = read_message(&mut client).unwrap().unwrap();
+        assert_eq!(
+            parse_identity_blobs(&reply).unwrap(),
+            vec![(b"chosen".to_vec(), "comment".to_string())]
+        );
+
+        // Other requests are passed through to the agent
+        write_message(&mut client, &[13, 1, 2, 3]).unwrap();
+        assert_eq!(read_message(&mut agent).unwrap().unwrap(), [13, 1, 2, 3]);
+        write_message(&mut agent, &[14, 4]).unwrap();
+        assert_eq!(read_message(&mut client).unwrap().unwrap(), [14, 4]);
+
+        drop(client);
+        thread.join().unwrap();
+    }
+}
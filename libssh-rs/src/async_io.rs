@@ -0,0 +1,957 @@
+use crate::{
+    AuthStatus, Channel, Error, Metadata, PollStatus, Session, SetAttributes, Sftp, SftpFile,
+    SshKey, SshResult,
+};
+use libssh_rs_sys as sys;
+use std::future::Future;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, Interest, ReadBuf};
+use tokio::task::{JoinError, JoinHandle};
+
+/// A `Session` that is driven by the tokio reactor.
+///
+/// The session is placed into non-blocking mode, and operations that
+/// would otherwise block in libssh instead wait for the session socket
+/// to become ready before trying again.  This allows many sessions and
+/// channels to be serviced without dedicating a thread to each of them.
+///
+/// `AsyncSession` is cheap to clone; clones refer to the same session.
+///
+/// libssh has no non-blocking SFTP client, so the operations of
+/// [AsyncSftp](struct.AsyncSftp.html) run on tokio's blocking thread pool,
+/// with the session switched to blocking mode while they do.
+/// Operations on the channels of the session wait until no SFTP
+/// operation is in progress, rather than blocking the thread that
+/// polls them, so they make no progress in the meantime; use a
+/// separate session for SFTP if that is a problem.
+/// The same applies to methods of the underlying
+/// [session](#method.session) and [channel](struct.AsyncChannel.html#method.channel),
+/// which must not be called while an SFTP operation is in progress,
+/// as they would block.
+#[derive(Clone)]
+pub struct AsyncSession {
+    inner: Arc<AsyncInner>,
+}
+
+struct AsyncInner {
+    session: Session,
+    fd: Mutex<Option<Arc<AsyncFd<SessionFd>>>>,
+    waiters: Mutex<Waiters>,
+    /// The number of blocking operations that are in progress
+    blocking: Mutex<usize>,
+}
+
+/// Tasks that are waiting for input to arrive on the session.
+/// Reading from the session for one channel can consume the data
+/// destined for another, so whenever that happens all of the waiting
+/// tasks are woken to check again.
+struct Waiters {
+    generation: u64,
+    wakers: Vec<Waker>,
+}
+
+impl Waiters {
+    /// Starts a new generation, returning the wakers of the tasks
+    /// that were waiting for it
+    fn advance(&mut self) -> Vec<Waker> {
+        self.generation += 1;
+        std::mem::take(&mut self.wakers)
+    }
+
+    /// Arranges for `waker` to be returned by the next `advance`,
+    /// unless there has been one since `generation`
+    fn register(&mut self, generation: u64, waker: &Waker) -> bool {
+        if self.generation != generation {
+            return false;
+        }
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+        true
+    }
+}
+
+struct SessionFd(RawFd);
+
+impl AsRawFd for SessionFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Returns true if `fd` is ready for any of `events` right now
+fn fd_ready(fd: RawFd, events: libc::c_short) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) > 0 && pfd.revents != 0 }
+}
+
+fn join_result<T>(result: Result<T, JoinError>) -> std::io::Result<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(std::io::Error::other(err)),
+    }
+}
+
+impl AsyncInner {
+    fn async_fd(&self) -> std::io::Result<Arc<AsyncFd<SessionFd>>> {
+        let raw = self.session.as_raw_fd();
+        if raw < 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "session has no socket",
+            ));
+        }
+        let mut fd = self.fd.lock().unwrap();
+        if let Some(fd) = fd.as_ref() {
+            if fd.get_ref().0 == raw {
+                return Ok(Arc::clone(fd));
+            }
+        }
+        let async_fd = Arc::new(AsyncFd::with_interest(
+            SessionFd(raw),
+            Interest::READABLE | Interest::WRITABLE,
+        )?);
+        fd.replace(Arc::clone(&async_fd));
+        Ok(async_fd)
+    }
+
+    fn generation(&self) -> u64 {
+        self.waiters.lock().unwrap().generation
+    }
+
+    fn wake_all(&self) {
+        let wakers = self.waiters.lock().unwrap().advance();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Arranges for `waker` to be woken by the next `wake_all`.
+    /// Returns false if there has been a `wake_all` since `generation`
+    /// was obtained, in which case the caller should try again instead.
+    fn register(&self, generation: u64, waker: &Waker) -> bool {
+        self.waiters.lock().unwrap().register(generation, waker)
+    }
+
+    fn is_blocking(&self) -> bool {
+        *self.blocking.lock().unwrap() > 0
+    }
+
+    /// Calls `f` until it returns something other than `Error::TryAgain`,
+    /// waiting for the session socket to become ready in between.
+    /// `f` is not called while a blocking operation is in progress, as
+    /// it would block too; the blocking operation wakes all waiting
+    /// tasks when it completes.
+    fn poll_io<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: &mut dyn FnMut() -> SshResult<T>,
+    ) -> Poll<SshResult<T>> {
+        loop {
+            let generation = self.generation();
+            if self.is_blocking() {
+                if self.register(generation, cx.waker()) {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+            let raw = self.session.as_raw_fd();
+            let had_input = raw >= 0 && fd_ready(raw, libc::POLLIN);
+
+            let res = f();
+            if had_input {
+                self.wake_all();
+            }
+            match res {
+                Err(Error::TryAgain) => {}
+                res => return Poll::Ready(res),
+            }
+
+            if !self.register(generation, cx.waker()) {
+                continue;
+            }
+
+            let fd = self.async_fd()?;
+            let (_read_pending, write_pending) = self.session.get_poll_state();
+            let (ready, events) = if write_pending {
+                (fd.poll_write_ready(cx), libc::POLLOUT)
+            } else {
+                (fd.poll_read_ready(cx), libc::POLLIN)
+            };
+            match ready {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                Poll::Ready(Ok(mut guard)) => {
+                    if fd_ready(fd.get_ref().0, events) {
+                        // libssh didn't take what was available; give
+                        // other tasks a chance to run before trying again
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    guard.clear_ready();
+                }
+            }
+        }
+    }
+}
+
+/// Switches the session into blocking mode for as long as
+/// at least one blocking operation is in progress.
+/// Other tasks wait for it to be dropped before using the session,
+/// rather than blocking the threads that poll them.
+struct BlockingMode<'a> {
+    inner: &'a AsyncInner,
+}
+
+impl<'a> BlockingMode<'a> {
+    fn enter(inner: &'a AsyncInner) -> Self {
+        let mut count = inner.blocking.lock().unwrap();
+        if *count == 0 {
+            inner.session.set_blocking(true);
+        }
+        *count += 1;
+        Self { inner }
+    }
+}
+
+impl<'a> Drop for BlockingMode<'a> {
+    fn drop(&mut self) {
+        let mut count = self.inner.blocking.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.inner.session.set_blocking(false);
+        }
+    }
+}
+
+impl AsyncSession {
+    /// Wrap `session`, switching it to non-blocking mode.
+    pub fn new(session: Session) -> Self {
+        session.set_blocking(false);
+        Self {
+            inner: Arc::new(AsyncInner {
+                session,
+                fd: Mutex::new(None),
+                waiters: Mutex::new(Waiters {
+                    generation: 0,
+                    wakers: vec![],
+                }),
+                blocking: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// Returns the underlying session, which can be used to set
+    /// options and perform other operations that don't block.
+    pub fn session(&self) -> &Session {
+        &self.inner.session
+    }
+
+    async fn io<T>(&self, mut f: impl FnMut() -> SshResult<T>) -> SshResult<T> {
+        std::future::poll_fn(|cx| self.inner.poll_io(cx, &mut f)).await
+    }
+
+    async fn auth(&self, mut f: impl FnMut() -> SshResult<AuthStatus>) -> SshResult<AuthStatus> {
+        self.io(|| match f()? {
+            AuthStatus::Again => Err(Error::TryAgain),
+            status => Ok(status),
+        })
+        .await
+    }
+
+    /// Runs `f` on the blocking thread pool, with the session
+    /// in blocking mode
+    fn spawn_blocking<T, F>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || {
+            let result = {
+                let _blocking = BlockingMode::enter(&inner);
+                f()
+            };
+            // Other tasks may be waiting for the operation to complete,
+            // or for input that it consumed
+            inner.wake_all();
+            result
+        })
+    }
+
+    async fn run_blocking<T, F>(&self, f: F) -> SshResult<T>
+    where
+        F: FnOnce() -> SshResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        join_result(self.spawn_blocking(f).await)?
+    }
+
+    /// Connect to the configured remote host
+    pub async fn connect(&self) -> SshResult<()> {
+        self.io(|| self.inner.session.connect()).await
+    }
+
+    /// Disconnect from the remote host
+    pub fn disconnect(&self) {
+        self.inner.session.disconnect()
+    }
+
+    /// Try to authenticate using the `"none"` method.
+    /// See [Session::userauth_none](struct.Session.html#method.userauth_none).
+    pub async fn userauth_none(&self, username: Option<&str>) -> SshResult<AuthStatus> {
+        self.auth(|| self.inner.session.userauth_none(username))
+            .await
+    }
+
+    /// Try to authenticate using a password.
+    /// See [Session::userauth_password](struct.Session.html#method.userauth_password).
+    pub async fn userauth_password(
+        &self,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> SshResult<AuthStatus> {
+        self.auth(|| self.inner.session.userauth_password(username, password))
+            .await
+    }
+
+    /// Try to authenticate using a private key.
+    /// See [Session::userauth_publickey](struct.Session.html#method.userauth_publickey).
+    pub async fn userauth_publickey(
+        &self,
+        username: Option<&str>,
+        privkey: &SshKey,
+    ) -> SshResult<AuthStatus> {
+        self.auth(|| self.inner.session.userauth_publickey(username, privkey))
+            .await
+    }
+
+    /// Try to authenticate using the keys held by an ssh agent.
+    /// See [Session::userauth_agent](struct.Session.html#method.userauth_agent).
+    pub async fn userauth_agent(&self, username: Option<&str>) -> SshResult<AuthStatus> {
+        self.auth(|| self.inner.session.userauth_agent(username))
+            .await
+    }
+
+    /// Try to automatically authenticate using public key authentication.
+    /// See [Session::userauth_public_key_auto](struct.Session.html#method.userauth_public_key_auto).
+    pub async fn userauth_public_key_auto(
+        &self,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> SshResult<AuthStatus> {
+        self.auth(|| {
+            self.inner
+                .session
+                .userauth_public_key_auto(username, password)
+        })
+        .await
+    }
+
+    /// Create a new channel.  Use
+    /// [AsyncChannel::open_session](struct.AsyncChannel.html#method.open_session)
+    /// to open it.
+    pub fn new_channel(&self) -> SshResult<AsyncChannel> {
+        let channel = self.inner.session.new_channel()?;
+        Ok(AsyncChannel {
+            session: self.clone(),
+            channel: Mutex::new(channel),
+        })
+    }
+
+    /// Open an SFTP session on this connection
+    pub async fn sftp(&self) -> SshResult<AsyncSftp> {
+        let session = self.clone();
+        let sftp = self
+            .run_blocking(move || session.inner.session.sftp())
+            .await?;
+        Ok(AsyncSftp {
+            session: self.clone(),
+            sftp: Arc::new(Mutex::new(sftp)),
+        })
+    }
+}
+
+/// A `Channel` that is driven by the tokio reactor.
+///
+/// `AsyncRead` reads from the stdout of the channel and `AsyncWrite`
+/// writes to its stdin.  Use [stderr](#method.stderr) to read from
+/// the stderr of the channel.
+pub struct AsyncChannel {
+    session: AsyncSession,
+    channel: Mutex<Channel>,
+}
+
+impl AsyncChannel {
+    /// Returns the underlying channel.
+    /// Its methods must not be called in a way that would block.
+    pub fn channel(&self) -> MutexGuard<'_, Channel> {
+        self.channel.lock().unwrap()
+    }
+
+    async fn io<T>(&self, mut f: impl FnMut(&Channel) -> SshResult<T>) -> SshResult<T> {
+        self.session.io(|| f(&self.channel())).await
+    }
+
+    /// Open a session channel; see
+    /// [Channel::open_session](struct.Channel.html#method.open_session).
+    pub async fn open_session(&self) -> SshResult<()> {
+        self.io(|chan| chan.open_session()).await
+    }
+
+    /// Request a PTY; see
+    /// [Channel::request_pty](struct.Channel.html#method.request_pty).
+    pub async fn request_pty(&self, term: &str, columns: u32, rows: u32) -> SshResult<()> {
+        self.io(|chan| chan.request_pty(term, columns, rows)).await
+    }
+
+    /// Request a shell; see
+    /// [Channel::request_shell](struct.Channel.html#method.request_shell).
+    pub async fn request_shell(&self) -> SshResult<()> {
+        self.io(|chan| chan.request_shell()).await
+    }
+
+    /// Run a command; see
+    /// [Channel::request_exec](struct.Channel.html#method.request_exec).
+    pub async fn request_exec(&self, command: &str) -> SshResult<()> {
+        self.io(|chan| chan.request_exec(command)).await
+    }
+
+    /// Request a subsystem; see
+    /// [Channel::request_subsystem](struct.Channel.html#method.request_subsystem).
+    pub async fn request_subsystem(&self, subsys: &str) -> SshResult<()> {
+        self.io(|chan| chan.request_subsystem(subsys)).await
+    }
+
+    /// Set an environment variable; see
+    /// [Channel::request_env](struct.Channel.html#method.request_env).
+    pub async fn request_env(&self, name: &str, value: &str) -> SshResult<()> {
+        self.io(|chan| chan.request_env(name, value)).await
+    }
+
+    /// Send an end of file on the channel
+    pub async fn send_eof(&self) -> SshResult<()> {
+        self.io(|chan| chan.send_eof()).await
+    }
+
+    /// Close the channel
+    pub async fn close(&self) -> SshResult<()> {
+        self.io(|chan| chan.close()).await
+    }
+
+    /// Wait for the remote end to close the channel, and then return
+    /// its exit status, if it reported one.
+    /// Any remaining output is discarded.
+    pub async fn wait_for_exit_status(&self) -> SshResult<Option<c_int>> {
+        let mut buf = [0u8; 8192];
+        self.io(|chan| {
+            for &is_stderr in &[false, true] {
+                while chan.read_nonblocking(&mut buf, is_stderr)? > 0 {}
+            }
+            if chan.is_closed() || chan.is_eof() {
+                Ok(())
+            } else {
+                Err(Error::TryAgain)
+            }
+        })
+        .await?;
+        Ok(self.channel().get_exit_status())
+    }
+
+    /// Returns a reader for the stderr stream of the channel
+    pub fn stderr(&mut self) -> AsyncChannelStderr<'_> {
+        AsyncChannelStderr { channel: self }
+    }
+
+    fn poll_read_stream(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+        is_stderr: bool,
+    ) -> Poll<std::io::Result<()>> {
+        let dest = buf.initialize_unfilled();
+        let res = self.session.inner.poll_io(cx, &mut || {
+            let chan = self.channel();
+            match chan.poll_timeout(is_stderr, Some(Duration::from_millis(0)))? {
+                PollStatus::EndOfFile => Ok(0),
+                PollStatus::AvailableBytes(0) if chan.is_closed() => Ok(0),
+                PollStatus::AvailableBytes(0) => Err(Error::TryAgain),
+                PollStatus::AvailableBytes(_) => chan.read_nonblocking(dest, is_stderr),
+            }
+        });
+        match res {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for AsyncChannel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.poll_read_stream(cx, buf, false)
+    }
+}
+
+impl AsyncWrite for AsyncChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.session
+            .inner
+            .poll_io(cx, &mut || {
+                let chan = self.channel();
+                // Writing more than the window would cause libssh
+                // to wait for the remote end to enlarge it
+                let window = chan.window_size();
+                if window == 0 {
+                    return Err(Error::TryAgain);
+                }
+                match chan.write_impl(&buf[..buf.len().min(window)], false)? {
+                    0 => Err(Error::TryAgain),
+                    n => Ok(n),
+                }
+            })
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let session = &self.session.inner.session;
+        self.session
+            .inner
+            .poll_io(cx, &mut || {
+                session.blocking_flush(Some(Duration::from_millis(0)))
+            })
+            .map_err(Into::into)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        self.session
+            .inner
+            .poll_io(cx, &mut || self.channel().send_eof())
+            .map_err(Into::into)
+    }
+}
+
+/// Reads from the stderr stream of an `AsyncChannel`
+pub struct AsyncChannelStderr<'a> {
+    channel: &'a mut AsyncChannel,
+}
+
+impl<'a> AsyncRead for AsyncChannelStderr<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.channel.poll_read_stream(cx, buf, true)
+    }
+}
+
+/// An SFTP session that is used from async code.
+///
+/// Each operation runs on tokio's blocking thread pool, as libssh
+/// provides no way to perform SFTP operations without blocking.
+/// `AsyncSftp` is cheap to clone; clones refer to the same SFTP session.
+#[derive(Clone)]
+pub struct AsyncSftp {
+    session: AsyncSession,
+    sftp: Arc<Mutex<Sftp>>,
+}
+
+impl AsyncSftp {
+    async fn run<T, F>(&self, f: F) -> SshResult<T>
+    where
+        F: FnOnce(&Sftp) -> SshResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let sftp = Arc::clone(&self.sftp);
+        self.session
+            .run_blocking(move || f(&sftp.lock().unwrap()))
+            .await
+    }
+
+    /// See [Sftp::create_dir](struct.Sftp.html#method.create_dir)
+    pub async fn create_dir(&self, filename: &str, mode: sys::mode_t) -> SshResult<()> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.create_dir(&filename, mode)).await
+    }
+
+    /// See [Sftp::canonicalize](struct.Sftp.html#method.canonicalize)
+    pub async fn canonicalize(&self, filename: &str) -> SshResult<String> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.canonicalize(&filename)).await
+    }
+
+    /// See [Sftp::chmod](struct.Sftp.html#method.chmod)
+    pub async fn chmod(&self, filename: &str, mode: sys::mode_t) -> SshResult<()> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.chmod(&filename, mode)).await
+    }
+
+    /// See [Sftp::read_link](struct.Sftp.html#method.read_link)
+    pub async fn read_link(&self, filename: &str) -> SshResult<String> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.read_link(&filename)).await
+    }
+
+    /// See [Sftp::set_metadata](struct.Sftp.html#method.set_metadata)
+    pub async fn set_metadata(&self, filename: &str, metadata: &SetAttributes) -> SshResult<()> {
+        let filename = filename.to_string();
+        let metadata = metadata.clone();
+        self.run(move |sftp| sftp.set_metadata(&filename, &metadata))
+            .await
+    }
+
+    /// See [Sftp::metadata](struct.Sftp.html#method.metadata)
+    pub async fn metadata(&self, filename: &str) -> SshResult<Metadata> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.metadata(&filename)).await
+    }
+
+    /// See [Sftp::symlink_metadata](struct.Sftp.html#method.symlink_metadata)
+    pub async fn symlink_metadata(&self, filename: &str) -> SshResult<Metadata> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.symlink_metadata(&filename)).await
+    }
+
+    /// See [Sftp::rename](struct.Sftp.html#method.rename)
+    pub async fn rename(&self, filename: &str, new_name: &str) -> SshResult<()> {
+        let filename = filename.to_string();
+        let new_name = new_name.to_string();
+        self.run(move |sftp| sftp.rename(&filename, &new_name))
+            .await
+    }
+
+    /// See [Sftp::remove_file](struct.Sftp.html#method.remove_file)
+    pub async fn remove_file(&self, filename: &str) -> SshResult<()> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.remove_file(&filename)).await
+    }
+
+    /// See [Sftp::remove_dir](struct.Sftp.html#method.remove_dir)
+    pub async fn remove_dir(&self, filename: &str) -> SshResult<()> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.remove_dir(&filename)).await
+    }
+
+    /// See [Sftp::symlink](struct.Sftp.html#method.symlink)
+    pub async fn symlink(&self, target: &str, dest: &str) -> SshResult<()> {
+        let target = target.to_string();
+        let dest = dest.to_string();
+        self.run(move |sftp| sftp.symlink(&target, &dest)).await
+    }
+
+    /// See [Sftp::read_dir](struct.Sftp.html#method.read_dir)
+    pub async fn read_dir(&self, filename: &str) -> SshResult<Vec<Metadata>> {
+        let filename = filename.to_string();
+        self.run(move |sftp| sftp.read_dir(&filename)).await
+    }
+
+    /// Open a file on the server.
+    /// See [Sftp::open](struct.Sftp.html#method.open)
+    pub async fn open(
+        &self,
+        filename: &str,
+        accesstype: c_int,
+        mode: sys::mode_t,
+    ) -> SshResult<AsyncSftpFile> {
+        let filename = filename.to_string();
+        let file = self
+            .run(move |sftp| sftp.open(&filename, accesstype, mode))
+            .await?;
+        Ok(AsyncSftpFile {
+            session: self.session.clone(),
+            file: Arc::new(Mutex::new(file)),
+            state: FileState::Idle,
+            pending: vec![],
+        })
+    }
+}
+
+/// A file opened via [AsyncSftp::open](struct.AsyncSftp.html#method.open).
+///
+/// Implements `AsyncRead`, `AsyncWrite` and `AsyncSeek`.
+/// Each write is sent to the server before it completes, so
+/// flushing is a no-op; use [sync_all](#method.sync_all) to have
+/// the server commit the data to storage.
+pub struct AsyncSftpFile {
+    session: AsyncSession,
+    file: Arc<Mutex<SftpFile>>,
+    state: FileState,
+    /// Data that was read but that didn't fit into the caller's buffer
+    pending: Vec<u8>,
+}
+
+enum FileState {
+    Idle,
+    Reading(JoinHandle<std::io::Result<Vec<u8>>>),
+    Writing(JoinHandle<std::io::Result<usize>>),
+    Seeking(JoinHandle<std::io::Result<u64>>),
+}
+
+fn busy() -> std::io::Error {
+    std::io::Error::other("another operation is in progress")
+}
+
+impl AsyncSftpFile {
+    async fn run<T, F>(&self, f: F) -> SshResult<T>
+    where
+        F: FnOnce(&mut SftpFile) -> SshResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let file = Arc::clone(&self.file);
+        self.session
+            .run_blocking(move || f(&mut file.lock().unwrap()))
+            .await
+    }
+
+    /// Retrieve metadata for the file
+    pub async fn metadata(&self) -> SshResult<Metadata> {
+        self.run(|file| file.metadata()).await
+    }
+
+    /// Ask the server to commit the contents of the file to storage.
+    /// This requires that the server support the
+    /// `fsync@openssh.com` extension.
+    pub async fn sync_all(&self) -> SshResult<()> {
+        self.run(|file| Ok(file.flush()?)).await
+    }
+}
+
+impl AsyncRead for AsyncSftpFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                FileState::Idle if !this.pending.is_empty() => {
+                    let n = this.pending.len().min(buf.remaining());
+                    buf.put_slice(&this.pending[..n]);
+                    this.pending.drain(..n);
+                    return Poll::Ready(Ok(()));
+                }
+                FileState::Idle => {
+                    let len = buf.remaining();
+                    let file = Arc::clone(&this.file);
+                    this.state = FileState::Reading(this.session.spawn_blocking(move || {
+                        let mut data = vec![0u8; len];
+                        let n = file.lock().unwrap().read(&mut data)?;
+                        data.truncate(n);
+                        Ok(data)
+                    }));
+                }
+                FileState::Reading(handle) => {
+                    let result = match Pin::new(handle).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    this.state = FileState::Idle;
+                    this.pending = join_result(result)??;
+                    if this.pending.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                FileState::Writing(_) | FileState::Seeking(_) => return Poll::Ready(Err(busy())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncSftpFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                FileState::Idle => {
+                    let data = buf.to_vec();
+                    let file = Arc::clone(&this.file);
+                    this.state = FileState::Writing(
+                        this.session
+                            .spawn_blocking(move || file.lock().unwrap().write(&data)),
+                    );
+                }
+                FileState::Writing(handle) => {
+                    let result = match Pin::new(handle).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    this.state = FileState::Idle;
+                    return Poll::Ready(join_result(result)?);
+                }
+                FileState::Reading(_) | FileState::Seeking(_) => return Poll::Ready(Err(busy())),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let FileState::Writing(handle) = &mut this.state {
+            let result = match Pin::new(handle).poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.state = FileState::Idle;
+            join_result(result)??;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for AsyncSftpFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        if !matches!(this.state, FileState::Idle) {
+            return Err(busy());
+        }
+        // Data that we have read ahead of the caller has
+        // already advanced the position of the file
+        let position = match position {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - this.pending.len() as i64),
+            position => position,
+        };
+        this.pending.clear();
+        let file = Arc::clone(&this.file);
+        this.state = FileState::Seeking(
+            this.session
+                .spawn_blocking(move || file.lock().unwrap().seek(position)),
+        );
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                FileState::Idle => {
+                    let pending = this.pending.len() as u64;
+                    let file = Arc::clone(&this.file);
+                    this.state = FileState::Seeking(this.session.spawn_blocking(move || {
+                        let position = file.lock().unwrap().stream_position()?;
+                        Ok(position - pending)
+                    }));
+                }
+                FileState::Seeking(handle) => {
+                    let result = match Pin::new(handle).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    this.state = FileState::Idle;
+                    return Poll::Ready(join_result(result)?);
+                }
+                FileState::Reading(_) | FileState::Writing(_) => return Poll::Ready(Err(busy())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn waiters() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        let mut waiters = Waiters {
+            generation: 0,
+            wakers: vec![],
+        };
+
+        assert!(waiters.register(0, &waker));
+        assert!(waiters.register(0, &waker));
+        assert_eq!(waiters.wakers.len(), 1);
+        for waker in waiters.advance() {
+            waker.wake();
+        }
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        // A registration from before the last wake must try again
+        assert!(!waiters.register(0, &waker));
+        assert!(waiters.wakers.is_empty());
+        assert!(waiters.register(1, &waker));
+    }
+
+    #[test]
+    fn readiness() {
+        let mut fds = [0 as c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+        assert!(!fd_ready(read_fd, libc::POLLIN));
+        assert!(fd_ready(write_fd, libc::POLLOUT));
+        assert_eq!(unsafe { libc::write(write_fd, b"x".as_ptr() as _, 1) }, 1);
+        assert!(fd_ready(read_fd, libc::POLLIN));
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+    }
+
+    #[test]
+    fn join_results() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            assert_eq!(join_result(tokio::spawn(async { 42 }).await).unwrap(), 42);
+
+            let handle = tokio::spawn(std::future::pending::<()>());
+            handle.abort();
+            assert!(join_result(handle.await).is_err());
+
+            let panicked = tokio::spawn(async { panic!("boom") }).await;
+            let caught =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| join_result(panicked)));
+            assert_eq!(*caught.unwrap_err().downcast::<&str>().unwrap(), "boom");
+        });
+    }
+}
@@ -0,0 +1,641 @@
+use crate::{Error, KeyType, SshKey, SshResult};
+use libssh_rs_sys as sys;
+use std::ffi::CString;
+use std::ptr::null_mut;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod signature;
+
+/// Describes why a certificate was not accepted by
+/// [SshCertificate::verify](struct.SshCertificate.html#method.verify)
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateError {
+    #[error("the certificate is a user certificate, not a host certificate")]
+    NotAHostCertificate,
+    #[error("the certificate was not signed by a trusted certificate authority")]
+    UntrustedAuthority,
+    #[error("the certificate signature algorithm {} is not supported", .0)]
+    UnsupportedSignatureAlgorithm(String),
+    #[error("the certificate signature is not valid")]
+    BadSignature,
+    #[error("the certificate is not valid before {:?}", .0)]
+    NotYetValid(SystemTime),
+    #[error("the certificate expired at {:?}", .0)]
+    Expired(SystemTime),
+    #[error("the certificate has an unsupported critical option {}", .0)]
+    UnsupportedCriticalOption(String),
+    #[error("the certificate does not list any principals")]
+    NoPrincipals,
+    #[error("{} is not one of the certificate principals {:?}", .hostname, .principals)]
+    PrincipalNotListed {
+        hostname: String,
+        principals: Vec<String>,
+    },
+    #[error("the certificate is malformed: {}", .0)]
+    Malformed(String),
+}
+
+/// Whether a certificate identifies a user or a host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertType {
+    User,
+    Host,
+}
+
+/// An OpenSSH certificate, as described in `PROTOCOL.certkeys`
+/// in the OpenSSH distribution.
+/// Obtain one from a certificate key via
+/// [SshKey::certificate](struct.SshKey.html#method.certificate).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshCertificate {
+    key_type: String,
+    public_key: Vec<u8>,
+    serial: u64,
+    cert_type: CertType,
+    key_id: String,
+    principals: Vec<String>,
+    valid_after: u64,
+    valid_before: u64,
+    critical_options: Vec<(String, String)>,
+    extensions: Vec<(String, String)>,
+    signature_key: Vec<u8>,
+    signature: Vec<u8>,
+    /// The encoding of all fields up to the signature,
+    /// over which the signature is computed
+    signed_data: Vec<u8>,
+}
+
+impl SshCertificate {
+    /// Parses a certificate from its wire encoding, which is the
+    /// base64 decoded second field of a `-cert.pub` file
+    pub fn from_blob(blob: &[u8]) -> SshResult<Self> {
+        let mut reader = Reader::new(blob);
+        let key_type = reader.read_str()?.to_string();
+        let (plain_type, num_fields) = certified_key_type(&key_type)?;
+        let _nonce = reader.read_string()?;
+
+        let mut public_key = vec![];
+        put_string(&mut public_key, plain_type.as_bytes());
+        for _ in 0..num_fields {
+            put_string(&mut public_key, reader.read_string()?);
+        }
+
+        let serial = reader.read_u64()?;
+        let cert_type = match reader.read_u32()? {
+            1 => CertType::User,
+            2 => CertType::Host,
+            other => {
+                return Err(Error::Fatal(format!(
+                    "malformed certificate: unknown certificate type {}",
+                    other
+                )))
+            }
+        };
+        let key_id = reader.read_str()?.to_string();
+        let mut principals = vec![];
+        let mut principal_reader = Reader::new(reader.read_string()?);
+        while !principal_reader.is_empty() {
+            principals.push(principal_reader.read_str()?.to_string());
+        }
+        let valid_after = reader.read_u64()?;
+        let valid_before = reader.read_u64()?;
+        let critical_options = parse_options(reader.read_string()?)?;
+        let extensions = parse_options(reader.read_string()?)?;
+        let _reserved = reader.read_string()?;
+        let signature_key = reader.read_string()?.to_vec();
+        let signed_data = blob[..blob.len() - reader.remaining()].to_vec();
+        let signature = reader.read_string()?.to_vec();
+        if !reader.is_empty() {
+            return Err(Error::fatal("malformed certificate: trailing data"));
+        }
+
+        Ok(Self {
+            key_type,
+            public_key,
+            serial,
+            cert_type,
+            key_id,
+            principals,
+            valid_after,
+            valid_before,
+            critical_options,
+            extensions,
+            signature_key,
+            signature,
+            signed_data,
+        })
+    }
+
+    /// Returns the certificate key type name,
+    /// such as `ssh-ed25519-cert-v01@openssh.com`
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    /// Returns the wire encoding of the public key that is certified
+    pub fn public_key_blob(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Returns the serial number assigned by the CA
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn cert_type(&self) -> CertType {
+        self.cert_type
+    }
+
+    /// Returns the free-form identifier assigned by the CA,
+    /// which is typically logged by the server
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Returns the user or host names for which the certificate is valid.
+    /// An empty list means that it is valid for any principal.
+    pub fn principals(&self) -> &[String] {
+        &self.principals
+    }
+
+    /// Returns the start of the validity period
+    pub fn valid_after(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.valid_after)
+    }
+
+    /// Returns the end of the validity period, or `None`
+    /// if the certificate does not expire
+    pub fn valid_before(&self) -> Option<SystemTime> {
+        if self.valid_before == u64::MAX {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::from_secs(self.valid_before))
+        }
+    }
+
+    /// Returns true if `now` is within the validity period
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.valid_after <= now && now < self.valid_before
+    }
+
+    /// Returns the critical options as name and value pairs.
+    /// Options that are flags have an empty value.
+    pub fn critical_options(&self) -> &[(String, String)] {
+        &self.critical_options
+    }
+
+    /// Returns the extensions as name and value pairs, such
+    /// as `permit-pty`; these are usually flags with an empty value.
+    pub fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
+    }
+
+    /// Returns the wire encoding of the key of the certificate
+    /// authority that signed the certificate
+    pub fn signature_key_blob(&self) -> &[u8] {
+        &self.signature_key
+    }
+
+    /// Returns the key of the certificate authority
+    /// that signed the certificate
+    pub fn signature_key(&self) -> SshResult<SshKey> {
+        SshKey::from_pubkey_blob(&self.signature_key)
+    }
+
+    /// Checks that this is a valid host certificate for `hostname`
+    /// at the time `now`, signed by one of `ca_keys`.
+    ///
+    /// Unlike user certificates, a host certificate with no principals
+    /// is rejected, as OpenSSH does.
+    /// Signatures made with the SHA-1 based `ssh-rsa` algorithm and
+    /// by DSA or security key authorities are not supported.
+    pub fn verify(
+        &self,
+        ca_keys: &[SshKey],
+        hostname: &str,
+        now: SystemTime,
+    ) -> Result<(), CertificateError> {
+        let ca_blobs = ca_keys
+            .iter()
+            .map(|key| key.export_pubkey_blob())
+            .collect::<SshResult<Vec<_>>>()
+            .map_err(|err| CertificateError::Malformed(err.to_string()))?;
+        self.verify_blobs(&ca_blobs, hostname, now)
+    }
+
+    pub(crate) fn verify_blobs(
+        &self,
+        ca_blobs: &[Vec<u8>],
+        hostname: &str,
+        now: SystemTime,
+    ) -> Result<(), CertificateError> {
+        if self.cert_type != CertType::Host {
+            return Err(CertificateError::NotAHostCertificate);
+        }
+        if !ca_blobs.contains(&self.signature_key) {
+            return Err(CertificateError::UntrustedAuthority);
+        }
+        signature::verify_signature(&self.signature_key, &self.signature, &self.signed_data)?;
+
+        if !self.is_valid_at(now) {
+            return Err(match self.valid_before() {
+                Some(valid_before) if now >= valid_before => {
+                    CertificateError::Expired(valid_before)
+                }
+                _ => CertificateError::NotYetValid(self.valid_after()),
+            });
+        }
+        // No critical options are defined for host certificates
+        if let Some((name, _)) = self.critical_options.first() {
+            return Err(CertificateError::UnsupportedCriticalOption(name.clone()));
+        }
+        if self.principals.is_empty() {
+            return Err(CertificateError::NoPrincipals);
+        }
+        if !self
+            .principals
+            .iter()
+            .any(|principal| principal.eq_ignore_ascii_case(hostname))
+        {
+            return Err(CertificateError::PrincipalNotListed {
+                hostname: hostname.to_string(),
+                principals: self.principals.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parses the critical options or extensions of a certificate,
+/// each of which is a name followed by a string that holds the
+/// value, which is itself encoded as a string unless it is empty
+fn parse_options(data: &[u8]) -> SshResult<Vec<(String, String)>> {
+    let mut reader = Reader::new(data);
+    let mut options = vec![];
+    while !reader.is_empty() {
+        let name = reader.read_str()?.to_string();
+        let mut value_reader = Reader::new(reader.read_string()?);
+        let value = if value_reader.is_empty() {
+            String::new()
+        } else {
+            value_reader.read_str()?.to_string()
+        };
+        options.push((name, value));
+    }
+    Ok(options)
+}
+
+impl SshKey {
+    /// Import a base64 encoded certificate of the given type,
+    /// as found in the second field of a `-cert.pub` file
+    pub fn from_cert_base64(b64_cert: &str, key_type: KeyType) -> SshResult<SshKey> {
+        let b64_cert = CString::new(b64_cert)
+            .map_err(|e| Error::Fatal(format!("Failed to process certificate: {:?}", e)))?;
+        let mut key = null_mut();
+        let res = unsafe {
+            sys::ssh_pki_import_cert_base64(b64_cert.as_ptr(), key_type.as_sys(), &mut key)
+        };
+        if res != sys::SSH_OK as i32 || key.is_null() {
+            return Err(Error::fatal("Failed to parse certificate"));
+        }
+        Ok(SshKey { key })
+    }
+
+    /// Import a certificate from a file, typically named
+    /// after the private key with a `-cert.pub` suffix
+    pub fn from_cert_file(filename: &str) -> SshResult<SshKey> {
+        let filename_cstr = CString::new(filename).map_err(|e| {
+            Error::Fatal(format!(
+                "Could not make CString from filename '{filename}': {e:#}"
+            ))
+        })?;
+        let mut key = null_mut();
+        let res = unsafe { sys::ssh_pki_import_cert_file(filename_cstr.as_ptr(), &mut key) };
+        if res != sys::SSH_OK as i32 || key.is_null() {
+            return Err(Error::Fatal(format!(
+                "Failed to parse certificate from file '{filename}'"
+            )));
+        }
+        Ok(SshKey { key })
+    }
+
+    /// Attaches the certificate `cert` to this private key, which
+    /// must be the private half of the certified key.
+    /// The resulting key can then be passed to
+    /// [Session::userauth_publickey](struct.Session.html#method.userauth_publickey)
+    /// to authenticate using the certificate.
+    pub fn copy_cert_to_privkey(&mut self, cert: &SshKey) -> SshResult<()> {
+        let res = unsafe { sys::ssh_pki_copy_cert_to_privkey(cert.key, self.key) };
+        if res != sys::SSH_OK as i32 {
+            return Err(Error::fatal(
+                "failed to attach certificate: the key does not match or already has one",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns true if this is a certificate, or a private key
+    /// that has a certificate attached
+    pub fn has_certificate(&self) -> bool {
+        // libssh exports the certificate rather than the key
+        // when one is present
+        self.export_pubkey_blob()
+            .ok()
+            .and_then(|blob| Reader::new(&blob).read_str().ok().map(is_cert_type_name))
+            .unwrap_or(false)
+    }
+
+    /// Parses the certificate held by this key
+    pub fn certificate(&self) -> SshResult<SshCertificate> {
+        SshCertificate::from_blob(&self.export_pubkey_blob()?)
+    }
+
+    pub(crate) fn export_pubkey_blob(&self) -> SshResult<Vec<u8>> {
+        base64_decode(&self.export_pubkey_base64()?)
+    }
+
+    /// Imports a public key or certificate from its wire encoding
+    pub(crate) fn from_pubkey_blob(blob: &[u8]) -> SshResult<SshKey> {
+        let name = Reader::new(blob).read_str()?;
+        let key_type = KeyType::from_name(name);
+        if is_cert_type_name(name) {
+            Self::from_cert_base64(&base64_encode(blob), key_type)
+        } else {
+            Self::from_pubkey_base64(&base64_encode(blob), key_type)
+        }
+    }
+}
+
+fn is_cert_type_name(name: &str) -> bool {
+    name.ends_with("-cert-v01@openssh.com")
+}
+
+/// Returns the name of the plain key type that is certified by
+/// the certificate type `name`, along with the number of fields
+/// in its public key encoding
+fn certified_key_type(name: &str) -> SshResult<(String, usize)> {
+    let plain = name
+        .strip_suffix("-cert-v01@openssh.com")
+        .ok_or_else(|| Error::Fatal(format!("{} is not a certificate type", name)))?;
+    let num_fields = match plain {
+        "ssh-rsa" => 2,
+        "ssh-dss" => 4,
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => 2,
+        "ssh-ed25519" => 1,
+        "sk-ecdsa-sha2-nistp256" => 3,
+        "sk-ssh-ed25519" => 2,
+        _ => {
+            return Err(Error::Fatal(format!(
+                "unsupported certificate type {}",
+                name
+            )))
+        }
+    };
+    if plain.starts_with("sk-") {
+        Ok((format!("{}@openssh.com", plain), num_fields))
+    } else {
+        Ok((plain.to_string(), num_fields))
+    }
+}
+
+/// Reads the SSH wire encoding described in RFC 4251 section 5
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> SshResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::fatal("malformed key data: truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u32(&mut self) -> SshResult<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> SshResult<u64> {
+        let hi = self.read_u32()? as u64;
+        let lo = self.read_u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    pub fn read_string(&mut self) -> SshResult<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+
+    pub fn read_str(&mut self) -> SshResult<&'a str> {
+        std::str::from_utf8(self.read_string()?)
+            .map_err(|_| Error::fatal("malformed key data: invalid utf-8"))
+    }
+}
+
+pub(crate) fn put_string(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Encodes standard base64, with padding
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0u8; 4];
+        bytes[1..=chunk.len()].copy_from_slice(chunk);
+        let acc = u32::from_be_bytes(bytes);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(acc >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64, ignoring any whitespace
+pub(crate) fn base64_decode(input: &str) -> SshResult<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input: Vec<u8> = input.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let data = match input.iter().position(|&c| c == b'=') {
+        Some(pad)
+            if input[pad..].iter().all(|&c| c == b'=')
+                && pad % 4 >= 2
+                && pad % 4 + input.len() - pad == 4 =>
+        {
+            &input[..pad]
+        }
+        Some(_) => return Err(Error::fatal("invalid base64 padding")),
+        None => &input[..],
+    };
+
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    for chunk in data.chunks(4) {
+        if chunk.len() == 1 {
+            return Err(Error::fatal("invalid base64 length"));
+        }
+        let mut acc = 0u32;
+        for &c in chunk {
+            acc = acc << 6 | value(c).ok_or_else(|| Error::fatal("invalid base64 character"))?;
+        }
+        acc <<= 6 * (4 - chunk.len() as u32);
+        let bytes = acc.to_be_bytes();
+        out.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+        assert_eq!(base64_decode("Zm8=").unwrap(), b"fo");
+        assert_eq!(base64_decode("Zm9v").unwrap(), b"foo");
+        assert_eq!(base64_decode("Zm9v\nYmFy").unwrap(), b"foobar");
+        assert!(base64_decode("Zm9v=").is_err());
+        assert!(base64_decode("Z=m9").is_err());
+        assert!(base64_decode("Zm9vY").is_err());
+
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+    }
+
+    #[test]
+    fn parse_certificate() {
+        let mut blob = vec![];
+        put_string(&mut blob, b"ssh-ed25519-cert-v01@openssh.com");
+        put_string(&mut blob, b"nonce");
+        put_string(&mut blob, &[7; 32]);
+        blob.extend_from_slice(&42u64.to_be_bytes());
+        blob.extend_from_slice(&1u32.to_be_bytes());
+        put_string(&mut blob, b"alice@example");
+        let mut principals = vec![];
+        put_string(&mut principals, b"alice");
+        put_string(&mut principals, b"root");
+        put_string(&mut blob, &principals);
+        blob.extend_from_slice(&100u64.to_be_bytes());
+        blob.extend_from_slice(&u64::MAX.to_be_bytes());
+        put_string(&mut blob, b"");
+        put_string(&mut blob, b"");
+        put_string(&mut blob, b"");
+        put_string(&mut blob, b"ca key");
+        put_string(&mut blob, b"signature");
+
+        let cert = SshCertificate::from_blob(&blob).unwrap();
+        assert_eq!(cert.key_type(), "ssh-ed25519-cert-v01@openssh.com");
+        assert_eq!(cert.serial(), 42);
+        assert_eq!(cert.cert_type(), CertType::User);
+        assert_eq!(cert.key_id(), "alice@example");
+        assert_eq!(cert.principals(), ["alice", "root"]);
+        assert_eq!(cert.valid_before(), None);
+        assert!(cert.is_valid_at(UNIX_EPOCH + Duration::from_secs(100)));
+        assert!(!cert.is_valid_at(UNIX_EPOCH + Duration::from_secs(99)));
+
+        let mut public_key = vec![];
+        put_string(&mut public_key, b"ssh-ed25519");
+        put_string(&mut public_key, &[7; 32]);
+        assert_eq!(cert.public_key_blob(), &public_key[..]);
+
+        assert!(SshCertificate::from_blob(&blob[..blob.len() - 1]).is_err());
+    }
+
+    // Generated with `ssh-keygen -s ca -I web1 -h -V 20200101:20300101`
+    // using ed25519, ecdsa and rsa CA keys. The ed25519 one also has
+    // `-n host.example.com,web1 -z 7`; the others have
+    // `-n host.example.com` and serial 0.
+    const ED25519_CA: &str = "AAAAC3NzaC1lZDI1NTE5AAAAID35mcrgYJkPBPGHcw494j1fyGQrrP1OLJFAZLHEhdh5";
+    const ED25519_SIGNED: &str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIOQdih8ZUcC4l7xWzY/ylisjrvBMfqy2Qs5j4w+5oEDwAAAAIHDlL6YoA9MD9FJqDHaLKqBC4SMUEoUEvLUDNGqRKR9CAAAAAAAAAAcAAAACAAAABHdlYjEAAAAcAAAAEGhvc3QuZXhhbXBsZS5jb20AAAAEd2ViMQAAAABeC+EAAAAAAHDb2IAAAAAAAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAID35mcrgYJkPBPGHcw494j1fyGQrrP1OLJFAZLHEhdh5AAAAUwAAAAtzc2gtZWQyNTUxOQAAAEDaqub2QOcX9tcmDbsvpQ4UPTb6vuE1XqXESAVHigz7fz6a57YrCKQ9uT8moIodHey5H7vIuTRKIxsAHK+HNHEI";
+    const ECDSA_CA: &str = "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBK+onEF504w+pN3dzfDZmqKyy17GEJ9JdLcVyahBXlxXo/PdeXrCITEshZgI8jU5bXJcWPNkhKqDsPVb77iyUa8=";
+    const ECDSA_SIGNED: &str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIMEOHXv6UprVL0LwDmgmnBFUgEub+4s3KgbTk/MDWtWHAAAAIHDlL6YoA9MD9FJqDHaLKqBC4SMUEoUEvLUDNGqRKR9CAAAAAAAAAAAAAAACAAAABHdlYjEAAAAUAAAAEGhvc3QuZXhhbXBsZS5jb20AAAAAXgvhAAAAAABw29iAAAAAAAAAAAAAAAAAAAAAaAAAABNlY2RzYS1zaGEyLW5pc3RwMjU2AAAACG5pc3RwMjU2AAAAQQSvqJxBedOMPqTd3c3w2ZqisstexhCfSXS3FcmoQV5cV6Pz3Xl6wiExLIWYCPI1OW1yXFjzZISqg7D1W++4slGvAAAAZAAAABNlY2RzYS1zaGEyLW5pc3RwMjU2AAAASQAAACEA6k8FxRummBgl4jrof9qaSOUtQ82TC3r6eeNAcgz3GlkAAAAgWJf9O3e+OkITe5UdknpqkJ72hlroeBbKOWIghbT8lac=";
+    const RSA_CA: &str = "AAAAB3NzaC1yc2EAAAADAQABAAAAgQCxM4RXVi9tmJyXQD+rg03LtiMo/RhoHu44q7Nqx8K9vAByZ075ewNdD8hGXgH70CJnzVwC+DlW49CE9JMwv2ui/4ZnxI+ixNPr1gvMrGfbnMzZ1U96I53HaILOA7sGCnE0//oD9NWINRPV0OF5uClrdczjuNuIx4sVOgIkrwhPXw==";
+    const RSA_SIGNED: &str = "AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIMOGYAsWcnuqb+Bo0HX+W2pERZcnIm7KXbicIGFoOC/CAAAAIHDlL6YoA9MD9FJqDHaLKqBC4SMUEoUEvLUDNGqRKR9CAAAAAAAAAAAAAAACAAAABHdlYjEAAAAUAAAAEGhvc3QuZXhhbXBsZS5jb20AAAAAXgvhAAAAAABw29iAAAAAAAAAAAAAAAAAAAAAlwAAAAdzc2gtcnNhAAAAAwEAAQAAAIEAsTOEV1YvbZicl0A/q4NNy7YjKP0YaB7uOKuzasfCvbwAcmdO+XsDXQ/IRl4B+9AiZ81cAvg5VuPQhPSTML9rov+GZ8SPosTT69YLzKxn25zM2dVPeiOdx2iCzgO7BgpxNP/6A/TViDUT1dDhebgpa3XM47jbiMeLFToCJK8IT18AAACUAAAADHJzYS1zaGEyLTUxMgAAAIBK/TrmFNaf213P7Yerag9NXblBPox3apQT97SwmF9v9FiGc/mv6EgjJWNT/ciDVgmupsqqfvqLMjk7VyHZu9pBPWENLrSX8aR6CPEcpSvGolx6wO9I0WnNqFrhehckTGtyKSrNcDmkaXn42zPYSDhA7AB1RDKf+Oh6bmzhNCoQqQ==";
+
+    #[test]
+    fn verify_host_certificate() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        for (ca, signed, serial, principals) in [
+            (
+                ED25519_CA,
+                ED25519_SIGNED,
+                7,
+                &["host.example.com", "web1"][..],
+            ),
+            (ECDSA_CA, ECDSA_SIGNED, 0, &["host.example.com"][..]),
+            (RSA_CA, RSA_SIGNED, 0, &["host.example.com"][..]),
+        ] {
+            let ca = base64_decode(ca).unwrap();
+            let cert = SshCertificate::from_blob(&base64_decode(signed).unwrap()).unwrap();
+            assert_eq!(cert.cert_type(), CertType::Host);
+            assert_eq!(cert.key_id(), "web1");
+            assert_eq!(cert.serial(), serial);
+            assert_eq!(cert.principals(), principals);
+            assert_eq!(cert.signature_key_blob(), &ca[..]);
+            let cas = vec![ca];
+            cert.verify_blobs(&cas, "HOST.example.com", now).unwrap();
+
+            assert_eq!(
+                cert.verify_blobs(&[], "host.example.com", now),
+                Err(CertificateError::UntrustedAuthority)
+            );
+            assert!(matches!(
+                cert.verify_blobs(&cas, "other.example.com", now),
+                Err(CertificateError::PrincipalNotListed { .. })
+            ));
+            assert!(matches!(
+                cert.verify_blobs(&cas, "host.example.com", UNIX_EPOCH),
+                Err(CertificateError::NotYetValid(_))
+            ));
+
+            // Change the key id from web1 to web2
+            let mut tampered = base64_decode(signed).unwrap();
+            let key_id = tampered
+                .windows(4)
+                .position(|window| window == b"web1")
+                .unwrap();
+            tampered[key_id + 3] = b'2';
+            let tampered = SshCertificate::from_blob(&tampered).unwrap();
+            assert_eq!(
+                tampered.verify_blobs(&cas, "host.example.com", now),
+                Err(CertificateError::BadSignature)
+            );
+        }
+    }
+}
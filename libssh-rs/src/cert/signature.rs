@@ -0,0 +1,224 @@
+use super::{CertificateError, Reader};
+use openssl_sys as ffi;
+use std::os::raw::c_int;
+use std::ptr::null_mut;
+
+/// Verifies `signature`, in the SSH signature encoding, over `data`
+/// using the public key whose wire encoding is `key_blob`.
+///
+/// The `ssh-rsa` (SHA-1) and `ssh-dss` algorithms are not accepted,
+/// matching the default `CASignatureAlgorithms` of OpenSSH.
+pub(crate) fn verify_signature(
+    key_blob: &[u8],
+    signature: &[u8],
+    data: &[u8],
+) -> Result<(), CertificateError> {
+    let mut sig_reader = Reader::new(signature);
+    let algorithm = sig_reader.read_str().map_err(malformed)?;
+    let sig = sig_reader.read_string().map_err(malformed)?;
+
+    let mut key_reader = Reader::new(key_blob);
+    let key_type = key_reader.read_str().map_err(malformed)?;
+
+    let verified = match algorithm {
+        "ssh-ed25519" if key_type == "ssh-ed25519" => {
+            let public = key_reader.read_string().map_err(malformed)?;
+            let pkey = Pkey(unsafe {
+                ffi::EVP_PKEY_new_raw_public_key(
+                    ffi::EVP_PKEY_ED25519,
+                    null_mut(),
+                    public.as_ptr(),
+                    public.len(),
+                )
+            });
+            if pkey.0.is_null() {
+                return Err(malformed_str("invalid ed25519 key"));
+            }
+            digest_verify(&pkey, std::ptr::null(), sig, data)
+        }
+        "rsa-sha2-256" | "rsa-sha2-512" if key_type == "ssh-rsa" => {
+            let e = key_reader.read_string().map_err(malformed)?;
+            let n = key_reader.read_string().map_err(malformed)?;
+            let pkey = rsa_key(e, n)?;
+
+            // OpenSSL requires the signature to be exactly as long as the
+            // modulus, whereas some implementations strip leading zeroes
+            let modulus_len = n.iter().skip_while(|&&b| b == 0).count();
+            if sig.len() > modulus_len {
+                return Err(CertificateError::BadSignature);
+            }
+            let mut padded = vec![0u8; modulus_len - sig.len()];
+            padded.extend_from_slice(sig);
+
+            let md = unsafe {
+                if algorithm == "rsa-sha2-256" {
+                    ffi::EVP_sha256()
+                } else {
+                    ffi::EVP_sha512()
+                }
+            };
+            digest_verify(&pkey, md, &padded, data)
+        }
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521"
+            if key_type == algorithm =>
+        {
+            let (nid, md) = unsafe {
+                match algorithm {
+                    "ecdsa-sha2-nistp256" => (ffi::NID_X9_62_prime256v1, ffi::EVP_sha256()),
+                    "ecdsa-sha2-nistp384" => (ffi::NID_secp384r1, ffi::EVP_sha384()),
+                    _ => (ffi::NID_secp521r1, ffi::EVP_sha512()),
+                }
+            };
+            let _curve = key_reader.read_string().map_err(malformed)?;
+            let point = key_reader.read_string().map_err(malformed)?;
+            let pkey = ec_key(nid, point)?;
+
+            let mut sig_reader = Reader::new(sig);
+            let r = sig_reader.read_string().map_err(malformed)?;
+            let s = sig_reader.read_string().map_err(malformed)?;
+            let der = ecdsa_sig_der(r, s)?;
+            digest_verify(&pkey, md, &der, data)
+        }
+        _ => {
+            return Err(CertificateError::UnsupportedSignatureAlgorithm(
+                algorithm.to_string(),
+            ))
+        }
+    };
+
+    if verified {
+        Ok(())
+    } else {
+        Err(CertificateError::BadSignature)
+    }
+}
+
+fn malformed(err: crate::Error) -> CertificateError {
+    CertificateError::Malformed(err.to_string())
+}
+
+fn malformed_str(reason: &str) -> CertificateError {
+    CertificateError::Malformed(reason.to_string())
+}
+
+struct Pkey(*mut ffi::EVP_PKEY);
+
+impl Drop for Pkey {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_PKEY_free(self.0) }
+    }
+}
+
+fn digest_verify(pkey: &Pkey, md: *const ffi::EVP_MD, sig: &[u8], data: &[u8]) -> bool {
+    unsafe {
+        let ctx = ffi::EVP_MD_CTX_new();
+        if ctx.is_null() {
+            return false;
+        }
+        let ok = ffi::EVP_DigestVerifyInit(ctx, null_mut(), md, null_mut(), pkey.0) == 1
+            && ffi::EVP_DigestVerify(ctx, sig.as_ptr(), sig.len(), data.as_ptr(), data.len()) == 1;
+        ffi::EVP_MD_CTX_free(ctx);
+        ok
+    }
+}
+
+fn bignum(bytes: &[u8]) -> Result<*mut ffi::BIGNUM, CertificateError> {
+    let bn = unsafe { ffi::BN_bin2bn(bytes.as_ptr(), bytes.len() as c_int, null_mut()) };
+    if bn.is_null() {
+        Err(malformed_str("invalid integer"))
+    } else {
+        Ok(bn)
+    }
+}
+
+fn rsa_key(e: &[u8], n: &[u8]) -> Result<Pkey, CertificateError> {
+    unsafe {
+        let e = bignum(e)?;
+        let n = match bignum(n) {
+            Ok(n) => n,
+            Err(err) => {
+                ffi::BN_free(e);
+                return Err(err);
+            }
+        };
+        let rsa = ffi::RSA_new();
+        // RSA_set0_key takes ownership of the bignums only on success
+        if rsa.is_null() || ffi::RSA_set0_key(rsa, n, e, null_mut()) != 1 {
+            ffi::BN_free(e);
+            ffi::BN_free(n);
+            if !rsa.is_null() {
+                ffi::RSA_free(rsa);
+            }
+            return Err(malformed_str("invalid rsa key"));
+        }
+        let pkey = Pkey(ffi::EVP_PKEY_new());
+        let ok = !pkey.0.is_null() && ffi::EVP_PKEY_set1_RSA(pkey.0, rsa) == 1;
+        ffi::RSA_free(rsa);
+        if ok {
+            Ok(pkey)
+        } else {
+            Err(malformed_str("invalid rsa key"))
+        }
+    }
+}
+
+fn ec_key(nid: c_int, point: &[u8]) -> Result<Pkey, CertificateError> {
+    unsafe {
+        let key = ffi::EC_KEY_new_by_curve_name(nid);
+        if key.is_null() {
+            return Err(malformed_str("unsupported curve"));
+        }
+        let group = ffi::EC_KEY_get0_group(key);
+        let ec_point = ffi::EC_POINT_new(group);
+        let pkey = Pkey(ffi::EVP_PKEY_new());
+        let ok = !ec_point.is_null()
+            && ffi::EC_POINT_oct2point(group, ec_point, point.as_ptr(), point.len(), null_mut())
+                == 1
+            && ffi::EC_KEY_set_public_key(key, ec_point) == 1
+            && !pkey.0.is_null()
+            && ffi::EVP_PKEY_set1_EC_KEY(pkey.0, key) == 1;
+        if !ec_point.is_null() {
+            ffi::EC_POINT_free(ec_point);
+        }
+        ffi::EC_KEY_free(key);
+        if ok {
+            Ok(pkey)
+        } else {
+            Err(malformed_str("invalid ecdsa key"))
+        }
+    }
+}
+
+/// Converts the `r` and `s` values of an SSH ECDSA signature
+/// into the DER encoding that OpenSSL verifies
+fn ecdsa_sig_der(r: &[u8], s: &[u8]) -> Result<Vec<u8>, CertificateError> {
+    unsafe {
+        let r = bignum(r)?;
+        let s = match bignum(s) {
+            Ok(s) => s,
+            Err(err) => {
+                ffi::BN_free(r);
+                return Err(err);
+            }
+        };
+        let sig = ffi::ECDSA_SIG_new();
+        // ECDSA_SIG_set0 takes ownership of the bignums only on success
+        if sig.is_null() || ffi::ECDSA_SIG_set0(sig, r, s) != 1 {
+            ffi::BN_free(r);
+            ffi::BN_free(s);
+            if !sig.is_null() {
+                ffi::ECDSA_SIG_free(sig);
+            }
+            return Err(malformed_str("invalid ecdsa signature"));
+        }
+        let len = ffi::i2d_ECDSA_SIG(sig, null_mut());
+        let mut der = vec![0u8; len.max(0) as usize];
+        let mut out = der.as_mut_ptr();
+        let written = ffi::i2d_ECDSA_SIG(sig, &mut out);
+        ffi::ECDSA_SIG_free(sig);
+        if len <= 0 || written != len {
+            return Err(malformed_str("invalid ecdsa signature"));
+        }
+        Ok(der)
+    }
+}
@@ -3,6 +3,7 @@ use libssh_rs_sys as sys;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::os::raw::c_int;
+use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
@@ -56,6 +57,89 @@ impl Drop for Channel {
 /// State visible to the callbacks
 struct CallbackState {
     signal_state: Mutex<Option<SignalState>>,
+    request_handler: Mutex<Option<Box<dyn ChannelRequestHandler>>>,
+}
+
+impl CallbackState {
+    /// Passes a request to the request handler, returning the status
+    /// code that libssh expects from the callback.  Requests are
+    /// rejected if there is no handler or if the handler panics.
+    fn handle_request(&self, func: impl FnOnce(&mut dyn ChannelRequestHandler) -> bool) -> c_int {
+        let mut handler = self.request_handler.lock().unwrap();
+        let accepted = match handler.as_mut() {
+            Some(handler) => {
+                match std::panic::catch_unwind(AssertUnwindSafe(|| func(handler.as_mut()))) {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        eprintln!("Error in channel request handler: {:?}", err);
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if accepted {
+            sys::SSH_OK as c_int
+        } else {
+            sys::SSH_ERROR
+        }
+    }
+}
+
+/// A channel that libssh has created, along with the callbacks that
+/// we have registered with it, that has yet to be associated with the
+/// `Session` that owns it.  This allows creating channels from within
+/// session callbacks, where we only have access to the `SessionHolder`.
+pub(crate) struct PendingChannel {
+    chan: sys::ssh_channel,
+    callbacks: Box<sys::ssh_channel_callbacks_struct>,
+    callback_state: Box<CallbackState>,
+}
+
+impl PendingChannel {
+    pub(crate) fn new(chan: sys::ssh_channel) -> Self {
+        let callback_state = Box::new(CallbackState {
+            signal_state: Mutex::new(None),
+            request_handler: Mutex::new(None),
+        });
+
+        let callbacks = Box::new(sys::ssh_channel_callbacks_struct {
+            size: std::mem::size_of::<sys::ssh_channel_callbacks_struct>(),
+            userdata: callback_state.as_ref() as *const CallbackState as *mut _,
+            channel_data_function: None,
+            channel_eof_function: None,
+            channel_close_function: None,
+            channel_signal_function: None,
+            channel_exit_status_function: None,
+            channel_exit_signal_function: Some(handle_exit_signal),
+            channel_pty_request_function: Some(handle_pty_request),
+            channel_shell_request_function: Some(handle_shell_request),
+            channel_auth_agent_req_function: None,
+            channel_x11_req_function: None,
+            channel_pty_window_change_function: Some(handle_pty_window_change),
+            channel_exec_request_function: Some(handle_exec_request),
+            channel_env_request_function: Some(handle_env_request),
+            channel_subsystem_request_function: Some(handle_subsystem_request),
+            channel_write_wontblock_function: None,
+        });
+
+        unsafe { sys::ssh_set_channel_callbacks(chan, callbacks.as_ref() as *const _ as *mut _) };
+
+        Self {
+            chan,
+            callbacks,
+            callback_state,
+        }
+    }
+
+    pub(crate) fn into_channel(self, sess: &Arc<Mutex<SessionHolder>>) -> Channel {
+        Channel {
+            sess: Arc::clone(sess),
+            chan_inner: self.chan,
+            callback_state: self.callback_state,
+            _callbacks: self.callbacks,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -105,6 +189,90 @@ unsafe extern "C" fn handle_exit_signal(
         });
 }
 
+unsafe extern "C" fn handle_pty_request(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    term: *const ::std::os::raw::c_char,
+    width: ::std::os::raw::c_int,
+    height: ::std::os::raw::c_int,
+    pxwidth: ::std::os::raw::c_int,
+    pxheight: ::std::os::raw::c_int,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    let request = PtyRequest {
+        term: cstr_to_opt_string(term).unwrap_or_default(),
+        cols: width.max(0) as u32,
+        rows: height.max(0) as u32,
+        px_width: pxwidth.max(0) as u32,
+        px_height: pxheight.max(0) as u32,
+    };
+    callback_state.handle_request(|h| h.pty_request(&request))
+}
+
+unsafe extern "C" fn handle_pty_window_change(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    width: ::std::os::raw::c_int,
+    height: ::std::os::raw::c_int,
+    pxwidth: ::std::os::raw::c_int,
+    pxheight: ::std::os::raw::c_int,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    let size = PtySize {
+        cols: width.max(0) as u32,
+        rows: height.max(0) as u32,
+        px_width: pxwidth.max(0) as u32,
+        px_height: pxheight.max(0) as u32,
+    };
+    callback_state.handle_request(|h| h.pty_window_change(size))
+}
+
+unsafe extern "C" fn handle_shell_request(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    callback_state.handle_request(|h| h.shell_request())
+}
+
+unsafe extern "C" fn handle_exec_request(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    command: *const ::std::os::raw::c_char,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    let command = cstr_to_opt_string(command).unwrap_or_default();
+    callback_state.handle_request(|h| h.exec_request(&command))
+}
+
+unsafe extern "C" fn handle_env_request(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    env_name: *const ::std::os::raw::c_char,
+    env_value: *const ::std::os::raw::c_char,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    let name = cstr_to_opt_string(env_name).unwrap_or_default();
+    let value = cstr_to_opt_string(env_value).unwrap_or_default();
+    callback_state.handle_request(|h| h.env_request(&name, &value))
+}
+
+unsafe extern "C" fn handle_subsystem_request(
+    _session: sys::ssh_session,
+    _channel: sys::ssh_channel,
+    subsystem: *const ::std::os::raw::c_char,
+    userdata: *mut ::std::os::raw::c_void,
+) -> ::std::os::raw::c_int {
+    let callback_state: &CallbackState = &*(userdata as *const CallbackState);
+    let subsystem = cstr_to_opt_string(subsystem).unwrap_or_default();
+    callback_state.handle_request(|h| h.subsystem_request(&subsystem))
+}
+
 impl Channel {
     /// Accept an X11 forwarding channel.
     /// Returns a newly created `Channel`, or `None` if no X11 request from the server.
@@ -120,41 +288,10 @@ impl Channel {
     }
 
     pub(crate) fn new(sess: &Arc<Mutex<SessionHolder>>, chan: sys::ssh_channel) -> Self {
-        let callback_state = Box::new(CallbackState {
-            signal_state: Mutex::new(None),
-        });
-
-        let callbacks = Box::new(sys::ssh_channel_callbacks_struct {
-            size: std::mem::size_of::<sys::ssh_channel_callbacks_struct>(),
-            userdata: callback_state.as_ref() as *const CallbackState as *mut _,
-            channel_data_function: None,
-            channel_eof_function: None,
-            channel_close_function: None,
-            channel_signal_function: None,
-            channel_exit_status_function: None,
-            channel_exit_signal_function: Some(handle_exit_signal),
-            channel_pty_request_function: None,
-            channel_shell_request_function: None,
-            channel_auth_agent_req_function: None,
-            channel_x11_req_function: None,
-            channel_pty_window_change_function: None,
-            channel_exec_request_function: None,
-            channel_env_request_function: None,
-            channel_subsystem_request_function: None,
-            channel_write_wontblock_function: None,
-        });
-
-        unsafe { sys::ssh_set_channel_callbacks(chan, callbacks.as_ref() as *const _ as *mut _) };
-
-        Self {
-            sess: Arc::clone(&sess),
-            chan_inner: chan,
-            callback_state,
-            _callbacks: callbacks,
-        }
+        PendingChannel::new(chan).into_channel(sess)
     }
 
-    fn lock_session(&self) -> (MutexGuard<SessionHolder>, sys::ssh_channel) {
+    pub(crate) fn lock_session(&self) -> (MutexGuard<SessionHolder>, sys::ssh_channel) {
         (self.sess.lock().unwrap(), self.chan_inner)
     }
 
@@ -190,6 +327,66 @@ impl Channel {
         self.callback_state.signal_state.lock().unwrap().clone()
     }
 
+    /// Sets the handler that will receive requests that the client
+    /// makes on this server-side channel, such as to allocate a PTY
+    /// or to execute a command.
+    ///
+    /// Requests that arrive while no handler is set are rejected.
+    pub fn set_request_handler<H: ChannelRequestHandler + 'static>(&self, handler: H) {
+        self.replace_request_handler(Some(Box::new(handler)));
+    }
+
+    pub(crate) fn replace_request_handler(
+        &self,
+        handler: Option<Box<dyn ChannelRequestHandler>>,
+    ) -> Option<Box<dyn ChannelRequestHandler>> {
+        let _sess = self.lock_session();
+        std::mem::replace(
+            &mut *self.callback_state.request_handler.lock().unwrap(),
+            handler,
+        )
+    }
+
+    /// Send the exit status of the remote process to the client.
+    /// This is used by a server to report the result of a command
+    /// that was started via an exec or shell request, and should be
+    /// sent before closing the channel.
+    pub fn send_exit_status(&self, exit_status: c_int) -> SshResult<()> {
+        let (sess, chan) = self.lock_session();
+        let res = unsafe { sys::ssh_channel_request_send_exit_status(chan, exit_status) };
+        sess.basic_status(res, "ssh_channel_request_send_exit_status failed")
+    }
+
+    /// Inform the client that the remote process was terminated by a signal.
+    /// This is the counterpart of [send_exit_status](#method.send_exit_status)
+    /// and is reported to the client via its `get_exit_signal` method.
+    ///
+    /// `signal` is the name of the signal, without the `"SIG"` prefix.
+    /// For example, `"ABRT"`, `"INT"`, `"KILL"` and so on.
+    /// `error_message` and `language` may be empty.
+    pub fn send_exit_signal(
+        &self,
+        signal: &str,
+        core_dumped: bool,
+        error_message: &str,
+        language: &str,
+    ) -> SshResult<()> {
+        let (sess, chan) = self.lock_session();
+        let signal = CString::new(signal)?;
+        let error_message = CString::new(error_message)?;
+        let language = CString::new(language)?;
+        let res = unsafe {
+            sys::ssh_channel_request_send_exit_signal(
+                chan,
+                signal.as_ptr(),
+                if core_dumped { 1 } else { 0 },
+                error_message.as_ptr(),
+                language.as_ptr(),
+            )
+        };
+        sess.basic_status(res, "ssh_channel_request_send_exit_signal failed")
+    }
+
     /// Check if the channel is closed or not.
     pub fn is_closed(&self) -> bool {
         let (_sess, chan) = self.lock_session();
@@ -561,7 +758,7 @@ impl Channel {
         Ok(self.read_timeout(buf, is_stderr, None)?)
     }
 
-    fn write_impl(&self, buf: &[u8], is_stderr: bool) -> SshResult<usize> {
+    pub(crate) fn write_impl(&self, buf: &[u8], is_stderr: bool) -> SshResult<usize> {
         let (sess, chan) = self.lock_session();
 
         let res = unsafe {
@@ -659,3 +856,89 @@ pub enum PollStatus {
     /// The channel is in the EOF state
     EndOfFile,
 }
+
+/// The parameters of a PTY requested by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PtyRequest {
+    /// The value for the `TERM` environment variable
+    pub term: String,
+    /// The width of the terminal, in characters
+    pub cols: u32,
+    /// The height of the terminal, in characters
+    pub rows: u32,
+    /// The width of the terminal, in pixels.  May be 0.
+    pub px_width: u32,
+    /// The height of the terminal, in pixels.  May be 0.
+    pub px_height: u32,
+}
+
+impl PtyRequest {
+    /// Returns the dimensions of the requested PTY
+    pub fn size(&self) -> PtySize {
+        PtySize {
+            cols: self.cols,
+            rows: self.rows,
+            px_width: self.px_width,
+            px_height: self.px_height,
+        }
+    }
+}
+
+/// The dimensions of a PTY, as reported by a client when it
+/// requests a PTY or when its window is resized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtySize {
+    /// The width of the terminal, in characters
+    pub cols: u32,
+    /// The height of the terminal, in characters
+    pub rows: u32,
+    /// The width of the terminal, in pixels.  May be 0.
+    pub px_width: u32,
+    /// The height of the terminal, in pixels.  May be 0.
+    pub px_height: u32,
+}
+
+/// Receives the requests that a client makes on a server-side
+/// session `Channel`.
+///
+/// Each method returns `true` to accept the request, or `false` to
+/// reject it, and defaults to rejecting the request, so you only need
+/// to implement the methods for the requests that your server supports.
+///
+/// The methods are called while the `Session` is processing events,
+/// with the session locked, so they must not call methods on the
+/// `Session` or any of its `Channel`s.  An accepted exec or shell request
+/// is typically recorded so that your application can then start the
+/// command and write its output to the channel.
+pub trait ChannelRequestHandler: Send {
+    /// The client wants a PTY to be allocated for the command or shell.
+    fn pty_request(&mut self, _request: &PtyRequest) -> bool {
+        false
+    }
+
+    /// The client's window, and thus the PTY, has been resized.
+    fn pty_window_change(&mut self, _size: PtySize) -> bool {
+        false
+    }
+
+    /// The client wants to start the user's shell.
+    fn shell_request(&mut self) -> bool {
+        false
+    }
+
+    /// The client wants to run `command`.
+    fn exec_request(&mut self, _command: &str) -> bool {
+        false
+    }
+
+    /// The client wants to set an environment variable for the
+    /// command or shell that it will start.
+    fn env_request(&mut self, _name: &str, _value: &str) -> bool {
+        false
+    }
+
+    /// The client wants to start a subsystem, such as `"sftp"`.
+    fn subsystem_request(&mut self, _subsystem: &str) -> bool {
+        false
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{Channel, Error, RawSocket, Session, SessionHolder, SshResult};
+use libssh_rs_sys as sys;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+/// Selects which of the data streams of a channel a
+/// [Connector](struct.Connector.html) reads from or writes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectorStream {
+    /// The regular data stream; stdout for a command on the remote end
+    Stdout,
+    /// The extended data stream; stderr for a command on the remote end
+    Stderr,
+    /// Both the regular and extended data streams.
+    /// When writing to a channel, this is equivalent to `Stderr`.
+    Both,
+}
+
+impl ConnectorStream {
+    fn flags(self) -> sys::ssh_connector_flags_e {
+        match self {
+            Self::Stdout => sys::ssh_connector_flags_e::SSH_CONNECTOR_STDOUT,
+            Self::Stderr => sys::ssh_connector_flags_e::SSH_CONNECTOR_STDERR,
+            Self::Both => sys::ssh_connector_flags_e::SSH_CONNECTOR_BOTH,
+        }
+    }
+}
+
+/// Copies data from one channel or file descriptor to another
+/// without passing through Rust code.
+///
+/// A connector has a single input and a single output, each of which
+/// may be either a channel or a file descriptor; use a pair of
+/// connectors to relay data in both directions.
+/// A connector does nothing by itself; it must be registered with an
+/// [Event](struct.Event.html) which then moves the data as part of
+/// each call to [Event::dopoll](struct.Event.html#method.dopoll).
+///
+/// The channels that a connector is attached to are borrowed for
+/// the lifetime of the connector.
+pub struct Connector<'a> {
+    sess: Arc<Mutex<SessionHolder>>,
+    pub(crate) connector: sys::ssh_connector,
+    sessions: Vec<Arc<Mutex<SessionHolder>>>,
+    _channels: PhantomData<&'a Channel>,
+}
+
+unsafe impl Send for Connector<'_> {}
+
+impl Drop for Connector<'_> {
+    fn drop(&mut self) {
+        // Freeing the connector removes its callbacks from the channels,
+        // so hold their sessions while that happens
+        let _sessions: Vec<_> = self.sessions().iter().map(|s| s.lock().unwrap()).collect();
+        unsafe { sys::ssh_connector_free(self.connector) }
+    }
+}
+
+impl<'a> Connector<'a> {
+    /// Create a new connector for use with channels belonging to `session`
+    pub fn new(session: &Session) -> SshResult<Self> {
+        let sess = session.sess.lock().unwrap();
+        let connector = unsafe { sys::ssh_connector_new(**sess) };
+        if connector.is_null() {
+            return Err(sess
+                .last_error()
+                .unwrap_or_else(|| Error::fatal("ssh_connector_new failed")));
+        }
+        drop(sess);
+        Ok(Self {
+            sess: Arc::clone(&session.sess),
+            connector,
+            sessions: vec![],
+            _channels: PhantomData,
+        })
+    }
+
+    /// The sessions that own the channels attached to this
+    /// connector, or the session that it was created from if
+    /// there are none
+    pub(crate) fn sessions(&self) -> &[Arc<Mutex<SessionHolder>>] {
+        if self.sessions.is_empty() {
+            std::slice::from_ref(&self.sess)
+        } else {
+            &self.sessions
+        }
+    }
+
+    fn track_session(&mut self, channel: &Channel) {
+        if !self.sessions.iter().any(|s| Arc::ptr_eq(s, &channel.sess)) {
+            self.sessions.push(Arc::clone(&channel.sess));
+        }
+    }
+
+    /// Read data from the specified stream(s) of `channel`
+    pub fn set_in_channel(
+        &mut self,
+        channel: &'a Channel,
+        stream: ConnectorStream,
+    ) -> SshResult<()> {
+        let (sess, chan) = channel.lock_session();
+        let res =
+            unsafe { sys::ssh_connector_set_in_channel(self.connector, chan, stream.flags()) };
+        sess.basic_status(res, "ssh_connector_set_in_channel failed")?;
+        drop(sess);
+        self.track_session(channel);
+        Ok(())
+    }
+
+    /// Write data to the specified stream of `channel`
+    pub fn set_out_channel(
+        &mut self,
+        channel: &'a Channel,
+        stream: ConnectorStream,
+    ) -> SshResult<()> {
+        let (sess, chan) = channel.lock_session();
+        let res =
+            unsafe { sys::ssh_connector_set_out_channel(self.connector, chan, stream.flags()) };
+        sess.basic_status(res, "ssh_connector_set_out_channel failed")?;
+        drop(sess);
+        self.track_session(channel);
+        Ok(())
+    }
+
+    /// Read data from the file descriptor `fd`.
+    /// The descriptor remains owned by the caller and must remain
+    /// open for as long as the connector is in use.
+    pub fn set_in_fd(&mut self, fd: RawSocket) {
+        let _sess = self.sess.lock().unwrap();
+        unsafe { sys::ssh_connector_set_in_fd(self.connector, fd as sys::socket_t) }
+    }
+
+    /// Write data to the file descriptor `fd`.
+    /// The descriptor remains owned by the caller and must remain
+    /// open for as long as the connector is in use.
+    pub fn set_out_fd(&mut self, fd: RawSocket) {
+        let _sess = self.sess.lock().unwrap();
+        unsafe { sys::ssh_connector_set_out_fd(self.connector, fd as sys::socket_t) }
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{Connector, Error, RawSocket, Session, SessionHolder, SshResult};
+use libssh_rs_sys as sys;
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_short, c_void};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[cfg(unix)]
+mod poll_bits {
+    use std::os::raw::c_short;
+    pub const IN: c_short = libc::POLLIN;
+    pub const PRI: c_short = libc::POLLPRI;
+    pub const OUT: c_short = libc::POLLOUT;
+    pub const ERR: c_short = libc::POLLERR;
+    pub const HUP: c_short = libc::POLLHUP;
+    pub const NVAL: c_short = libc::POLLNVAL;
+}
+
+// libssh uses the WSAPoll definitions on Windows
+#[cfg(windows)]
+mod poll_bits {
+    use std::os::raw::c_short;
+    pub const IN: c_short = 0x0300;
+    pub const PRI: c_short = 0x0400;
+    pub const OUT: c_short = 0x0010;
+    pub const ERR: c_short = 0x0001;
+    pub const HUP: c_short = 0x0002;
+    pub const NVAL: c_short = 0x0004;
+}
+
+bitflags::bitflags! {
+    /// The conditions that can be monitored for a file descriptor
+    /// registered via [Event::add_fd](struct.Event.html#method.add_fd)
+    pub struct PollFlags : c_short {
+        /// There is data to read
+        const IN = poll_bits::IN;
+        /// There is urgent data to read
+        const PRI = poll_bits::PRI;
+        /// Writing is now possible
+        const OUT = poll_bits::OUT;
+        /// An error condition; reported even if not requested
+        const ERR = poll_bits::ERR;
+        /// Hung up; reported even if not requested
+        const HUP = poll_bits::HUP;
+        /// The descriptor is invalid; reported even if not requested
+        const NVAL = poll_bits::NVAL;
+    }
+}
+
+type FdCallback = Box<dyn FnMut(RawSocket, PollFlags) + Send>;
+
+/// An event loop that services multiple sessions and file
+/// descriptors from a single thread.
+///
+/// Polling an `Event` processes incoming data for each of the
+/// registered sessions, dispatching any callbacks that have been
+/// set up on them and their channels, calls the callbacks that
+/// were registered for file descriptors as they become ready, and
+/// moves data through any registered [Connector](struct.Connector.html)s.
+/// The connectors are borrowed for the lifetime of the `Event`.
+///
+/// The registered sessions are locked for the duration of each
+/// call to [dopoll](#method.dopoll), so other threads that use
+/// those sessions will be blocked until it returns.
+pub struct Event<'a> {
+    event: sys::ssh_event,
+    sessions: Vec<Arc<Mutex<SessionHolder>>>,
+    fds: HashMap<RawSocket, Box<FdCallback>>,
+    connectors: Vec<&'a Connector<'a>>,
+}
+
+unsafe impl Send for Event<'_> {}
+
+impl Drop for Event<'_> {
+    fn drop(&mut self) {
+        for connector in std::mem::take(&mut self.connectors) {
+            let _sessions = lock_sessions(connector.sessions().iter());
+            unsafe { sys::ssh_event_remove_connector(self.event, connector.connector) };
+        }
+        for sess in self.sessions.drain(..) {
+            let sess = sess.lock().unwrap();
+            unsafe { sys::ssh_event_remove_session(self.event, **sess) };
+        }
+        for fd in self.fds.keys() {
+            unsafe { sys::ssh_event_remove_fd(self.event, *fd as sys::socket_t) };
+        }
+        unsafe { sys::ssh_event_free(self.event) }
+    }
+}
+
+impl<'a> Event<'a> {
+    /// Create a new, empty, event loop
+    pub fn new() -> SshResult<Self> {
+        let event = unsafe { sys::ssh_event_new() };
+        if event.is_null() {
+            return Err(Error::fatal("ssh_event_new failed"));
+        }
+        Ok(Self {
+            event,
+            sessions: vec![],
+            fds: HashMap::new(),
+            connectors: vec![],
+        })
+    }
+
+    /// Add a session to the event loop.
+    /// The session must be connected, or have been accepted
+    /// by a [Bind](struct.Bind.html).
+    pub fn add_session(&mut self, session: &Session) -> SshResult<()> {
+        if self.sessions.iter().any(|s| Arc::ptr_eq(s, &session.sess)) {
+            return Ok(());
+        }
+        let sess = session.sess.lock().unwrap();
+        let res = unsafe { sys::ssh_event_add_session(self.event, **sess) };
+        if res != sys::SSH_OK as c_int {
+            return Err(sess
+                .last_error()
+                .unwrap_or_else(|| Error::fatal("ssh_event_add_session failed")));
+        }
+        drop(sess);
+        self.sessions.push(Arc::clone(&session.sess));
+        Ok(())
+    }
+
+    /// Remove a session that was previously added via
+    /// [add_session](#method.add_session)
+    pub fn remove_session(&mut self, session: &Session) -> SshResult<()> {
+        let idx = self
+            .sessions
+            .iter()
+            .position(|s| Arc::ptr_eq(s, &session.sess))
+            .ok_or_else(|| Error::fatal("session is not registered with this event"))?;
+        let sess = self.sessions.remove(idx);
+        if self.connector_sessions().any(|s| Arc::ptr_eq(s, &sess)) {
+            // Still needed by a connector
+            return Ok(());
+        }
+        let sess = sess.lock().unwrap();
+        let res = unsafe { sys::ssh_event_remove_session(self.event, **sess) };
+        sess.basic_status(res, "ssh_event_remove_session failed")
+    }
+
+    /// Add a connector to the event loop.
+    /// The sessions that own the channels attached to the connector
+    /// are polled as part of the event loop for as long as the
+    /// connector remains registered.
+    pub fn add_connector(&mut self, connector: &'a Connector<'a>) -> SshResult<()> {
+        if self
+            .connectors
+            .iter()
+            .any(|c| c.connector == connector.connector)
+        {
+            return Ok(());
+        }
+        let sessions = lock_sessions(connector.sessions().iter());
+        let res = unsafe { sys::ssh_event_add_connector(self.event, connector.connector) };
+        if res != sys::SSH_OK as c_int {
+            return Err(sessions
+                .iter()
+                .find_map(|sess| sess.last_error())
+                .unwrap_or_else(|| Error::fatal("ssh_event_add_connector failed")));
+        }
+        drop(sessions);
+        self.connectors.push(connector);
+        Ok(())
+    }
+
+    /// Remove a connector that was previously added via
+    /// [add_connector](#method.add_connector)
+    pub fn remove_connector(&mut self, connector: &Connector) -> SshResult<()> {
+        let idx = self
+            .connectors
+            .iter()
+            .position(|c| c.connector == connector.connector)
+            .ok_or_else(|| Error::fatal("connector is not registered with this event"))?;
+        self.connectors.remove(idx);
+
+        let sessions = lock_sessions(connector.sessions().iter());
+        let res = unsafe { sys::ssh_event_remove_connector(self.event, connector.connector) };
+        if res != sys::SSH_OK as c_int {
+            return Err(Error::fatal("ssh_event_remove_connector failed"));
+        }
+
+        // libssh removes the connector's sessions from the event along
+        // with the connector, so put back any that are still needed
+        for (holder, sess) in connector.sessions().iter().zip(sessions.iter()) {
+            let needed = self.sessions.iter().any(|s| Arc::ptr_eq(s, holder))
+                || self.connector_sessions().any(|s| Arc::ptr_eq(s, holder));
+            if needed {
+                let res = unsafe { sys::ssh_event_add_session(self.event, ***sess) };
+                sess.basic_status(res, "ssh_event_add_session failed")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn connector_sessions(&self) -> impl Iterator<Item = &Arc<Mutex<SessionHolder>>> {
+        self.connectors.iter().flat_map(|c| c.sessions().iter())
+    }
+
+    /// Monitor `fd` for the conditions specified by `events`.
+    /// `callback` is called with the descriptor and the conditions
+    /// that are present whenever it becomes ready.
+    ///
+    /// The descriptor remains owned by the caller, and must remain
+    /// open until it is removed via [remove_fd](#method.remove_fd) or
+    /// the `Event` is dropped.
+    /// Registering a descriptor that is already registered replaces
+    /// its events and callback.
+    pub fn add_fd<F>(&mut self, fd: RawSocket, events: PollFlags, callback: F) -> SshResult<()>
+    where
+        F: FnMut(RawSocket, PollFlags) + Send + 'static,
+    {
+        if self.fds.contains_key(&fd) {
+            self.remove_fd(fd)?;
+        }
+        let mut callback: Box<FdCallback> = Box::new(Box::new(callback));
+        let res = unsafe {
+            sys::ssh_event_add_fd(
+                self.event,
+                fd as sys::socket_t,
+                events.bits(),
+                Some(bridge_fd_callback),
+                &mut *callback as *mut FdCallback as *mut c_void,
+            )
+        };
+        if res != sys::SSH_OK as c_int {
+            return Err(Error::fatal("ssh_event_add_fd failed"));
+        }
+        self.fds.insert(fd, callback);
+        Ok(())
+    }
+
+    /// Stop monitoring a descriptor that was previously added via
+    /// [add_fd](#method.add_fd)
+    pub fn remove_fd(&mut self, fd: RawSocket) -> SshResult<()> {
+        if self.fds.remove(&fd).is_none() {
+            return Err(Error::fatal("fd is not registered with this event"));
+        }
+        let res = unsafe { sys::ssh_event_remove_fd(self.event, fd as sys::socket_t) };
+        if res != sys::SSH_OK as c_int {
+            return Err(Error::fatal("ssh_event_remove_fd failed"));
+        }
+        Ok(())
+    }
+
+    /// Wait for activity on any of the registered sessions and
+    /// descriptors, and process it.
+    /// If `timeout` is `None`, waits indefinitely.
+    /// Returns `Error::TryAgain` if the timeout expired
+    /// before there was any activity.
+    pub fn dopoll(&mut self, timeout: Option<Duration>) -> SshResult<()> {
+        let timeout = match timeout {
+            Some(t) => t.as_millis() as c_int,
+            None => -1,
+        };
+        let sessions = lock_sessions(self.sessions.iter().chain(self.connector_sessions()));
+        let res = unsafe { sys::ssh_event_dopoll(self.event, timeout) };
+        match res {
+            sys::SSH_AGAIN => Err(Error::TryAgain),
+            sys::SSH_ERROR => Err(sessions
+                .iter()
+                .find_map(|sess| sess.last_error())
+                .unwrap_or_else(|| Error::fatal("ssh_event_dopoll failed"))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Lock each of the distinct sessions in `sessions`, in order
+fn lock_sessions<'s>(
+    sessions: impl Iterator<Item = &'s Arc<Mutex<SessionHolder>>>,
+) -> Vec<MutexGuard<'s, SessionHolder>> {
+    let mut unique: Vec<&Arc<Mutex<SessionHolder>>> = vec![];
+    for sess in sessions {
+        if !unique.iter().any(|s| Arc::ptr_eq(s, sess)) {
+            unique.push(sess);
+        }
+    }
+    unique.into_iter().map(|s| s.lock().unwrap()).collect()
+}
+
+unsafe extern "C" fn bridge_fd_callback(
+    fd: sys::socket_t,
+    revents: c_int,
+    userdata: *mut c_void,
+) -> c_int {
+    let callback: &mut FdCallback = &mut *(userdata as *mut FdCallback);
+    let revents = PollFlags::from_bits_truncate(revents as c_short);
+    if let Err(err) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        callback(fd as RawSocket, revents)
+    })) {
+        eprintln!("Error in fd callback: {:?}", err);
+    }
+    0
+}
@@ -0,0 +1,668 @@
+use crate::{Channel, Error, Session, SshResult};
+use libssh_rs_sys as sys;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+mod socks;
+
+/// How long to wait for activity before checking in with libssh
+/// again; libssh may have buffered data from the socket, so we
+/// can't wait indefinitely on the socket alone.
+/// This also bounds how long it takes to stop a forwarder.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size of the buffer used to move data between a channel and
+/// a local socket
+const BUFFER_SIZE: usize = 32 * 1024;
+
+/// A local socket whose data is relayed to and from a channel
+pub(crate) trait LocalStream: Read + Write + AsRawFd + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+    fn shutdown_write(&self) -> std::io::Result<()>;
+}
+
+impl LocalStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown_write(&self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl LocalStream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown_write(&self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Produces the connections that a `ForwardLoop` relays
+pub(crate) trait Acceptor: Send {
+    /// The descriptors that become readable when `accept` may have
+    /// something to return.  `accept` is also called at least once
+    /// every `POLL_INTERVAL`.
+    fn raw_fds(&self) -> Vec<RawFd>;
+
+    /// Returns the next new connection, if any.
+    /// Failures that only affect a single connection should be
+    /// handled by discarding that connection; returning an error
+    /// stops the forwarder.
+    fn accept(&mut self, session: &Session) -> SshResult<Option<Relay>>;
+
+    /// Returns `true` once `accept` will never return anything more,
+    /// so that the forwarder can stop when its relays have finished
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+/// Copies data in both directions between a channel and a local
+/// socket, propagating EOF from each side to the other.
+pub(crate) struct Relay {
+    channel: Channel,
+    stream: Box<dyn LocalStream>,
+    to_channel: Vec<u8>,
+    to_stream: Vec<u8>,
+    channel_eof: bool,
+    stream_eof: bool,
+    failed: bool,
+}
+
+impl Relay {
+    pub(crate) fn new(channel: Channel, stream: Box<dyn LocalStream>) -> SshResult<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            channel,
+            stream,
+            to_channel: vec![],
+            to_stream: vec![],
+            channel_eof: false,
+            stream_eof: false,
+            failed: false,
+        })
+    }
+
+    /// Moves whatever data is ready in either direction.
+    /// Returns `true` if anything happened.
+    fn pump(&mut self, buf: &mut [u8]) -> SshResult<bool> {
+        let mut progress = false;
+
+        if self.to_stream.is_empty() && !self.channel_eof {
+            let n = self.channel.read_nonblocking(buf, false)?;
+            if n > 0 {
+                self.to_stream.extend_from_slice(&buf[..n]);
+                progress = true;
+            } else if self.channel.is_eof() || self.channel.is_closed() {
+                self.channel_eof = true;
+                let _ = self.stream.shutdown_write();
+                progress = true;
+            }
+        }
+        if !self.to_stream.is_empty() {
+            match self.stream.write(&self.to_stream) {
+                Ok(n) => {
+                    self.to_stream.drain(..n);
+                    progress = true;
+                }
+                Err(err) if would_block(&err) => {}
+                Err(_) => {
+                    // The local end has gone away
+                    self.failed = true;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if self.to_channel.is_empty() && !self.stream_eof {
+            match self.stream.read(buf) {
+                Ok(0) => {
+                    self.stream_eof = true;
+                    self.channel.send_eof()?;
+                    progress = true;
+                }
+                Ok(n) => {
+                    self.to_channel.extend_from_slice(&buf[..n]);
+                    progress = true;
+                }
+                Err(err) if would_block(&err) => {}
+                Err(_) => {
+                    self.failed = true;
+                    return Ok(true);
+                }
+            }
+        }
+        if !self.to_channel.is_empty() {
+            // Don't write more than the window allows, as that
+            // would block until the remote end consumes it
+            let len = self.channel.window_size().min(self.to_channel.len());
+            if len > 0 {
+                match self.channel.write_impl(&self.to_channel[..len], false) {
+                    Ok(n) => {
+                        self.to_channel.drain(..n);
+                        progress = true;
+                    }
+                    Err(Error::TryAgain) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    fn is_finished(&self) -> bool {
+        let channel_done = self.channel_eof && self.to_stream.is_empty();
+        let stream_done = self.stream_eof && self.to_channel.is_empty();
+        self.failed || (channel_done && (stream_done || self.channel.is_closed()))
+    }
+
+    /// Whether we are waiting for data from the channel
+    fn wants_channel(&self) -> bool {
+        (self.to_stream.is_empty() && !self.channel_eof) || !self.to_channel.is_empty()
+    }
+
+    fn poll_events(&self) -> libc::c_short {
+        let mut events = 0;
+        if self.to_channel.is_empty() && !self.stream_eof {
+            events |= libc::POLLIN;
+        }
+        if !self.to_stream.is_empty() {
+            events |= libc::POLLOUT;
+        }
+        events
+    }
+}
+
+fn would_block(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+}
+
+/// Accepts connections and relays them until asked to stop
+pub(crate) struct ForwardLoop {
+    session: Session,
+    acceptor: Box<dyn Acceptor>,
+    relays: Vec<Relay>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ForwardLoop {
+    fn run(mut self) -> SshResult<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        while !self.stop.load(Ordering::Relaxed) {
+            let mut progress = false;
+
+            while let Some(relay) = self.acceptor.accept(&self.session)? {
+                self.relays.push(relay);
+                progress = true;
+            }
+
+            for relay in &mut self.relays {
+                match relay.pump(&mut buf) {
+                    Ok(true) => progress = true,
+                    Ok(false) => {}
+                    Err(_) => relay.failed = true,
+                }
+            }
+            self.relays.retain(|relay| {
+                if relay.is_finished() {
+                    let _ = relay.channel.close();
+                    false
+                } else {
+                    true
+                }
+            });
+
+            if self.relays.is_empty() && self.acceptor.is_exhausted() {
+                return Ok(());
+            }
+            if !self.session.is_connected() {
+                return Err(Error::fatal("the session was disconnected"));
+            }
+            if !progress {
+                self.wait_for_io()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until there may be something to do, or `POLL_INTERVAL`
+    /// elapses.
+    fn wait_for_io(&self) -> SshResult<()> {
+        let mut fds = vec![];
+        if self.relays.iter().any(Relay::wants_channel) {
+            let sess = self.session.lock_session();
+            fds.push(libc::pollfd {
+                fd: unsafe { sys::ssh_get_fd(**sess) },
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        for fd in self.acceptor.raw_fds() {
+            fds.push(libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        for relay in &self.relays {
+            fds.push(libc::pollfd {
+                fd: relay.stream.as_raw_fd(),
+                events: relay.poll_events(),
+                revents: 0,
+            });
+        }
+        let res = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                POLL_INTERVAL.as_millis() as libc::c_int,
+            )
+        };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs a `ForwardLoop` on a background thread, stopping it
+/// when dropped
+pub(crate) struct ForwardThread {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<SshResult<()>>>,
+}
+
+impl ForwardThread {
+    pub(crate) fn spawn<A: Acceptor + 'static>(session: &Session, acceptor: A) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let forward = ForwardLoop {
+            session: Session {
+                sess: Arc::clone(&session.sess),
+            },
+            acceptor: Box::new(acceptor),
+            relays: vec![],
+            stop: Arc::clone(&stop),
+        };
+        let thread = std::thread::spawn(move || forward.run());
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .map(|thread| !thread.is_finished())
+            .unwrap_or(false)
+    }
+
+    /// Lets the loop run until its acceptor is exhausted and
+    /// all of its relays have finished
+    pub(crate) fn detach(mut self) {
+        self.thread.take();
+    }
+
+    pub(crate) fn stop(&mut self) -> SshResult<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(Error::fatal("the forwarding thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ForwardThread {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Accepts local TCP connections and opens a `direct-tcpip`
+/// channel for each of them
+struct TcpAcceptor {
+    listener: TcpListener,
+    remote_host: String,
+    remote_port: u16,
+}
+
+impl Acceptor for TcpAcceptor {
+    fn raw_fds(&self) -> Vec<RawFd> {
+        vec![self.listener.as_raw_fd()]
+    }
+
+    fn accept(&mut self, session: &Session) -> SshResult<Option<Relay>> {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if would_block(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let channel = session.new_channel()?;
+            match channel.open_forward(
+                &self.remote_host,
+                self.remote_port,
+                &peer.ip().to_string(),
+                peer.port(),
+            ) {
+                Ok(()) => return Ok(Some(Relay::new(channel, Box::new(stream))?)),
+                // The server refused this connection; drop it and carry on
+                Err(_) if session.is_connected() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Accepts local unix domain socket connections and opens a
+/// `direct-streamlocal@openssh.com` channel for each of them
+struct UnixAcceptor {
+    listener: UnixListener,
+    remote_path: String,
+}
+
+impl Acceptor for UnixAcceptor {
+    fn raw_fds(&self) -> Vec<RawFd> {
+        vec![self.listener.as_raw_fd()]
+    }
+
+    fn accept(&mut self, session: &Session) -> SshResult<Option<Relay>> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if would_block(&err) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let channel = session.new_channel()?;
+            match channel.open_forward_unix(&self.remote_path, "localhost", 0) {
+                Ok(()) => return Ok(Some(Relay::new(channel, Box::new(stream))?)),
+                Err(_) if session.is_connected() => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// A running local port forward, as created by
+/// [Session::forward_local](struct.Session.html#method.forward_local)
+/// or [Session::forward_local_unix](struct.Session.html#method.forward_local_unix).
+///
+/// The forward stops, closing the listening socket and all of the
+/// connections that it accepted, when this handle is dropped.
+pub struct LocalForward {
+    local_addr: Option<SocketAddr>,
+    local_path: Option<PathBuf>,
+    thread: ForwardThread,
+}
+
+impl LocalForward {
+    /// Returns the address of the listening TCP socket, which
+    /// is useful when binding to port `0`.
+    /// Returns `None` if the forward listens on a unix domain socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns the path of the listening unix domain socket, or `None`
+    /// if the forward listens on a TCP socket
+    pub fn local_path(&self) -> Option<&Path> {
+        self.local_path.as_deref()
+    }
+
+    /// Returns `false` if the forward has stopped because of an error.
+    /// Use [stop](#method.stop) to find out what the error was.
+    pub fn is_running(&self) -> bool {
+        self.thread.is_running()
+    }
+
+    /// Stops the forward, returning the error that caused it to
+    /// stop early, if any
+    pub fn stop(mut self) -> SshResult<()> {
+        self.thread.stop()
+    }
+}
+
+impl Drop for LocalForward {
+    fn drop(&mut self) {
+        let _ = self.thread.stop();
+        if let Some(path) = &self.local_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The local destination for connections arriving via a
+/// [RemoteForward](struct.RemoteForward.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardTarget {
+    /// Connect to the TCP port `port` on `host`
+    Tcp(String, u16),
+    /// Connect to the unix domain socket at the specified path
+    Unix(PathBuf),
+}
+
+impl ForwardTarget {
+    fn connect(&self) -> std::io::Result<Box<dyn LocalStream>> {
+        Ok(match self {
+            Self::Tcp(host, port) => Box::new(TcpStream::connect((host.as_str(), *port))?),
+            Self::Unix(path) => Box::new(UnixStream::connect(path)?),
+        })
+    }
+}
+
+type TargetMap = Arc<Mutex<HashMap<u16, ForwardTarget>>>;
+
+/// Accepts forwarded channels from the server and connects each
+/// of them to the target configured for its destination port
+struct RemoteAcceptor {
+    targets: TargetMap,
+}
+
+impl Acceptor for RemoteAcceptor {
+    fn raw_fds(&self) -> Vec<RawFd> {
+        // accept_forward waits for the session socket itself
+        vec![]
+    }
+
+    fn accept(&mut self, session: &Session) -> SshResult<Option<Relay>> {
+        loop {
+            let (port, channel) = match session.accept_forward(Duration::from_millis(0)) {
+                Ok(accepted) => accepted,
+                Err(Error::TryAgain) => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            let target = self.targets.lock().unwrap().get(&port).cloned();
+            // If there is no target, or it can't be reached, then
+            // dropping the channel closes it and the server will
+            // disconnect the remote client
+            if let Some(target) = target {
+                if let Ok(stream) = target.connect() {
+                    return Ok(Some(Relay::new(channel, stream)?));
+                }
+            }
+        }
+    }
+}
+
+/// A set of remote port forwards, as created by
+/// [Session::forward_remote](struct.Session.html#method.forward_remote).
+///
+/// Connections made to the forwarded ports on the server are
+/// connected to the corresponding local targets.
+/// The forwards are cancelled and all of the connections are
+/// closed when this handle is dropped.
+pub struct RemoteForward {
+    session: Session,
+    listeners: Vec<(Option<String>, u16)>,
+    targets: TargetMap,
+    thread: ForwardThread,
+}
+
+impl RemoteForward {
+    /// Asks the server to listen on `bind_address` and `port`
+    /// in the same way as [Session::forward_remote](struct.Session.html#method.forward_remote),
+    /// and adds it to this set of forwards.
+    /// Returns the port that the server bound.
+    pub fn add(
+        &mut self,
+        bind_address: Option<&str>,
+        port: u16,
+        target: ForwardTarget,
+    ) -> SshResult<u16> {
+        let bound_port = self.session.listen_forward(bind_address, port)?;
+        let bound_port = if bound_port == 0 { port } else { bound_port };
+        self.targets.lock().unwrap().insert(bound_port, target);
+        self.listeners
+            .push((bind_address.map(|s| s.to_string()), bound_port));
+        Ok(bound_port)
+    }
+
+    /// Returns the ports that the server is listening on
+    pub fn bound_ports(&self) -> Vec<u16> {
+        self.listeners.iter().map(|(_, port)| *port).collect()
+    }
+
+    /// Returns `false` if forwarding has stopped because of an error.
+    /// Use [stop](#method.stop) to find out what the error was.
+    pub fn is_running(&self) -> bool {
+        self.thread.is_running()
+    }
+
+    /// Cancels the forwards, returning the error that caused
+    /// forwarding to stop early, if any
+    pub fn stop(mut self) -> SshResult<()> {
+        let result = self.thread.stop();
+        self.cancel();
+        result
+    }
+
+    fn cancel(&mut self) {
+        for (bind_address, port) in self.listeners.drain(..) {
+            let _ = self.session.cancel_forward(bind_address.as_deref(), port);
+        }
+    }
+}
+
+impl Drop for RemoteForward {
+    fn drop(&mut self) {
+        let _ = self.thread.stop();
+        self.cancel();
+    }
+}
+
+impl Session {
+    /// Forwards connections made to a local TCP port to
+    /// `remote_host:remote_port` via the remote server;
+    /// this is the equivalent of `ssh -L`.
+    ///
+    /// Listens on `bind_addr` and opens a new forwarding channel for
+    /// each accepted connection, then copies data in both directions
+    /// until both sides have sent EOF or either side closes.
+    /// The connections are serviced by a background thread, which
+    /// locks the session while it works with the channels.
+    ///
+    /// The session must be in blocking mode.
+    pub fn forward_local<A: ToSocketAddrs>(
+        &self,
+        bind_addr: A,
+        remote_host: &str,
+        remote_port: u16,
+    ) -> SshResult<LocalForward> {
+        let listener = TcpListener::bind(bind_addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let thread = ForwardThread::spawn(
+            self,
+            TcpAcceptor {
+                listener,
+                remote_host: remote_host.to_string(),
+                remote_port,
+            },
+        );
+        Ok(LocalForward {
+            local_addr: Some(local_addr),
+            local_path: None,
+            thread,
+        })
+    }
+
+    /// Forwards connections made to a local unix domain socket to the
+    /// unix domain socket at `remote_path` on the remote server.
+    ///
+    /// Creates a listening socket at `bind_path`, which is removed again
+    /// when the forward is dropped, and otherwise behaves in the same
+    /// way as [forward_local](#method.forward_local).
+    pub fn forward_local_unix<P: AsRef<Path>>(
+        &self,
+        bind_path: P,
+        remote_path: &str,
+    ) -> SshResult<LocalForward> {
+        let bind_path = bind_path.as_ref();
+        let listener = UnixListener::bind(bind_path)?;
+        listener.set_nonblocking(true)?;
+        let thread = ForwardThread::spawn(
+            self,
+            UnixAcceptor {
+                listener,
+                remote_path: remote_path.to_string(),
+            },
+        );
+        Ok(LocalForward {
+            local_addr: None,
+            local_path: Some(bind_path.to_path_buf()),
+            thread,
+        })
+    }
+
+    /// Asks the server to forward connections made to `port` on its
+    /// `bind_address` to the local `target`; this is the equivalent of
+    /// `ssh -R`.
+    ///
+    /// `bind_address` and `port` have the same meaning as for
+    /// [listen_forward](#method.listen_forward).  Further forwards can be
+    /// added to the returned handle via
+    /// [RemoteForward::add](struct.RemoteForward.html#method.add).
+    /// Each forwarded connection is serviced by a background thread in
+    /// the same way as for [forward_local](#method.forward_local).
+    ///
+    /// The session must be in blocking mode, and nothing else should be
+    /// calling [accept_forward](#method.accept_forward) on it.
+    pub fn forward_remote(
+        &self,
+        bind_address: Option<&str>,
+        port: u16,
+        target: ForwardTarget,
+    ) -> SshResult<RemoteForward> {
+        let targets = TargetMap::default();
+        let mut forward = RemoteForward {
+            session: Session {
+                sess: Arc::clone(&self.sess),
+            },
+            listeners: vec![],
+            targets: Arc::clone(&targets),
+            thread: ForwardThread::spawn(self, RemoteAcceptor { targets }),
+        };
+        forward.add(bind_address, port, target)?;
+        Ok(forward)
+    }
+}
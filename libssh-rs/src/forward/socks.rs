@@ -0,0 +1,462 @@
+use super::{would_block, Acceptor, ForwardThread, LocalForward, Relay};
+use crate::{Error, Session, SshResult};
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+/// How long a client has to complete the SOCKS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most handshake data that we'll buffer for a client;
+/// a well formed request is much smaller than this
+const MAX_HANDSHAKE_LEN: usize = 1024;
+
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+const SOCKS5_SUCCEEDED: u8 = 0x00;
+const SOCKS5_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const SOCKS5_NO_AUTHENTICATION: u8 = 0x00;
+const SOCKS5_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const SOCKS_CONNECT: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Socks4,
+    Socks5,
+}
+
+impl Version {
+    /// Builds the reply to a CONNECT request
+    fn reply(self, success: bool, socks5_code: u8) -> Vec<u8> {
+        match self {
+            Self::Socks4 => {
+                let code = if success {
+                    SOCKS4_GRANTED
+                } else {
+                    SOCKS4_REJECTED
+                };
+                vec![0, code, 0, 0, 0, 0, 0, 0]
+            }
+            // We don't know the address that the server bound,
+            // so report the unspecified IPv4 address
+            Self::Socks5 => vec![5, socks5_code, 0, 1, 0, 0, 0, 0, 0, 0],
+        }
+    }
+}
+
+/// The outcome of feeding client data to a `Handshake`
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// More data is needed from the client
+    Incomplete,
+    /// Send this to the client, and then continue the handshake
+    Reply(Vec<u8>),
+    /// Send this to the client, and then close the connection
+    Fail(Vec<u8>),
+    /// The client wants to connect to `host:port`
+    Connect {
+        version: Version,
+        host: String,
+        port: u16,
+    },
+}
+
+/// Incrementally parses the client side of a SOCKS4, SOCKS4a or
+/// SOCKS5 handshake
+#[derive(Default)]
+struct Handshake {
+    input: Vec<u8>,
+    /// Set once a SOCKS5 client has negotiated the auth method
+    negotiated: bool,
+}
+
+impl Handshake {
+    /// Parses the buffered input, consuming whatever it was able to
+    fn advance(&mut self) -> Step {
+        let step = match self.input.first() {
+            None => return Step::Incomplete,
+            Some(4) => parse_socks4(&self.input),
+            Some(5) if !self.negotiated => parse_socks5_greeting(&self.input),
+            Some(5) => parse_socks5_request(&self.input),
+            // Not a protocol that we know; there's no way to
+            // tell the client, so just hang up
+            Some(_) => Some((self.input.len(), Step::Fail(vec![]))),
+        };
+        match step {
+            Some((len, step)) => {
+                self.input.drain(..len);
+                if let Step::Reply(_) = &step {
+                    self.negotiated = true;
+                }
+                step
+            }
+            None if self.input.len() > MAX_HANDSHAKE_LEN => Step::Fail(vec![]),
+            None => Step::Incomplete,
+        }
+    }
+}
+
+/// Reads a NUL terminated string from the start of `buf`,
+/// returning it and the length that it occupied
+fn nul_terminated(buf: &[u8]) -> Option<(&[u8], usize)> {
+    let len = buf.iter().position(|&b| b == 0)?;
+    Some((&buf[..len], len + 1))
+}
+
+/// Parses a SOCKS4 or SOCKS4a request.
+/// Returns the number of bytes consumed along with the outcome,
+/// or `None` if the request is incomplete.
+fn parse_socks4(buf: &[u8]) -> Option<(usize, Step)> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let command = buf[1];
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    let (_user, user_len) = nul_terminated(&buf[8..])?;
+    let mut len = 8 + user_len;
+
+    // SOCKS4a signals a hostname with an address of 0.0.0.x, x != 0
+    let octets = ip.octets();
+    let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let (host, host_len) = nul_terminated(&buf[len..])?;
+        len += host_len;
+        String::from_utf8_lossy(host).to_string()
+    } else {
+        ip.to_string()
+    };
+
+    if command != SOCKS_CONNECT {
+        return Some((len, Step::Fail(Version::Socks4.reply(false, 0))));
+    }
+    Some((
+        len,
+        Step::Connect {
+            version: Version::Socks4,
+            host,
+            port,
+        },
+    ))
+}
+
+/// Parses the SOCKS5 method selection message
+fn parse_socks5_greeting(buf: &[u8]) -> Option<(usize, Step)> {
+    let num_methods = *buf.get(1)? as usize;
+    let methods = buf.get(2..2 + num_methods)?;
+    let len = 2 + num_methods;
+    if methods.contains(&SOCKS5_NO_AUTHENTICATION) {
+        Some((len, Step::Reply(vec![5, SOCKS5_NO_AUTHENTICATION])))
+    } else {
+        Some((len, Step::Fail(vec![5, SOCKS5_NO_ACCEPTABLE_METHODS])))
+    }
+}
+
+/// Parses a SOCKS5 request
+fn parse_socks5_request(buf: &[u8]) -> Option<(usize, Step)> {
+    let command = *buf.get(1)?;
+    let address_type = *buf.get(3)?;
+    let (host, addr_len) = match address_type {
+        1 => {
+            let octets: [u8; 4] = buf.get(4..8)?.try_into().unwrap();
+            (Ipv4Addr::from(octets).to_string(), 4)
+        }
+        3 => {
+            let name_len = *buf.get(4)? as usize;
+            let name = buf.get(5..5 + name_len)?;
+            (String::from_utf8_lossy(name).to_string(), 1 + name_len)
+        }
+        4 => {
+            let octets: [u8; 16] = buf.get(4..20)?.try_into().unwrap();
+            (Ipv6Addr::from(octets).to_string(), 16)
+        }
+        _ => {
+            return Some((
+                buf.len(),
+                Step::Fail(Version::Socks5.reply(false, SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED)),
+            ))
+        }
+    };
+    let port_bytes = buf.get(4 + addr_len..6 + addr_len)?;
+    let port = u16::from_be_bytes([port_bytes[0], port_bytes[1]]);
+    let len = 6 + addr_len;
+
+    if command != SOCKS_CONNECT {
+        return Some((
+            len,
+            Step::Fail(Version::Socks5.reply(false, SOCKS5_COMMAND_NOT_SUPPORTED)),
+        ));
+    }
+    Some((
+        len,
+        Step::Connect {
+            version: Version::Socks5,
+            host,
+            port,
+        },
+    ))
+}
+
+/// A client that has yet to complete the SOCKS handshake
+struct PendingClient {
+    stream: TcpStream,
+    peer: SocketAddr,
+    handshake: Handshake,
+    deadline: Instant,
+}
+
+impl PendingClient {
+    /// Reads whatever the client has sent so far.
+    /// Returns `false` if the client has gone away.
+    fn fill(&mut self) -> bool {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => self.handshake.input.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                Err(err) if would_block(&err) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Accepts SOCKS clients and opens a `direct-tcpip` channel to
+/// the destination that each of them requests
+struct SocksAcceptor {
+    listener: TcpListener,
+    pending: Vec<PendingClient>,
+}
+
+impl SocksAcceptor {
+    /// Makes as much progress as possible with the handshake for
+    /// `client`, returning the relay if it completed.
+    /// Returns `Ok(None)` once the client should be discarded.
+    fn handshake(
+        &mut self,
+        session: &Session,
+        mut client: PendingClient,
+    ) -> SshResult<Option<Relay>> {
+        loop {
+            match client.handshake.advance() {
+                Step::Incomplete => {
+                    if Instant::now() < client.deadline {
+                        self.pending.push(client);
+                    }
+                    return Ok(None);
+                }
+                Step::Reply(reply) => {
+                    // Replies are small enough that they won't block
+                    if client.stream.write_all(&reply).is_err() {
+                        return Ok(None);
+                    }
+                }
+                Step::Fail(reply) => {
+                    let _ = client.stream.write_all(&reply);
+                    return Ok(None);
+                }
+                Step::Connect {
+                    version,
+                    host,
+                    port,
+                } => {
+                    let channel = session.new_channel()?;
+                    let opened = channel.open_forward(
+                        &host,
+                        port,
+                        &client.peer.ip().to_string(),
+                        client.peer.port(),
+                    );
+                    let code = match &opened {
+                        Ok(()) => SOCKS5_SUCCEEDED,
+                        Err(Error::RequestDenied(_)) => SOCKS5_CONNECTION_REFUSED,
+                        Err(_) => SOCKS5_GENERAL_FAILURE,
+                    };
+                    let reply = version.reply(opened.is_ok(), code);
+                    if let Err(err) = opened {
+                        let _ = client.stream.write_all(&reply);
+                        if session.is_connected() {
+                            return Ok(None);
+                        }
+                        return Err(err);
+                    }
+                    if client.stream.write_all(&reply).is_err() {
+                        return Ok(None);
+                    }
+                    // Anything that the client sent after its request
+                    // is destined for the server
+                    let mut relay = Relay::new(channel, Box::new(client.stream))?;
+                    relay.to_channel = client.handshake.input;
+                    return Ok(Some(relay));
+                }
+            }
+        }
+    }
+}
+
+impl Acceptor for SocksAcceptor {
+    fn raw_fds(&self) -> Vec<RawFd> {
+        std::iter::once(self.listener.as_raw_fd())
+            .chain(self.pending.iter().map(|client| client.stream.as_raw_fd()))
+            .collect()
+    }
+
+    fn accept(&mut self, session: &Session) -> SshResult<Option<Relay>> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    stream.set_nonblocking(true)?;
+                    self.pending.push(PendingClient {
+                        stream,
+                        peer,
+                        handshake: Handshake::default(),
+                        deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if would_block(&err) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut clients = std::mem::take(&mut self.pending).into_iter();
+        while let Some(mut client) = clients.next() {
+            if !client.fill() {
+                continue;
+            }
+            if let Some(relay) = self.handshake(session, client)? {
+                self.pending.extend(clients);
+                return Ok(Some(relay));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Session {
+    /// Runs a local SOCKS proxy that connects to the requested
+    /// destinations via the remote server;
+    /// this is the equivalent of `ssh -D`.
+    ///
+    /// Listens on `bind_addr` for SOCKS4, SOCKS4a and SOCKS5 clients.
+    /// Each CONNECT request is satisfied by opening a forwarding channel
+    /// to the requested host and port, which may be a domain name that
+    /// is then resolved by the server.  If the server refuses to open
+    /// the channel, the client receives a "connection refused" reply.
+    /// SOCKS5 clients must accept the "no authentication" method.
+    ///
+    /// The connections are serviced by a background thread in the same
+    /// way as for [forward_local](#method.forward_local).
+    pub fn forward_dynamic<A: ToSocketAddrs>(&self, bind_addr: A) -> SshResult<LocalForward> {
+        let listener = TcpListener::bind(bind_addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let thread = ForwardThread::spawn(
+            self,
+            SocksAcceptor {
+                listener,
+                pending: vec![],
+            },
+        );
+        Ok(LocalForward {
+            local_addr: Some(local_addr),
+            local_path: None,
+            thread,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed(handshake: &mut Handshake, data: &[u8]) -> Step {
+        handshake.input.extend_from_slice(data);
+        handshake.advance()
+    }
+
+    #[test]
+    fn socks4() {
+        let mut handshake = Handshake::default();
+        assert_eq!(
+            feed(&mut handshake, &[4, 1, 0, 80, 10, 0]),
+            Step::Incomplete
+        );
+        assert_eq!(
+            feed(&mut handshake, &[0, 1, b'u', 0]),
+            Step::Connect {
+                version: Version::Socks4,
+                host: "10.0.0.1".to_string(),
+                port: 80
+            }
+        );
+
+        let mut handshake = Handshake::default();
+        assert_eq!(
+            feed(
+                &mut handshake,
+                b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00"
+            ),
+            Step::Connect {
+                version: Version::Socks4,
+                host: "example.com".to_string(),
+                port: 443
+            }
+        );
+        assert!(handshake.input.is_empty());
+    }
+
+    #[test]
+    fn socks5() {
+        let mut handshake = Handshake::default();
+        assert_eq!(feed(&mut handshake, &[5, 2, 2, 0]), Step::Reply(vec![5, 0]));
+        assert_eq!(feed(&mut handshake, &[5, 1, 0, 3, 11]), Step::Incomplete);
+        assert_eq!(
+            feed(&mut handshake, b"example.com\x00\x50extra"),
+            Step::Connect {
+                version: Version::Socks5,
+                host: "example.com".to_string(),
+                port: 80
+            }
+        );
+        assert_eq!(handshake.input, b"extra");
+
+        let mut handshake = Handshake::default();
+        feed(&mut handshake, &[5, 1, 0]);
+        let mut request = vec![5, 1, 0, 4];
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&22u16.to_be_bytes());
+        assert_eq!(
+            feed(&mut handshake, &request),
+            Step::Connect {
+                version: Version::Socks5,
+                host: "::1".to_string(),
+                port: 22
+            }
+        );
+    }
+
+    #[test]
+    fn socks5_failures() {
+        let mut handshake = Handshake::default();
+        assert_eq!(
+            feed(&mut handshake, &[5, 1, 2]),
+            Step::Fail(vec![5, SOCKS5_NO_ACCEPTABLE_METHODS])
+        );
+
+        let mut handshake = Handshake::default();
+        feed(&mut handshake, &[5, 1, 0]);
+        assert_eq!(
+            feed(&mut handshake, &[5, 2, 0, 1, 127, 0, 0, 1, 0, 80]),
+            Step::Fail(Version::Socks5.reply(false, SOCKS5_COMMAND_NOT_SUPPORTED))
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::cert::base64_decode;
+use crate::{
+    Error, HostKeyStatus, KnownHostsEntry, KnownHostsFile, PublicKeyHashType, Session, SshKey,
+    SshResult,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The outcome of checking a host key with a `HostKeyVerifier`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyDecision {
+    /// The key is trusted
+    Accept,
+    /// The key is trusted and should be recorded, which
+    /// is done by calling `HostKeyVerifier::remember`
+    AcceptAndRemember,
+    /// The key must not be trusted, for the given reason
+    Reject(String),
+    /// The verifier has no opinion on the key, allowing another
+    /// verifier in a `HostKeyVerifierChain` to decide.
+    /// This is treated the same as `Reject` otherwise.
+    Unknown,
+}
+
+/// A policy for deciding whether to trust the key presented by a server.
+/// Pass an implementation to
+/// [Session::verify_host_key](struct.Session.html#method.verify_host_key)
+/// after connecting.
+pub trait HostKeyVerifier {
+    /// Decide whether `key`, presented by `host` on `port`, is trusted
+    fn verify(&self, host: &str, port: u16, key: &SshKey) -> SshResult<HostKeyDecision>;
+
+    /// Record `key` as trusted for `host` on `port`.
+    /// Called after `verify` returned `AcceptAndRemember`.
+    fn remember(&self, _host: &str, _port: u16, _key: &SshKey) -> SshResult<()> {
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Checks the key of the connected server using `verifier`,
+    /// returning an error if it is not trusted.
+    /// This is an alternative to checking
+    /// [is_known_server](#method.is_known_server), for when the trusted
+    /// keys are not (only) held in known_hosts files.
+    pub fn verify_host_key(&self, verifier: &dyn HostKeyVerifier) -> SshResult<()> {
+        let host = self.get_host_name()?;
+        let port = self.get_port()?;
+        let key = self.get_server_public_key()?;
+        match verifier.verify(&host, port, &key)? {
+            HostKeyDecision::Accept => Ok(()),
+            HostKeyDecision::AcceptAndRemember => verifier.remember(&host, port, &key),
+            HostKeyDecision::Reject(reason) => Err(Error::Fatal(format!(
+                "host key verification failed for {}: {}",
+                host, reason
+            ))),
+            HostKeyDecision::Unknown => Err(Error::Fatal(format!(
+                "host key verification failed for {}: the host key is not known",
+                host
+            ))),
+        }
+    }
+}
+
+fn describe_lines(status: &HostKeyStatus, path: &std::path::Path) -> String {
+    match status {
+        HostKeyStatus::Revoked(entry) => format!(
+            "the key is revoked on line {} of {}",
+            entry.line(),
+            path.display()
+        ),
+        HostKeyStatus::Changed(entries) => format!(
+            "the host key has changed; the old key is on line {} of {}",
+            line_numbers(entries),
+            path.display()
+        ),
+        HostKeyStatus::Other(entries) => format!(
+            "the host is known with a key of another type on line {} of {}",
+            line_numbers(entries),
+            path.display()
+        ),
+        _ => String::new(),
+    }
+}
+
+fn line_numbers(entries: &[&KnownHostsEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| entry.line().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Trusts only the keys that are present in known_hosts files,
+/// including keys signed by a matching `@cert-authority`.
+/// The files are read each time a key is checked.
+#[derive(Debug, Clone)]
+pub struct KnownHostsVerifier {
+    paths: Vec<PathBuf>,
+}
+
+impl KnownHostsVerifier {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            paths: vec![path.into()],
+        }
+    }
+
+    /// Also check the file at `path`, such as the global known_hosts file
+    pub fn with_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+}
+
+impl HostKeyVerifier for KnownHostsVerifier {
+    fn verify(&self, host: &str, port: u16, key: &SshKey) -> SshResult<HostKeyDecision> {
+        let mut accepted = false;
+        for path in &self.paths {
+            let file = KnownHostsFile::load(path)?;
+            match file.check(host, port, key)? {
+                HostKeyStatus::Ok(_) => accepted = true,
+                status @ HostKeyStatus::Revoked(_) | status @ HostKeyStatus::Changed(_) => {
+                    return Ok(HostKeyDecision::Reject(describe_lines(&status, path)))
+                }
+                HostKeyStatus::Other(_) | HostKeyStatus::NotFound => {}
+            }
+        }
+        Ok(if accepted {
+            HostKeyDecision::Accept
+        } else {
+            HostKeyDecision::Unknown
+        })
+    }
+}
+
+/// Trusts any key for a host that is not in the known_hosts file
+/// at `path`, and remembers it by adding it to that file.
+/// Changed and revoked keys are rejected, as are keys for hosts that
+/// are only known with keys of other types, unless
+/// [accept_other_key_types](#method.accept_other_key_types) is set.
+#[derive(Debug, Clone)]
+pub struct TrustOnFirstUse {
+    path: PathBuf,
+    hash: bool,
+    accept_other_key_types: bool,
+}
+
+impl TrustOnFirstUse {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            hash: false,
+            accept_other_key_types: false,
+        }
+    }
+
+    /// Hash the host names of the entries that are added
+    pub fn hash_hosts(mut self, hash: bool) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Also trust and remember the key of a host that is known only
+    /// with keys of other types. This is convenient when servers add
+    /// key types, but allows an attacker to present a key of a type
+    /// that the host isn't known with.
+    pub fn accept_other_key_types(mut self, accept: bool) -> Self {
+        self.accept_other_key_types = accept;
+        self
+    }
+}
+
+impl HostKeyVerifier for TrustOnFirstUse {
+    fn verify(&self, host: &str, port: u16, key: &SshKey) -> SshResult<HostKeyDecision> {
+        let file = KnownHostsFile::load(&self.path)?;
+        let status = file.check(host, port, key)?;
+        Ok(match status {
+            HostKeyStatus::Ok(_) => HostKeyDecision::Accept,
+            HostKeyStatus::Other(_) if self.accept_other_key_types => {
+                HostKeyDecision::AcceptAndRemember
+            }
+            HostKeyStatus::Revoked(_) | HostKeyStatus::Changed(_) | HostKeyStatus::Other(_) => {
+                HostKeyDecision::Reject(describe_lines(&status, &self.path))
+            }
+            HostKeyStatus::NotFound => HostKeyDecision::AcceptAndRemember,
+        })
+    }
+
+    fn remember(&self, host: &str, port: u16, key: &SshKey) -> SshResult<()> {
+        let mut file = KnownHostsFile::load(&self.path)?;
+        file.add(host, port, key, self.hash)?;
+        file.save()
+    }
+}
+
+/// Trusts keys by their SHA256 fingerprint, in the `SHA256:...`
+/// form that is displayed by OpenSSH.
+///
+/// Fingerprints may be allowed for any host or for a specific host;
+/// a host that has specific fingerprints rejects any other key.
+#[derive(Debug, Clone, Default)]
+pub struct FingerprintAllowList {
+    any_host: Vec<Vec<u8>>,
+    hosts: HashMap<String, Vec<Vec<u8>>>,
+}
+
+impl FingerprintAllowList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the key with `fingerprint` for any host
+    pub fn allow(&mut self, fingerprint: &str) -> SshResult<()> {
+        let fingerprint = parse_fingerprint(fingerprint)?;
+        self.any_host.push(fingerprint);
+        Ok(())
+    }
+
+    /// Trust the key with `fingerprint` for `host`
+    pub fn allow_host(&mut self, host: &str, fingerprint: &str) -> SshResult<()> {
+        let fingerprint = parse_fingerprint(fingerprint)?;
+        self.hosts
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(fingerprint);
+        Ok(())
+    }
+}
+
+fn parse_fingerprint(fingerprint: &str) -> SshResult<Vec<u8>> {
+    let encoded = fingerprint.strip_prefix("SHA256:").ok_or_else(|| {
+        Error::Fatal(format!(
+            "{} is not a SHA256 fingerprint of the form SHA256:...",
+            fingerprint
+        ))
+    })?;
+    let decoded = base64_decode(encoded)?;
+    if decoded.len() != 32 {
+        return Err(Error::Fatal(format!(
+            "{} is not a SHA256 fingerprint",
+            fingerprint
+        )));
+    }
+    Ok(decoded)
+}
+
+impl HostKeyVerifier for FingerprintAllowList {
+    fn verify(&self, host: &str, _port: u16, key: &SshKey) -> SshResult<HostKeyDecision> {
+        let fingerprint = key.get_public_key_hash(PublicKeyHashType::Sha256)?;
+        if self.any_host.contains(&fingerprint) {
+            return Ok(HostKeyDecision::Accept);
+        }
+        Ok(match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(allowed) if allowed.contains(&fingerprint) => HostKeyDecision::Accept,
+            Some(_) => HostKeyDecision::Reject(
+                "the host key fingerprint is not in the allow list".to_string(),
+            ),
+            None => HostKeyDecision::Unknown,
+        })
+    }
+}
+
+/// Consults each verifier in turn, until one of them
+/// returns a decision other than `HostKeyDecision::Unknown`
+#[derive(Default)]
+pub struct HostKeyVerifierChain {
+    verifiers: Vec<Box<dyn HostKeyVerifier + Send + Sync>>,
+}
+
+impl HostKeyVerifierChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `verifier` to the end of the chain
+    pub fn with<V: HostKeyVerifier + Send + Sync + 'static>(mut self, verifier: V) -> Self {
+        self.verifiers.push(Box::new(verifier));
+        self
+    }
+}
+
+impl HostKeyVerifierChain {
+    /// Returns the first verifier with an opinion on the key,
+    /// along with its decision
+    fn decide(
+        &self,
+        host: &str,
+        port: u16,
+        key: &SshKey,
+    ) -> SshResult<Option<(&dyn HostKeyVerifier, HostKeyDecision)>> {
+        for verifier in &self.verifiers {
+            match verifier.verify(host, port, key)? {
+                HostKeyDecision::Unknown => continue,
+                decision => return Ok(Some((verifier.as_ref(), decision))),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl HostKeyVerifier for HostKeyVerifierChain {
+    fn verify(&self, host: &str, port: u16, key: &SshKey) -> SshResult<HostKeyDecision> {
+        Ok(self
+            .decide(host, port, key)?
+            .map_or(HostKeyDecision::Unknown, |(_, decision)| decision))
+    }
+
+    /// Asks the verifier that decided to remember the key to do so.
+    /// The verifiers are consulted again to find it, as the
+    /// chain keeps no state between `verify` and `remember`.
+    fn remember(&self, host: &str, port: u16, key: &SshKey) -> SshResult<()> {
+        match self.decide(host, port, key)? {
+            Some((verifier, HostKeyDecision::AcceptAndRemember)) => {
+                verifier.remember(host, port, key)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::KeyType;
+    use std::sync::{Arc, Mutex};
+
+    /// Returns a fixed decision, recording the calls to `remember`
+    struct Fixed {
+        decision: HostKeyDecision,
+        remembered: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl HostKeyVerifier for Fixed {
+        fn verify(&self, _host: &str, _port: u16, _key: &SshKey) -> SshResult<HostKeyDecision> {
+            Ok(self.decision.clone())
+        }
+
+        fn remember(&self, host: &str, _port: u16, _key: &SshKey) -> SshResult<()> {
+            self.remembered.lock().unwrap().push(host.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let key = SshKey::from_pubkey_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIHDlL6YoA9MD9FJqDHaLKqBC4SMUEoUEvLUDNGqRKR9C",
+            KeyType::Ed25519,
+        )
+        .unwrap();
+        let remembered: Vec<_> = (0..3).map(|_| Arc::new(Mutex::new(vec![]))).collect();
+        let fixed = |decision, index: usize| Fixed {
+            decision,
+            remembered: Arc::clone(&remembered[index]),
+        };
+        let chain = HostKeyVerifierChain::new()
+            .with(fixed(HostKeyDecision::Unknown, 0))
+            .with(fixed(HostKeyDecision::AcceptAndRemember, 1))
+            .with(fixed(HostKeyDecision::AcceptAndRemember, 2));
+
+        assert_eq!(
+            chain.verify("host", 22, &key).unwrap(),
+            HostKeyDecision::AcceptAndRemember
+        );
+        chain.remember("host", 22, &key).unwrap();
+        assert!(remembered[0].lock().unwrap().is_empty());
+        assert_eq!(*remembered[1].lock().unwrap(), ["host"]);
+        assert!(remembered[2].lock().unwrap().is_empty());
+
+        let chain = HostKeyVerifierChain::new()
+            .with(fixed(HostKeyDecision::Accept, 0))
+            .with(fixed(HostKeyDecision::AcceptAndRemember, 1));
+        chain.remember("other", 22, &key).unwrap();
+        assert!(remembered[0].lock().unwrap().is_empty());
+        assert_eq!(*remembered[1].lock().unwrap(), ["host"]);
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            parse_fingerprint("SHA256:Hhsxgt+YMB+7sLiw1JcDnMXUv4L1p4Vb1mWHUUVXTzY")
+                .unwrap()
+                .len(),
+            32
+        );
+        assert!(parse_fingerprint("Hhsxgt+YMB+7sLiw1JcDnMXUv4L1p4Vb1mWHUUVXTzY").is_err());
+        assert!(parse_fingerprint("SHA256:Hhsxgt+YMB").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::forward::{Acceptor, ForwardThread, Relay};
+use crate::{AuthStatus, Error, KnownHosts, Session, SshOption, SshResult};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// One of the intermediate hosts that a connection is made
+/// through, as specified by the `ProxyJump` ssh config option
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyJump {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl ProxyJump {
+    /// Parses a single `[user@]host[:port]` jump specification.
+    /// IPv6 addresses must be enclosed in square brackets if
+    /// a port is specified.
+    pub fn parse(spec: &str) -> SshResult<Self> {
+        let (user, host_port) = match spec.rsplit_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, spec),
+        };
+        let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| Error::Fatal(format!("invalid jump host {}", spec)))?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(Error::Fatal(format!("invalid jump host {}", spec))),
+            }
+        } else {
+            match host_port.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (host_port, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(Error::Fatal(format!("invalid jump host {}", spec)));
+        }
+        let port = port
+            .map(|port| {
+                port.parse::<u16>()
+                    .map_err(|_| Error::Fatal(format!("invalid port in jump host {}", spec)))
+            })
+            .transpose()?;
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Parses a comma separated list of jump specifications, in the
+    /// order in which they are to be connected
+    pub fn parse_list(list: &str) -> SshResult<Vec<Self>> {
+        list.split(',')
+            .map(|spec| Self::parse(spec.trim()))
+            .collect()
+    }
+}
+
+/// libssh implements `ProxyJump` by generating a `ProxyCommand` of
+/// the form `ssh [-l user] [-p port] [-J jumps] -W '[%h]:%p' host`.
+/// Recognizes such a command and returns the equivalent jump hosts.
+pub(crate) fn parse_jump_proxy_command(command: &str) -> Option<Vec<ProxyJump>> {
+    let mut words = command.split_whitespace();
+    if words.next()? != "ssh" {
+        return None;
+    }
+    let mut last = ProxyJump {
+        user: None,
+        host: String::new(),
+        port: None,
+    };
+    let mut jumps = vec![];
+    let mut saw_forward = false;
+    while let Some(word) = words.next() {
+        match word {
+            "-l" => last.user = Some(words.next()?.to_string()),
+            "-p" => last.port = Some(words.next()?.parse().ok()?),
+            "-J" => jumps = ProxyJump::parse_list(words.next()?).ok()?,
+            "-W" => {
+                if words.next()? != "'[%h]:%p'" {
+                    return None;
+                }
+                saw_forward = true;
+            }
+            host if !host.starts_with('-') && last.host.is_empty() => {
+                last.host = host.to_string();
+            }
+            _ => return None,
+        }
+    }
+    if !saw_forward || last.host.is_empty() {
+        return None;
+    }
+    jumps.push(last);
+    Some(jumps)
+}
+
+/// Relays a single, already opened, channel to the socket that a
+/// session is using as its transport
+struct Transport {
+    relay: Option<Relay>,
+}
+
+impl Acceptor for Transport {
+    fn raw_fds(&self) -> Vec<RawFd> {
+        vec![]
+    }
+
+    fn accept(&mut self, _session: &Session) -> SshResult<Option<Relay>> {
+        Ok(self.relay.take())
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.relay.is_none()
+    }
+}
+
+impl Session {
+    /// Connects to `host:port` through `jump`, which must be an already
+    /// connected and authenticated session; this is the equivalent
+    /// of `ssh -J`.
+    ///
+    /// Opens a forwarding channel on `jump` and uses it as the transport
+    /// for this session, setting the `Hostname` and `Port` options so
+    /// that the host key is checked against the right known hosts entry.
+    /// The channel is serviced by a background thread, which holds on
+    /// to `jump` until this session disconnects.
+    ///
+    /// Chains of any length can be built by using a session that was
+    /// itself connected via `connect_via` as the `jump` session.
+    pub fn connect_via(&self, jump: &Session, host: &str, port: u16) -> SshResult<()> {
+        self.set_option(SshOption::Hostname(host.to_string()))?;
+        self.set_option(SshOption::Port(port))?;
+
+        let channel = jump.new_channel()?;
+        channel.open_forward(host, port, "127.0.0.1", 0)?;
+
+        let (ours, theirs) = UnixStream::pair()?;
+        let relay = Relay::new(channel, Box::new(ours))?;
+        ForwardThread::spawn(jump, Transport { relay: Some(relay) }).detach();
+
+        // libssh takes ownership of the socket and closes it
+        // when the session is disconnected
+        self.set_option(SshOption::Socket(theirs.into_raw_fd()))?;
+        self.connect_direct()
+    }
+
+    /// Connects through each of the `jumps` in turn.
+    ///
+    /// Each jump host is configured from the same config file as this
+    /// session, and must have a known host key and accept public key
+    /// authentication via [userauth_public_key_auto](#method.userauth_public_key_auto),
+    /// as there is no opportunity to interact with the user.
+    pub(crate) fn connect_jumps(
+        &self,
+        jumps: &[ProxyJump],
+        config_file: Option<&str>,
+    ) -> SshResult<()> {
+        let mut previous: Option<Session> = None;
+        for jump in jumps {
+            let hop = Session::new()?;
+            hop.set_option(SshOption::Hostname(jump.host.clone()))?;
+            if let Some(user) = &jump.user {
+                hop.set_option(SshOption::User(Some(user.clone())))?;
+            }
+            if let Some(port) = jump.port {
+                hop.set_option(SshOption::Port(port))?;
+            }
+            hop.options_parse_config(config_file)?;
+            hop.lock_session().proxy_jumps.clear();
+            // Explicit settings take precedence over the config
+            if let Some(user) = &jump.user {
+                hop.set_option(SshOption::User(Some(user.clone())))?;
+            }
+            if let Some(port) = jump.port {
+                hop.set_option(SshOption::Port(port))?;
+            }
+
+            match &previous {
+                Some(previous) => {
+                    let host = hop.get_host_name()?;
+                    let port = hop.get_port()?;
+                    hop.connect_via(previous, &host, port)?
+                }
+                None => hop.connect_direct()?,
+            }
+            if hop.is_known_server()? != KnownHosts::Ok {
+                return Err(Error::Fatal(format!(
+                    "the host key for jump host {} is not known",
+                    jump.host
+                )));
+            }
+            if hop.userauth_public_key_auto(None, None)? != AuthStatus::Success {
+                return Err(Error::Fatal(format!(
+                    "authentication with jump host {} failed",
+                    jump.host
+                )));
+            }
+            previous = Some(hop);
+        }
+
+        match previous {
+            Some(previous) => {
+                let host = self.get_host_name()?;
+                let port = self.get_port()?;
+                self.connect_via(&previous, &host, port)
+            }
+            None => self.connect_direct(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jump_specs() {
+        assert_eq!(
+            ProxyJump::parse_list("bastion, admin@[::1]:2222,other:23").unwrap(),
+            vec![
+                ProxyJump {
+                    user: None,
+                    host: "bastion".to_string(),
+                    port: None
+                },
+                ProxyJump {
+                    user: Some("admin".to_string()),
+                    host: "::1".to_string(),
+                    port: Some(2222)
+                },
+                ProxyJump {
+                    user: None,
+                    host: "other".to_string(),
+                    port: Some(23)
+                },
+            ]
+        );
+        assert!(ProxyJump::parse("host:port").is_err());
+    }
+
+    #[test]
+    fn jump_proxy_command() {
+        assert_eq!(
+            parse_jump_proxy_command("ssh -l me -p 2222 -J a,b:23 -W '[%h]:%p' c").unwrap(),
+            vec![
+                ProxyJump::parse("a").unwrap(),
+                ProxyJump::parse("b:23").unwrap(),
+                ProxyJump::parse("me@c:2222").unwrap(),
+            ]
+        );
+        assert_eq!(
+            parse_jump_proxy_command("ssh -W '[%h]:%p' bastion").unwrap(),
+            vec![ProxyJump::parse("bastion").unwrap()]
+        );
+        assert_eq!(parse_jump_proxy_command("nc -X 5 -x proxy %h %p"), None);
+        assert_eq!(parse_jump_proxy_command("ssh -W %h:%p -q bastion"), None);
+    }
+}
@@ -0,0 +1,658 @@
+use crate::cert::{base64_decode, base64_encode, Reader};
+use crate::{Error, Session, SshCertificate, SshKey, SshResult};
+use openssl_sys as ffi;
+use std::io::Write;
+use std::os::raw::{c_int, c_uint};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The marker that may precede the host patterns of a known_hosts entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownHostsMarker {
+    /// `@cert-authority`: the key is a CA that is trusted to
+    /// sign host certificates for the matching hosts
+    CertAuthority,
+    /// `@revoked`: the key must never be accepted
+    Revoked,
+}
+
+/// A single host key entry from a known_hosts file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownHostsEntry {
+    line: usize,
+    text: String,
+    marker: Option<KnownHostsMarker>,
+    hosts: Vec<String>,
+    key_type: String,
+    key: String,
+    comment: Option<String>,
+}
+
+impl KnownHostsEntry {
+    fn parse(line: usize, text: &str) -> Option<Self> {
+        let mut fields = text.split_whitespace();
+        let mut first = fields.next()?;
+        let marker = match first {
+            "@cert-authority" => Some(KnownHostsMarker::CertAuthority),
+            "@revoked" => Some(KnownHostsMarker::Revoked),
+            m if m.starts_with('@') => return None,
+            _ => None,
+        };
+        if marker.is_some() {
+            first = fields.next()?;
+        }
+        let hosts = first.split(',').map(str::to_string).collect();
+        let key_type = fields.next()?.to_string();
+        let key = fields.next()?.to_string();
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        Some(Self {
+            line,
+            text: text.to_string(),
+            marker,
+            hosts,
+            key_type,
+            key,
+            comment: if comment.is_empty() {
+                None
+            } else {
+                Some(comment)
+            },
+        })
+    }
+
+    /// Returns the 1-based line number of the entry in the file
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn marker(&self) -> Option<KnownHostsMarker> {
+        self.marker
+    }
+
+    /// Returns the host patterns of the entry. These may contain
+    /// wildcards, be negated with `!`, be of the form `[host]:port`
+    /// for non-standard ports, or be hashed as `|1|salt|hash`.
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// Returns true if the host name of the entry is hashed
+    pub fn is_hashed(&self) -> bool {
+        self.hosts.iter().any(|host| host.starts_with("|1|"))
+    }
+
+    /// Returns the key type name, such as `ssh-ed25519`
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    /// Returns the base64 encoded public key
+    pub fn key_base64(&self) -> &str {
+        &self.key
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Returns the public key of the entry
+    pub fn key(&self) -> SshResult<SshKey> {
+        SshKey::from_pubkey_base64(&self.key, crate::KeyType::from_name(&self.key_type))
+    }
+
+    /// Returns true if the entry applies to `host` on `port`
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host = host_key(host, port);
+        let mut matched = false;
+        for pattern in &self.hosts {
+            if let Some(hashed) = pattern.strip_prefix("|1|") {
+                if hashed_matches(hashed, &host) {
+                    matched = true;
+                }
+            } else if let Some(negated) = pattern.strip_prefix('!') {
+                if wildcard_matches(negated, &host) {
+                    return false;
+                }
+            } else if wildcard_matches(pattern, &host) {
+                matched = true;
+            }
+        }
+        matched
+    }
+
+    fn key_blob(&self) -> Option<Vec<u8>> {
+        base64_decode(&self.key).ok()
+    }
+}
+
+impl std::fmt::Display for KnownHostsEntry {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.text)
+    }
+}
+
+/// The result of looking up a host key with
+/// [KnownHostsFile::check](struct.KnownHostsFile.html#method.check)
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostKeyStatus<'a> {
+    /// The key is known for the host. The entry is a `@cert-authority`
+    /// entry if the key is a certificate signed by that authority.
+    Ok(&'a KnownHostsEntry),
+    /// The key, or the authority that signed it, is marked as revoked
+    Revoked(&'a KnownHostsEntry),
+    /// The host is known with a different key of the same type.
+    /// These entries are the ones to fix if the change is expected.
+    Changed(Vec<&'a KnownHostsEntry>),
+    /// The host is known, but only with keys of other types
+    Other(Vec<&'a KnownHostsEntry>),
+    /// The host is not known
+    NotFound,
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    Entry(KnownHostsEntry),
+    /// Comments, blank lines and lines that cannot be parsed,
+    /// which are preserved as they are
+    Other(String),
+}
+
+/// The contents of an OpenSSH known_hosts file, which can be
+/// inspected, modified and then written back atomically.
+///
+/// Lines that are not entries, such as comments, are preserved.
+/// Line numbers refer to the current contents, so they change
+/// as entries are added or removed.
+#[derive(Debug, Clone)]
+pub struct KnownHostsFile {
+    path: Option<PathBuf>,
+    lines: Vec<Line>,
+}
+
+impl KnownHostsFile {
+    /// Loads the known_hosts file at `path`.
+    /// A file that does not exist is treated as being empty.
+    pub fn load<P: AsRef<Path>>(path: P) -> SshResult<Self> {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(Error::Fatal(format!(
+                    "failed to read {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        let mut file = Self::parse(&contents);
+        file.path = Some(path.to_path_buf());
+        Ok(file)
+    }
+
+    /// Parses known_hosts data that is not associated with a file
+    pub fn parse(contents: &str) -> Self {
+        let lines = contents
+            .lines()
+            .enumerate()
+            .map(|(idx, text)| {
+                let trimmed = text.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(text.to_string());
+                }
+                match KnownHostsEntry::parse(idx + 1, trimmed) {
+                    Some(entry) => Line::Entry(entry),
+                    None => Line::Other(text.to_string()),
+                }
+            })
+            .collect();
+        Self { path: None, lines }
+    }
+
+    /// Returns the path that the file was loaded from
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns all of the entries in the file
+    pub fn entries(&self) -> impl Iterator<Item = &KnownHostsEntry> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// Returns the entries that apply to `host` on `port`,
+    /// including `@cert-authority` and `@revoked` entries
+    pub fn lookup(&self, host: &str, port: u16) -> Vec<&KnownHostsEntry> {
+        self.entries()
+            .filter(|entry| entry.matches(host, port))
+            .collect()
+    }
+
+    /// Checks `key`, as presented by `host` on `port`.
+    ///
+    /// If `key` is a host certificate, it is accepted if it was issued
+    /// for `host` by a matching `@cert-authority`, and is currently valid.
+    pub fn check(&self, host: &str, port: u16, key: &SshKey) -> SshResult<HostKeyStatus<'_>> {
+        let blob = key.export_pubkey_blob()?;
+        Ok(self.check_blob(host, port, &blob, SystemTime::now()))
+    }
+
+    /// Checks the host key of the connected `session`
+    pub fn check_server(&self, session: &Session) -> SshResult<HostKeyStatus<'_>> {
+        let host = session.get_host_name()?;
+        let port = session.get_port()?;
+        let key = session.get_server_public_key()?;
+        self.check(&host, port, &key)
+    }
+
+    fn check_blob(&self, host: &str, port: u16, blob: &[u8], now: SystemTime) -> HostKeyStatus<'_> {
+        let entries = self.lookup(host, port);
+        let cert = SshCertificate::from_blob(blob).ok();
+        let key_blob = cert
+            .as_ref()
+            .map(|cert| cert.public_key_blob())
+            .unwrap_or(blob);
+
+        let revoked_blobs = [
+            Some(key_blob),
+            cert.as_ref().map(|c| c.signature_key_blob()),
+        ];
+        for entry in self.entries() {
+            // Revocations apply regardless of the host
+            if entry.marker == Some(KnownHostsMarker::Revoked)
+                && entry
+                    .key_blob()
+                    .map(|b| revoked_blobs.contains(&Some(&b[..])))
+                    .unwrap_or(false)
+            {
+                return HostKeyStatus::Revoked(entry);
+            }
+        }
+
+        if let Some(cert) = &cert {
+            for entry in &entries {
+                if entry.marker == Some(KnownHostsMarker::CertAuthority) {
+                    if let Some(ca) = entry.key_blob() {
+                        if cert.verify_blobs(&[ca], host, now).is_ok() {
+                            return HostKeyStatus::Ok(entry);
+                        }
+                    }
+                }
+            }
+        }
+
+        let key_type = Reader::new(key_blob)
+            .read_str()
+            .map(str::to_string)
+            .unwrap_or_default();
+        let plain: Vec<&KnownHostsEntry> = entries
+            .into_iter()
+            .filter(|entry| entry.marker.is_none())
+            .collect();
+        if let Some(entry) = plain
+            .iter()
+            .find(|entry| entry.key_blob().as_deref() == Some(key_blob))
+        {
+            return HostKeyStatus::Ok(entry);
+        }
+        let changed: Vec<&KnownHostsEntry> = plain
+            .iter()
+            .copied()
+            .filter(|entry| entry.key_type == key_type)
+            .collect();
+        if !changed.is_empty() {
+            HostKeyStatus::Changed(changed)
+        } else if !plain.is_empty() {
+            HostKeyStatus::Other(plain)
+        } else {
+            HostKeyStatus::NotFound
+        }
+    }
+
+    /// Appends an entry for `key` on `host` and `port`, with the host
+    /// name hashed if `hash` is true, and returns its line number
+    pub fn add(&mut self, host: &str, port: u16, key: &SshKey, hash: bool) -> SshResult<usize> {
+        let key_type = key
+            .key_type()
+            .name()
+            .ok_or_else(|| Error::fatal("unknown key type"))?;
+        let key = key.export_pubkey_base64()?;
+        check_host_name(host)?;
+        let host = host_key(host, port);
+        let host = if hash { hash_host(&host)? } else { host };
+        self.add_line(&format!("{} {} {}", host, key_type, key))
+    }
+
+    /// Adds the host key of the connected `session`
+    pub fn add_server(&mut self, session: &Session, hash: bool) -> SshResult<usize> {
+        let host = session.get_host_name()?;
+        let port = session.get_port()?;
+        let key = session.get_server_public_key()?;
+        self.add(&host, port, &key, hash)
+    }
+
+    fn add_line(&mut self, text: &str) -> SshResult<usize> {
+        let line = self.lines.len() + 1;
+        let entry = KnownHostsEntry::parse(line, text)
+            .ok_or_else(|| Error::Fatal(format!("invalid known_hosts entry {:?}", text)))?;
+        self.lines.push(Line::Entry(entry));
+        Ok(line)
+    }
+
+    /// Removes the entry on line `line`, returning it
+    pub fn remove_line(&mut self, line: usize) -> Option<KnownHostsEntry> {
+        match self.lines.get(line.wrapping_sub(1)) {
+            Some(Line::Entry(_)) => {}
+            _ => return None,
+        }
+        let removed = match self.lines.remove(line - 1) {
+            Line::Entry(entry) => entry,
+            Line::Other(_) => unreachable!(),
+        };
+        self.renumber();
+        Some(removed)
+    }
+
+    /// Removes the entries for `host` on `port` that are of the same
+    /// type as `key` but hold a different key, as is needed when a
+    /// host key has legitimately changed, and returns them.
+    /// Entries that list other hosts as well are removed entirely.
+    /// `@cert-authority` and `@revoked` entries are retained.
+    pub fn remove_stale(
+        &mut self,
+        host: &str,
+        port: u16,
+        key: &SshKey,
+    ) -> SshResult<Vec<KnownHostsEntry>> {
+        let key_type = key.key_type().name().unwrap_or_default();
+        let key = key.export_pubkey_base64()?;
+        Ok(self.remove_where(|entry| {
+            entry.marker.is_none()
+                && entry.key_type == key_type
+                && entry.key != key
+                && entry.matches(host, port)
+        }))
+    }
+
+    /// Removes all entries for `host` on `port`, other than
+    /// `@cert-authority` and `@revoked` entries, and returns them.
+    /// This is the equivalent of `ssh-keygen -R`.
+    pub fn remove_host(&mut self, host: &str, port: u16) -> Vec<KnownHostsEntry> {
+        self.remove_where(|entry| entry.marker.is_none() && entry.matches(host, port))
+    }
+
+    fn remove_where<F: Fn(&KnownHostsEntry) -> bool>(
+        &mut self,
+        predicate: F,
+    ) -> Vec<KnownHostsEntry> {
+        let mut removed = vec![];
+        let mut kept = vec![];
+        for line in self.lines.drain(..) {
+            match line {
+                Line::Entry(entry) if predicate(&entry) => removed.push(entry),
+                line => kept.push(line),
+            }
+        }
+        self.lines = kept;
+        self.renumber();
+        removed
+    }
+
+    fn renumber(&mut self) {
+        for (idx, line) in self.lines.iter_mut().enumerate() {
+            if let Line::Entry(entry) = line {
+                entry.line = idx + 1;
+            }
+        }
+    }
+
+    /// Writes the contents back to the file that they were loaded from
+    pub fn save(&self) -> SshResult<()> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| Error::fatal("the known hosts data was not loaded from a file"))?;
+        self.save_as(path)
+    }
+
+    /// Writes the contents to `path`, atomically replacing it by
+    /// writing to a temporary file in the same directory and then
+    /// renaming it. The permissions of an existing file are retained.
+    pub fn save_as<P: AsRef<Path>>(&self, path: P) -> SshResult<()> {
+        let path = path.as_ref();
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::Fatal(format!("{} is not a file name", path.display())))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+
+        let result = (|| -> std::io::Result<()> {
+            let mut temp = std::fs::File::create(&temp_path)?;
+            temp.write_all(self.to_string().as_bytes())?;
+            if let Ok(metadata) = std::fs::metadata(path) {
+                temp.set_permissions(metadata.permissions())?;
+            }
+            temp.sync_all()?;
+            std::fs::rename(&temp_path, path)
+        })();
+        if let Err(err) = result {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(Error::Fatal(format!(
+                "failed to write {}: {}",
+                path.display(),
+                err
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for KnownHostsFile {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Entry(entry) => writeln!(fmt, "{}", entry.text)?,
+                Line::Other(text) => writeln!(fmt, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects host names that can't be written as a known_hosts entry,
+/// as they would be read back as a marker, a comment or several fields
+fn check_host_name(host: &str) -> SshResult<()> {
+    if host.is_empty()
+        || host.starts_with('@')
+        || host.starts_with('#')
+        || host.contains(|c: char| c.is_whitespace() || c == ',')
+    {
+        return Err(Error::Fatal(format!("invalid host name {:?}", host)));
+    }
+    Ok(())
+}
+
+/// Returns the name under which `host` is recorded
+/// in known_hosts files for `port`
+fn host_key(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Matches `host` against a pattern that may contain the
+/// `*` and `?` wildcards, ignoring case
+fn wildcard_matches(pattern: &str, host: &str) -> bool {
+    fn matches(pattern: &[u8], host: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => host.is_empty(),
+            Some((b'*', rest)) => (0..=host.len()).any(|skip| matches(rest, &host[skip..])),
+            Some((b'?', rest)) => !host.is_empty() && matches(rest, &host[1..]),
+            Some((c, rest)) => match host.split_first() {
+                Some((h, host)) => c.eq_ignore_ascii_case(h) && matches(rest, host),
+                None => false,
+            },
+        }
+    }
+    matches(pattern.as_bytes(), host.as_bytes())
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Option<[u8; 20]> {
+    let mut digest = [0u8; 20];
+    let mut len: c_uint = 0;
+    unsafe {
+        let ctx = ffi::HMAC_CTX_new();
+        if ctx.is_null() {
+            return None;
+        }
+        let ok = ffi::HMAC_Init_ex(
+            ctx,
+            key.as_ptr() as *const _,
+            key.len() as c_int,
+            ffi::EVP_sha1(),
+            std::ptr::null_mut(),
+        ) == 1
+            && ffi::HMAC_Update(ctx, data.as_ptr(), data.len()) == 1
+            && ffi::HMAC_Final(ctx, digest.as_mut_ptr(), &mut len) == 1;
+        ffi::HMAC_CTX_free(ctx);
+        if !ok || len as usize != digest.len() {
+            return None;
+        }
+    }
+    Some(digest)
+}
+
+/// Matches the `salt|hash` part of a hashed host name
+fn hashed_matches(hashed: &str, host: &str) -> bool {
+    let (salt, hash) = match hashed.split_once('|') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    match (base64_decode(salt), base64_decode(hash)) {
+        (Ok(salt), Ok(hash)) => {
+            hmac_sha1(&salt, host.to_ascii_lowercase().as_bytes()).map(|d| d.to_vec()) == Some(hash)
+        }
+        _ => false,
+    }
+}
+
+fn hash_host(host: &str) -> SshResult<String> {
+    let mut salt = [0u8; 20];
+    if unsafe { ffi::RAND_bytes(salt.as_mut_ptr(), salt.len() as c_int) } != 1 {
+        return Err(Error::fatal("failed to generate salt"));
+    }
+    let hash = hmac_sha1(&salt, host.to_ascii_lowercase().as_bytes())
+        .ok_or_else(|| Error::fatal("failed to hash host name"))?;
+    Ok(format!(
+        "|1|{}|{}",
+        base64_encode(&salt),
+        base64_encode(&hash)
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIHDlL6YoA9MD9FJqDHaLKqBC4SMUEoUEvLUDNGqRKR9C";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAID35mcrgYJkPBPGHcw494j1fyGQrrP1OLJFAZLHEhdh5";
+
+    #[test]
+    fn host_patterns() {
+        let file = KnownHostsFile::parse(&format!(
+            "# comment\n\
+             |1|E/BuVeKb/m9iPMVBJNs/awL6Nww=|BE4SmLkCYtjwiAO0utDv8fbj1mk= ssh-ed25519 {KEY}\n\
+             *.example.com,!bad.example.com ssh-ed25519 {KEY} some comment\n\
+             [alt.example.org]:2222 ssh-ed25519 {KEY}\n\
+             @cert-authority *.example.net ssh-ed25519 {OTHER_KEY}\n\
+             malformed-line\n"
+        ));
+        let entries: Vec<_> = file.entries().collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].line(), 2);
+        assert!(entries[0].is_hashed());
+        assert_eq!(entries[1].comment(), Some("some comment"));
+        assert_eq!(entries[3].marker(), Some(KnownHostsMarker::CertAuthority));
+
+        assert!(entries[0].matches("HOST.example.com", 22));
+        assert!(!entries[0].matches("host.example.com", 23));
+        assert!(entries[1].matches("www.example.com", 22));
+        assert!(!entries[1].matches("bad.example.com", 22));
+        assert!(entries[2].matches("alt.example.org", 2222));
+        assert!(!entries[2].matches("alt.example.org", 22));
+        assert_eq!(file.lookup("www.example.net", 22), vec![entries[3]]);
+
+        assert_eq!(file.to_string().lines().nth(5), Some("malformed-line"));
+    }
+
+    #[test]
+    fn check_and_modify() {
+        let key = base64_decode(KEY).unwrap();
+        let other_key = base64_decode(OTHER_KEY).unwrap();
+        let mut file = KnownHostsFile::parse(&format!(
+            "a.example.com ssh-ed25519 {OTHER_KEY}\n\
+             b.example.com ssh-ed25519 {KEY}\n\
+             c.example.com ssh-rsa AAAAB3NzaC1yc2E=\n\
+             @revoked * ssh-ed25519 {OTHER_KEY}\n"
+        ));
+        let now = SystemTime::now();
+
+        assert!(matches!(
+            file.check_blob("b.example.com", 22, &key, now),
+            HostKeyStatus::Ok(entry) if entry.line() == 2
+        ));
+        assert!(matches!(
+            file.check_blob("a.example.com", 22, &other_key, now),
+            HostKeyStatus::Revoked(entry) if entry.line() == 4
+        ));
+        match file.check_blob("a.example.com", 22, &key, now) {
+            HostKeyStatus::Changed(entries) => assert_eq!(entries[0].line(), 1),
+            status => panic!("unexpected {:?}", status),
+        }
+        assert!(matches!(
+            file.check_blob("c.example.com", 22, &key, now),
+            HostKeyStatus::Other(_)
+        ));
+        assert_eq!(
+            file.check_blob("d.example.com", 22, &key, now),
+            HostKeyStatus::NotFound
+        );
+
+        let removed = file.remove_host("a.example.com", 22);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(file.entries().next().unwrap().line(), 1);
+        assert_eq!(file.remove_line(2).unwrap().key_type(), "ssh-rsa");
+        assert_eq!(file.remove_line(5), None);
+        file.add_line(&format!("[d.example.com]:2222 ssh-ed25519 {KEY}"))
+            .unwrap();
+        assert!(matches!(
+            file.check_blob("d.example.com", 2222, &key, now),
+            HostKeyStatus::Ok(entry) if entry.line() == 3
+        ));
+    }
+
+    #[test]
+    fn hashing() {
+        let hashed = hash_host("[host.example.com]:2222").unwrap();
+        let entry = KnownHostsEntry::parse(1, &format!("{} ssh-ed25519 {}", hashed, KEY)).unwrap();
+        assert!(entry.matches("host.example.com", 2222));
+        assert!(!entry.matches("host.example.com", 22));
+    }
+
+    #[test]
+    fn host_names() {
+        assert!(check_host_name("host.example.com").is_ok());
+        assert!(check_host_name("::1").is_ok());
+        for host in ["", "@revoked", "#host", "a host", "a,b", "host\n"] {
+            assert!(check_host_name(host).is_err(), "{:?}", host);
+        }
+        let mut file = KnownHostsFile::parse("");
+        assert!(file.add_line("@revoked").is_err());
+    }
+}
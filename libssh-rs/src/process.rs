@@ -0,0 +1,464 @@
+use crate::{Channel, ChannelRequestHandler, Error, PtyRequest, PtySize, SshResult};
+use libssh_rs_sys as sys;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait for activity from either the client or the
+/// process before checking in with libssh again.  libssh may have
+/// buffered data from the socket, so we can't wait indefinitely
+/// on the socket alone.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Channel {
+    /// Runs `command` as a local process on behalf of the client,
+    /// typically in response to an accepted exec or shell request.
+    ///
+    /// If `pty` is `Some`, then the process is started in a new session
+    /// with a freshly allocated PTY of the requested size as its
+    /// controlling terminal, and `TERM` is set to the requested terminal
+    /// type.  Window-change requests from the client resize the PTY while
+    /// the process is running.
+    /// Otherwise, the stdin, stdout and stderr of the process are
+    /// connected to the corresponding streams of the channel.
+    ///
+    /// When the process exits, its exit status (or the signal that
+    /// terminated it) is sent to the client, followed by EOF, and the
+    /// channel is closed.  The exit status is also returned.
+    ///
+    /// This method blocks until the process has exited and all of its
+    /// output has been written to the channel.  If the channel fails
+    /// before then, the process is killed.
+    pub fn run_process(&self, command: Command, pty: Option<&PtyRequest>) -> SshResult<ExitStatus> {
+        let (mut child, io) = match pty {
+            Some(pty) => spawn_pty(command, pty)?,
+            None => spawn_piped(command)?,
+        };
+
+        let previous = Arc::new(Mutex::new(self.replace_request_handler(None)));
+        self.replace_request_handler(Some(Box::new(ProcessRequestHandler {
+            previous: Arc::clone(&previous),
+            pty_master: io.pty_master,
+        })));
+
+        let result = self.pump_process(&mut child, io);
+
+        self.replace_request_handler(previous.lock().unwrap().take());
+
+        let status = match result {
+            Ok(status) => status,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+
+        match status.signal() {
+            Some(signal) => {
+                self.send_exit_signal(&signal_name(signal), status.core_dumped(), "", "")?
+            }
+            None => self.send_exit_status(status.code().unwrap_or(255))?,
+        }
+        self.send_eof()?;
+        self.close()?;
+        Ok(status)
+    }
+
+    fn pump_process(&self, child: &mut Child, io: ProcessIo) -> SshResult<ExitStatus> {
+        let ProcessIo {
+            mut input,
+            mut outputs,
+            pty_master,
+        } = io;
+        let mut buf = [0u8; 8192];
+        let mut to_child: Vec<u8> = vec![];
+
+        loop {
+            let mut progress = false;
+
+            // Data from the client goes to the stdin of the process.
+            // We always read from the channel, even once the process has
+            // closed its input, so that libssh keeps processing requests
+            // and keeps the channel window open.
+            if to_child.is_empty() {
+                let n = self.read_nonblocking(&mut buf, false)?;
+                if n > 0 {
+                    progress = true;
+                    if input.is_some() {
+                        to_child.extend_from_slice(&buf[..n]);
+                    }
+                } else if self.is_eof() && pty_master.is_none() {
+                    // Closing the pipe delivers EOF to the process.
+                    // There is no equivalent for a PTY, as its master
+                    // is also used to read the output.
+                    input.take();
+                }
+            }
+            if let Some(stdin) = input.as_mut() {
+                if !to_child.is_empty() {
+                    match stdin.write(&to_child) {
+                        Ok(n) => {
+                            to_child.drain(..n);
+                            progress = true;
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                        Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                        Err(_) => {
+                            // The process is no longer reading its input
+                            input.take();
+                            to_child.clear();
+                        }
+                    }
+                }
+            } else {
+                to_child.clear();
+            }
+
+            // Output from the process goes to the client
+            let mut idx = 0;
+            while idx < outputs.len() {
+                let (file, is_stderr) = &mut outputs[idx];
+                match file.read(&mut buf) {
+                    Ok(0) => {
+                        outputs.remove(idx);
+                        continue;
+                    }
+                    Ok(n) => {
+                        self.write_all_impl(&buf[..n], *is_stderr)?;
+                        progress = true;
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    // Reading the PTY master reports EIO once the
+                    // process and its children have closed the slave
+                    Err(err) if pty_master.is_some() && err.raw_os_error() == Some(libc::EIO) => {
+                        outputs.remove(idx);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+                idx += 1;
+            }
+
+            if outputs.is_empty() {
+                return Ok(child.wait()?);
+            }
+
+            if !progress {
+                if self.is_closed() {
+                    return Err(Error::fatal("channel closed while the process was running"));
+                }
+                self.wait_for_io(&outputs)?;
+            }
+        }
+    }
+
+    /// Writes all of `buf` to the channel, waiting for the remote
+    /// window to open up as needed.
+    fn write_all_impl(&self, mut buf: &[u8], is_stderr: bool) -> SshResult<()> {
+        while !buf.is_empty() {
+            match self.write_impl(buf, is_stderr) {
+                Ok(n) => buf = &buf[n..],
+                Err(Error::TryAgain) => {
+                    let (sess, _chan) = self.lock_session();
+                    sess.blocking_flush(Some(POLL_INTERVAL))?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until either the session socket or one of the process
+    /// outputs is readable, or `POLL_INTERVAL` elapses.
+    fn wait_for_io(&self, outputs: &[(File, bool)]) -> SshResult<()> {
+        let session_fd = {
+            let (sess, _chan) = self.lock_session();
+            unsafe { sys::ssh_get_fd(**sess) }
+        };
+        let mut fds: Vec<libc::pollfd> = std::iter::once(session_fd)
+            .chain(outputs.iter().map(|(file, _)| file.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let res = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                POLL_INTERVAL.as_millis() as libc::c_int,
+            )
+        };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The local ends of the stdio of a spawned process
+struct ProcessIo {
+    input: Option<File>,
+    /// Each of the outputs, and whether it is stderr
+    outputs: Vec<(File, bool)>,
+    pty_master: Option<RawFd>,
+}
+
+fn spawn_piped(mut command: Command) -> SshResult<(Child, ProcessIo)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child.stdin.take().map(|f| into_file(f.into_raw_fd()));
+    let stdout = child.stdout.take().map(|f| into_file(f.into_raw_fd()));
+    let stderr = child.stderr.take().map(|f| into_file(f.into_raw_fd()));
+
+    let mut outputs = vec![];
+    outputs.extend(stdout.map(|f| (f, false)));
+    outputs.extend(stderr.map(|f| (f, true)));
+    for file in stdin.iter().chain(outputs.iter().map(|(f, _)| f)) {
+        set_nonblocking(file)?;
+    }
+
+    Ok((
+        child,
+        ProcessIo {
+            input: stdin,
+            outputs,
+            pty_master: None,
+        },
+    ))
+}
+
+fn spawn_pty(mut command: Command, pty: &PtyRequest) -> SshResult<(Child, ProcessIo)> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+    let size = winsize(pty.size());
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &size as *const _ as *mut _,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let master = into_file(master);
+    let slave = into_file(slave);
+
+    if !pty.term.is_empty() {
+        command.env("TERM", &pty.term);
+    }
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave.try_clone()?));
+    unsafe {
+        command.pre_exec(|| {
+            // Start a new session so that the PTY can become the
+            // controlling terminal of the process
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let child = command.spawn()?;
+    // The process has its own copies of the slave; we must close ours,
+    // including those held by the command, so that reading the master
+    // reports EOF when the process exits
+    drop(command);
+    drop(slave);
+
+    set_nonblocking(&master)?;
+    let pty_master = Some(master.as_raw_fd());
+    let input = Some(master.try_clone()?);
+
+    Ok((
+        child,
+        ProcessIo {
+            input,
+            outputs: vec![(master, false)],
+            pty_master,
+        },
+    ))
+}
+
+fn into_file(fd: RawFd) -> File {
+    unsafe { File::from_raw_fd(fd) }
+}
+
+fn set_nonblocking(file: &File) -> SshResult<()> {
+    let fd = file.as_raw_fd();
+    let res = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 {
+            -1
+        } else {
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        }
+    };
+    if res == -1 {
+        Err(std::io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+fn winsize(size: PtySize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows.min(u16::MAX.into()) as u16,
+        ws_col: size.cols.min(u16::MAX.into()) as u16,
+        ws_xpixel: size.px_width.min(u16::MAX.into()) as u16,
+        ws_ypixel: size.px_height.min(u16::MAX.into()) as u16,
+    }
+}
+
+/// Returns the RFC 4254 name for a signal, without the `"SIG"` prefix.
+/// Signals that RFC 4254 doesn't name are reported by number, as
+/// `"<number>@libssh-rs"`.
+fn signal_name(signal: libc::c_int) -> String {
+    match signal {
+        libc::SIGABRT => "ABRT",
+        libc::SIGALRM => "ALRM",
+        libc::SIGFPE => "FPE",
+        libc::SIGHUP => "HUP",
+        libc::SIGILL => "ILL",
+        libc::SIGINT => "INT",
+        libc::SIGKILL => "KILL",
+        libc::SIGPIPE => "PIPE",
+        libc::SIGQUIT => "QUIT",
+        libc::SIGSEGV => "SEGV",
+        libc::SIGTERM => "TERM",
+        libc::SIGUSR1 => "USR1",
+        libc::SIGUSR2 => "USR2",
+        _ => return format!("{}@libssh-rs", signal),
+    }
+    .to_string()
+}
+
+/// Installed on the channel while a process is running, so that
+/// window-change requests resize its PTY.  Other requests are passed
+/// on to the handler that was previously installed.
+struct ProcessRequestHandler {
+    previous: Arc<Mutex<Option<Box<dyn ChannelRequestHandler>>>>,
+    pty_master: Option<RawFd>,
+}
+
+impl ChannelRequestHandler for ProcessRequestHandler {
+    fn pty_request(&mut self, request: &PtyRequest) -> bool {
+        match self.previous.lock().unwrap().as_mut() {
+            Some(previous) => previous.pty_request(request),
+            None => false,
+        }
+    }
+
+    fn pty_window_change(&mut self, size: PtySize) -> bool {
+        if let Some(previous) = self.previous.lock().unwrap().as_mut() {
+            previous.pty_window_change(size);
+        }
+        match self.pty_master {
+            Some(master) => {
+                let size = winsize(size);
+                unsafe { libc::ioctl(master, libc::TIOCSWINSZ as _, &size) == 0 }
+            }
+            None => false,
+        }
+    }
+
+    fn shell_request(&mut self) -> bool {
+        match self.previous.lock().unwrap().as_mut() {
+            Some(previous) => previous.shell_request(),
+            None => false,
+        }
+    }
+
+    fn exec_request(&mut self, command: &str) -> bool {
+        match self.previous.lock().unwrap().as_mut() {
+            Some(previous) => previous.exec_request(command),
+            None => false,
+        }
+    }
+
+    fn env_request(&mut self, name: &str, value: &str) -> bool {
+        match self.previous.lock().unwrap().as_mut() {
+            Some(previous) => previous.env_request(name, value),
+            None => false,
+        }
+    }
+
+    fn subsystem_request(&mut self, subsystem: &str) -> bool {
+        match self.previous.lock().unwrap().as_mut() {
+            Some(previous) => previous.subsystem_request(subsystem),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn pty_process_exits() {
+        let pty = PtyRequest {
+            term: "xterm".to_string(),
+            cols: 80,
+            rows: 24,
+            px_width: 0,
+            px_height: 0,
+        };
+        let (mut child, io) = spawn_pty(Command::new("true"), &pty).unwrap();
+        let (mut master, _) = io.outputs.into_iter().next().unwrap();
+
+        // Reading the master must report EOF or EIO once the process has
+        // exited, rather than blocking on a copy of the slave we hold
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut buf = [0u8; 1024];
+        loop {
+            assert!(Instant::now() < deadline, "PTY master never reported EOF");
+            match master.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) if err.raw_os_error() == Some(libc::EIO) => break,
+                Err(err) => panic!("reading the PTY master failed: {}", err),
+            }
+        }
+
+        let status = child.wait().unwrap();
+        assert!(status.success());
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(signal_name(libc::SIGTERM), "TERM");
+        assert_eq!(signal_name(libc::SIGKILL), "KILL");
+        assert_eq!(
+            signal_name(libc::SIGCHLD),
+            format!("{}@libssh-rs", libc::SIGCHLD)
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use crate::{Error, Session, SessionHolder, SshResult};
+use libssh_rs_sys as sys;
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The direction of an SCP transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScpMode {
+    /// Push files to the remote host
+    Write,
+    /// Pull files from the remote host
+    Read,
+}
+
+/// A request received from the remote host while pulling files;
+/// see [Scp::pull_request](struct.Scp.html#method.pull_request).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScpRequest {
+    /// The remote host wants to send a directory, whose contents follow
+    /// up until the matching `EndDirectory`.
+    /// Must be accepted or denied.
+    NewDirectory { name: String, permissions: u32 },
+    /// The remote host wants to send a file.
+    /// Must be accepted or denied; once accepted, the content is
+    /// read via the `Read` impl of `Scp`.
+    NewFile {
+        name: String,
+        size: u64,
+        permissions: u32,
+    },
+    /// The end of the current directory
+    EndDirectory,
+    /// The remote host reported a problem, such as a file that
+    /// it could not read, but is carrying on with the transfer
+    Warning(String),
+    /// There is nothing more to pull
+    Eof,
+}
+
+/// An SCP transfer, as created by
+/// [Session::scp_new](struct.Session.html#method.scp_new).
+///
+/// When pushing, announce each file via [push_file](#method.push_file)
+/// and then write exactly that many bytes of content via the `Write` impl.
+/// When pulling, call [pull_request](#method.pull_request) (or iterate
+/// [requests](#method.requests)) and accept each file before reading
+/// its content via the `Read` impl.
+pub struct Scp {
+    sess: Arc<Mutex<SessionHolder>>,
+    scp_inner: sys::ssh_scp,
+    /// The number of bytes left to transfer for the current file
+    remaining: Cell<u64>,
+    /// The file most recently announced by pull_request
+    pending_size: Cell<Option<u64>>,
+}
+
+unsafe impl Send for Scp {}
+
+impl Drop for Scp {
+    fn drop(&mut self) {
+        let (_sess, scp) = self.lock_session();
+        unsafe { sys::ssh_scp_free(scp) }
+    }
+}
+
+impl Scp {
+    fn lock_session(&self) -> (MutexGuard<'_, SessionHolder>, sys::ssh_scp) {
+        (self.sess.lock().unwrap(), self.scp_inner)
+    }
+
+    /// Create a directory on the remote host and make it the
+    /// destination for subsequent pushes, until
+    /// [leave_directory](#method.leave_directory) is called.
+    /// The transfer must have been created with `recursive` set.
+    pub fn push_directory(&self, dirname: &str, mode: u32) -> SshResult<()> {
+        let dirname = CString::new(dirname)?;
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_push_directory(scp, dirname.as_ptr(), mode as c_int) };
+        sess.basic_status(res, "ssh_scp_push_directory failed")
+    }
+
+    /// Leave the directory most recently created via
+    /// [push_directory](#method.push_directory)
+    pub fn leave_directory(&self) -> SshResult<()> {
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_leave_directory(scp) };
+        sess.basic_status(res, "ssh_scp_leave_directory failed")
+    }
+
+    /// Announce a file of `size` bytes with permissions `mode`.
+    /// Its content must then be written in full before pushing
+    /// anything else.
+    pub fn push_file(&self, filename: &str, size: u64, mode: u32) -> SshResult<()> {
+        let filename = CString::new(filename)?;
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_push_file64(scp, filename.as_ptr(), size, mode as c_int) };
+        sess.basic_status(res, "ssh_scp_push_file64 failed")?;
+        self.remaining.set(size);
+        Ok(())
+    }
+
+    /// Wait for the next request from the remote host when pulling
+    pub fn pull_request(&self) -> SshResult<ScpRequest> {
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_pull_request(scp) };
+        self.pending_size.set(None);
+
+        let filename = || {
+            let name = unsafe { sys::ssh_scp_request_get_filename(scp) };
+            if name.is_null() {
+                String::new()
+            } else {
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .to_string()
+            }
+        };
+        let permissions = || unsafe { sys::ssh_scp_request_get_permissions(scp) } as u32;
+
+        if res == sys::ssh_scp_request_types::SSH_SCP_REQUEST_NEWDIR as c_int {
+            Ok(ScpRequest::NewDirectory {
+                name: filename(),
+                permissions: permissions(),
+            })
+        } else if res == sys::ssh_scp_request_types::SSH_SCP_REQUEST_NEWFILE as c_int {
+            let size = unsafe { sys::ssh_scp_request_get_size64(scp) };
+            self.pending_size.set(Some(size));
+            Ok(ScpRequest::NewFile {
+                name: filename(),
+                size,
+                permissions: permissions(),
+            })
+        } else if res == sys::ssh_scp_request_types::SSH_SCP_REQUEST_ENDDIR as c_int {
+            Ok(ScpRequest::EndDirectory)
+        } else if res == sys::ssh_scp_request_types::SSH_SCP_REQUEST_WARNING as c_int {
+            let warning = unsafe { sys::ssh_scp_request_get_warning(scp) };
+            let warning = if warning.is_null() {
+                String::new()
+            } else {
+                unsafe { CStr::from_ptr(warning) }
+                    .to_string_lossy()
+                    .to_string()
+            };
+            Ok(ScpRequest::Warning(warning))
+        } else if res == sys::ssh_scp_request_types::SSH_SCP_REQUEST_EOF as c_int {
+            Ok(ScpRequest::Eof)
+        } else if let Some(err) = sess.last_error() {
+            Err(err)
+        } else {
+            Err(Error::fatal("ssh_scp_pull_request failed"))
+        }
+    }
+
+    /// Returns an iterator over the requests received when pulling,
+    /// which ends once the remote host has nothing more to send
+    pub fn requests(&self) -> ScpRequests<'_> {
+        ScpRequests {
+            scp: self,
+            done: false,
+        }
+    }
+
+    /// Accept the directory or file from the most recent request
+    pub fn accept_request(&self) -> SshResult<()> {
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_accept_request(scp) };
+        sess.basic_status(res, "ssh_scp_accept_request failed")?;
+        if let Some(size) = self.pending_size.take() {
+            self.remaining.set(size);
+        }
+        Ok(())
+    }
+
+    /// Deny the directory or file from the most recent request,
+    /// sending `reason` to the remote host
+    pub fn deny_request(&self, reason: &str) -> SshResult<()> {
+        let reason = CString::new(reason)?;
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_deny_request(scp, reason.as_ptr()) };
+        self.pending_size.set(None);
+        sess.basic_status(res, "ssh_scp_deny_request failed")
+    }
+
+    /// Close the transfer, waiting for the remote host to finish
+    pub fn close(&self) -> SshResult<()> {
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_close(scp) };
+        sess.basic_status(res, "ssh_scp_close failed")
+    }
+
+    fn read_impl(&self, buf: &mut [u8]) -> SshResult<usize> {
+        let len = (buf.len() as u64).min(self.remaining.get()) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_read(scp, buf.as_mut_ptr() as _, len) };
+        if res < 0 {
+            return Err(sess
+                .last_error()
+                .unwrap_or_else(|| Error::fatal("ssh_scp_read failed")));
+        }
+        self.remaining.set(self.remaining.get() - res as u64);
+        Ok(res as usize)
+    }
+
+    fn write_impl(&self, buf: &[u8]) -> SshResult<usize> {
+        let remaining = self.remaining.get();
+        if buf.len() as u64 > remaining {
+            return Err(Error::Fatal(format!(
+                "attempt to write {} bytes to an scp file with only {} bytes left",
+                buf.len(),
+                remaining
+            )));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (sess, scp) = self.lock_session();
+        let res = unsafe { sys::ssh_scp_write(scp, buf.as_ptr() as _, buf.len()) };
+        sess.basic_status(res, "ssh_scp_write failed")?;
+        self.remaining.set(remaining - buf.len() as u64);
+        Ok(buf.len())
+    }
+}
+
+impl Read for Scp {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(self.read_impl(buf)?)
+    }
+}
+
+impl Read for &Scp {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(self.read_impl(buf)?)
+    }
+}
+
+impl Write for Scp {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(self.write_impl(buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for &Scp {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(self.write_impl(buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An iterator over the requests received when pulling via SCP
+pub struct ScpRequests<'a> {
+    scp: &'a Scp,
+    done: bool,
+}
+
+impl<'a> Iterator for ScpRequests<'a> {
+    type Item = SshResult<ScpRequest>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.scp.pull_request() {
+            Ok(ScpRequest::Eof) => {
+                self.done = true;
+                None
+            }
+            Ok(request) => Some(Ok(request)),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Rejects names that would place a pulled file anywhere
+/// other than directly inside the current directory
+fn check_name(name: &str) -> SshResult<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(Error::Fatal(format!("invalid name {:?} from scp", name)));
+    }
+    Ok(name)
+}
+
+pub(crate) fn local_mode(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        let mode = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        if metadata.is_dir() {
+            mode | 0o111
+        } else {
+            mode
+        }
+    }
+}
+
+pub(crate) fn set_local_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Create a new SCP transfer.
+    /// `location` is the remote directory to push into, or the
+    /// remote file or directory to pull.
+    /// `recursive` must be set to push or pull directories.
+    pub fn scp_new(&self, mode: ScpMode, recursive: bool, location: &str) -> SshResult<Scp> {
+        let location = CString::new(location)?;
+        let mut flags = match mode {
+            ScpMode::Write => sys::SSH_SCP_WRITE,
+            ScpMode::Read => sys::SSH_SCP_READ,
+        } as c_int;
+        if recursive {
+            flags |= sys::SSH_SCP_RECURSIVE as c_int;
+        }
+
+        let scp = {
+            let sess = self.lock_session();
+            let scp = unsafe { sys::ssh_scp_new(**sess, flags, location.as_ptr()) };
+            if scp.is_null() {
+                return Err(sess
+                    .last_error()
+                    .unwrap_or_else(|| Error::fatal("ssh_scp_new failed")));
+            }
+            Scp {
+                sess: Arc::clone(&self.sess),
+                scp_inner: scp,
+                remaining: Cell::new(0),
+                pending_size: Cell::new(None),
+            }
+        };
+
+        let (sess, scp_inner) = scp.lock_session();
+        let res = unsafe { sys::ssh_scp_init(scp_inner) };
+        sess.basic_status(res, "ssh_scp_init failed")?;
+        drop(sess);
+        Ok(scp)
+    }
+
+    /// Copy the local directory `local_dir`, and everything in it, into
+    /// the existing remote directory `remote_parent` via SCP;
+    /// this is the equivalent of `scp -r local_dir host:remote_parent`.
+    /// Symlinks are followed, and anything other than regular files
+    /// and directories is skipped. A symlink that leads back to a
+    /// directory that is being copied fails the upload.
+    pub fn scp_upload_dir<P: AsRef<Path>>(
+        &self,
+        local_dir: P,
+        remote_parent: &str,
+    ) -> SshResult<()> {
+        let local_dir = local_dir.as_ref();
+        let name = local_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::Fatal(format!("{} has no usable name", local_dir.display())))?;
+        let scp = self.scp_new(ScpMode::Write, true, remote_parent)?;
+        upload_tree(&scp, local_dir, name, &mut vec![])?;
+        scp.close()
+    }
+
+    /// Copy the remote directory `remote_dir`, and everything in it, into
+    /// the existing local directory `local_parent` via SCP;
+    /// this is the equivalent of `scp -r host:remote_dir local_parent`.
+    /// Returns any warnings that the remote host reported about
+    /// files that it was unable to send.
+    pub fn scp_download_dir<P: AsRef<Path>>(
+        &self,
+        remote_dir: &str,
+        local_parent: P,
+    ) -> SshResult<Vec<String>> {
+        let scp = self.scp_new(ScpMode::Read, true, remote_dir)?;
+        let mut warnings = vec![];
+        let mut path = local_parent.as_ref().to_path_buf();
+        // The modes to apply to the directories that we are in;
+        // they are applied when leaving, in case they are read-only
+        let mut dir_modes = vec![];
+        let mut buf = vec![0u8; 64 * 1024];
+
+        for request in scp.requests() {
+            match request? {
+                ScpRequest::NewDirectory { name, permissions } => {
+                    path.push(check_name(&name)?);
+                    if !path.is_dir() {
+                        std::fs::create_dir(&path)?;
+                    }
+                    dir_modes.push(permissions);
+                    scp.accept_request()?;
+                }
+                ScpRequest::NewFile {
+                    name, permissions, ..
+                } => {
+                    let file_path = path.join(check_name(&name)?);
+                    let mut file = File::create(&file_path)?;
+                    scp.accept_request()?;
+                    loop {
+                        let n = scp.read_impl(&mut buf)?;
+                        if n == 0 {
+                            break;
+                        }
+                        file.write_all(&buf[..n])?;
+                    }
+                    drop(file);
+                    set_local_mode(&file_path, permissions)?;
+                }
+                ScpRequest::EndDirectory => {
+                    if let Some(mode) = dir_modes.pop() {
+                        set_local_mode(&path, mode)?;
+                        path.pop();
+                    }
+                }
+                ScpRequest::Warning(warning) => warnings.push(warning),
+                ScpRequest::Eof => break,
+            }
+        }
+        scp.close()?;
+        Ok(warnings)
+    }
+}
+
+/// Uploads `local` as `name`. `ancestors` holds the canonical paths
+/// of the directories that contain it, to detect loops through symlinks.
+fn upload_tree(scp: &Scp, local: &Path, name: &str, ancestors: &mut Vec<PathBuf>) -> SshResult<()> {
+    let metadata = std::fs::metadata(local)?;
+    if metadata.is_dir() {
+        let canonical = std::fs::canonicalize(local)?;
+        if ancestors.contains(&canonical) {
+            return Err(Error::Fatal(format!(
+                "{} leads back to {}, which is already being copied",
+                local.display(),
+                canonical.display()
+            )));
+        }
+        scp.push_directory(name, local_mode(&metadata))?;
+        ancestors.push(canonical);
+        for entry in std::fs::read_dir(local)? {
+            let entry = entry?;
+            let child_name = entry.file_name();
+            let child_name = child_name.to_str().ok_or_else(|| {
+                Error::Fatal(format!("{} has a non-UTF-8 name", entry.path().display()))
+            })?;
+            upload_tree(scp, &entry.path(), child_name, ancestors)?;
+        }
+        ancestors.pop();
+        scp.leave_directory()
+    } else if metadata.is_file() {
+        scp.push_file(name, metadata.len(), local_mode(&metadata))?;
+        let mut file = File::open(local)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut remaining = metadata.len();
+        while remaining > 0 {
+            let len = (buf.len() as u64).min(remaining) as usize;
+            let n = file.read(&mut buf[..len])?;
+            if n == 0 {
+                return Err(Error::Fatal(format!(
+                    "{} was truncated while it was being uploaded",
+                    local.display()
+                )));
+            }
+            scp.write_impl(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,856 @@
+use crate::{
+    last_error_of, AuthMethods, Channel, Error, InteractiveAuthInfo, LogLevel, PendingChannel,
+    Session, SessionHolder, SshKey, SshResult,
+};
+use libssh_rs_sys as sys;
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+#[cfg(unix)]
+use std::os::unix::io::RawFd as RawSocket;
+#[cfg(windows)]
+use std::os::windows::io::RawSocket;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A `Bind` represents the listening side of an SSH server.
+///
+/// You configure the host keys and listen address via
+/// [set_option](#method.set_option), then call [listen](#method.listen)
+/// and [accept](#method.accept) to obtain a server-side `Session`
+/// for each incoming client connection.
+///
+/// ```no_run
+/// use libssh_rs::*;
+/// let bind = Bind::new().unwrap();
+/// bind.set_option(BindOption::BindAddress("127.0.0.1".to_string())).unwrap();
+/// bind.set_option(BindOption::BindPort(2222)).unwrap();
+/// bind.set_option(BindOption::HostKey("/etc/ssh/ssh_host_ed25519_key".to_string())).unwrap();
+/// bind.listen().unwrap();
+/// loop {
+///     let sess = bind.accept().unwrap();
+///     // authenticate the client and service its channels
+/// }
+/// ```
+///
+/// # Thread Safety
+///
+/// A `Bind` can be moved to another thread, and the `Session`s that
+/// it produces are independent of it and of each other, so it is
+/// common to hand each accepted `Session` off to its own thread.
+pub struct Bind {
+    bind: sys::ssh_bind,
+}
+
+unsafe impl Send for Bind {}
+
+impl Drop for Bind {
+    fn drop(&mut self) {
+        unsafe { sys::ssh_bind_free(self.bind) }
+    }
+}
+
+impl Bind {
+    /// Create a new Bind.
+    pub fn new() -> SshResult<Self> {
+        crate::initialize()?;
+        let bind = unsafe { sys::ssh_bind_new() };
+        if bind.is_null() {
+            Err(Error::fatal("ssh_bind_new failed"))
+        } else {
+            Ok(Self { bind })
+        }
+    }
+
+    fn last_error(&self) -> Option<Error> {
+        last_error_of(self.bind as _)
+    }
+
+    fn basic_status(&self, res: i32, what: &str) -> SshResult<()> {
+        if res == sys::SSH_OK as i32 {
+            Ok(())
+        } else if let Some(err) = self.last_error() {
+            Err(err)
+        } else {
+            Err(Error::fatal(what))
+        }
+    }
+
+    /// Configures the bind.
+    /// You will need to set at least one host key via either
+    /// `BindOption::HostKey` or `BindOption::ImportKey` prior to
+    /// accepting connections.
+    pub fn set_option(&self, option: BindOption) -> SshResult<()> {
+        let res = match option {
+            BindOption::BindAddress(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_BINDADDR,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::BindPort(port) => {
+                let port: c_uint = port.into();
+                unsafe {
+                    sys::ssh_bind_options_set(
+                        self.bind,
+                        sys::ssh_bind_options_e::SSH_BIND_OPTIONS_BINDPORT,
+                        &port as *const _ as _,
+                    )
+                }
+            }
+            BindOption::HostKey(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_HOSTKEY,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::ImportKey(key) => unsafe {
+                // The bind takes ownership of the key that we pass in
+                let key = sys::ssh_key_dup(key.key);
+                if key.is_null() {
+                    return Err(Error::fatal("ssh_key_dup failed"));
+                }
+                let res = sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_IMPORT_KEY,
+                    key as _,
+                );
+                if res != 0 {
+                    sys::ssh_key_free(key);
+                }
+                res
+            },
+            BindOption::Banner(banner) => unsafe {
+                let banner = CString::new(banner)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_BANNER,
+                    banner.as_ptr() as _,
+                )
+            },
+            BindOption::LogLevel(level) => unsafe {
+                let level = level.as_c_int();
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_LOG_VERBOSITY,
+                    &level as *const _ as _,
+                )
+            },
+            BindOption::KeyExchange(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_KEY_EXCHANGE,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::HostKeyAlgorithms(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_HOSTKEY_ALGORITHMS,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::PublicKeyAcceptedTypes(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_PUBKEY_ACCEPTED_KEY_TYPES,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::CiphersCS(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_CIPHERS_C_S,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::CiphersSC(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_CIPHERS_S_C,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::HmacCS(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_HMAC_C_S,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::HmacSC(name) => unsafe {
+                let name = CString::new(name)?;
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_HMAC_S_C,
+                    name.as_ptr() as _,
+                )
+            },
+            BindOption::ProcessConfig(value) => unsafe {
+                let value: c_uint = value.into();
+                sys::ssh_bind_options_set(
+                    self.bind,
+                    sys::ssh_bind_options_e::SSH_BIND_OPTIONS_PROCESS_CONFIG,
+                    &value as *const _ as _,
+                )
+            },
+        };
+
+        if res == 0 {
+            Ok(())
+        } else if let Some(err) = self.last_error() {
+            Err(err)
+        } else {
+            Err(Error::fatal("failed to set bind option"))
+        }
+    }
+
+    /// Start listening on the configured address and port.
+    ///
+    /// This is not required if you have supplied a listening
+    /// socket via [set_fd](#method.set_fd).
+    pub fn listen(&self) -> SshResult<()> {
+        let res = unsafe { sys::ssh_bind_listen(self.bind) };
+        self.basic_status(res, "ssh_bind_listen failed")
+    }
+
+    /// Adopt an existing listening socket rather than having
+    /// [listen](#method.listen) create one.
+    /// This is useful when the listener is passed in via socket
+    /// activation or was created with specific socket options.
+    ///
+    /// Ownership of the socket passes to the `Bind`, which will
+    /// close it when it is dropped.
+    pub fn set_fd(&self, fd: RawSocket) {
+        unsafe { sys::ssh_bind_set_fd(self.bind, fd as sys::socket_t) }
+    }
+
+    /// If `blocking == true` then set the bind to block mode, otherwise
+    /// set it to non-blocking mode.
+    pub fn set_blocking(&self, blocking: bool) {
+        unsafe { sys::ssh_bind_set_blocking(self.bind, if blocking { 1 } else { 0 }) }
+    }
+
+    /// Wait for and accept an incoming connection, then perform the
+    /// key exchange with the client.
+    ///
+    /// The returned `Session` has completed the key exchange and is
+    /// ready to authenticate the client.
+    pub fn accept(&self) -> SshResult<Session> {
+        let session = Session::new()?;
+        {
+            let sess = session.lock_session();
+            let res = unsafe { sys::ssh_bind_accept(self.bind, **sess) };
+            self.basic_status(res, "ssh_bind_accept failed")?;
+        }
+        session.handle_key_exchange()?;
+        Ok(session)
+    }
+
+    /// Accept a connection on a socket that your application has
+    /// already accepted from a listener, then perform the key
+    /// exchange with the client.
+    ///
+    /// Ownership of the socket passes to the returned `Session`.
+    /// The host keys must still have been configured on this `Bind`,
+    /// but it does not need to be listening.
+    pub fn accept_fd(&self, fd: RawSocket) -> SshResult<Session> {
+        let session = Session::new()?;
+        {
+            let sess = session.lock_session();
+            let res = unsafe { sys::ssh_bind_accept_fd(self.bind, **sess, fd as sys::socket_t) };
+            self.basic_status(res, "ssh_bind_accept_fd failed")?;
+        }
+        session.handle_key_exchange()?;
+        Ok(session)
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for Bind {
+    fn as_raw_fd(&self) -> RawSocket {
+        unsafe { sys::ssh_bind_get_fd(self.bind) }
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for Bind {
+    fn as_raw_socket(&self) -> RawSocket {
+        unsafe { sys::ssh_bind_get_fd(self.bind) as RawSocket }
+    }
+}
+
+impl Session {
+    /// Install the server callbacks and perform the server side of the
+    /// key exchange on a session that was accepted by a `Bind`.
+    /// `Bind::accept` and `Bind::accept_fd` call this for you.
+    pub(crate) fn handle_key_exchange(&self) -> SshResult<()> {
+        let mut sess = self.lock_session();
+        let mut state = Box::new(ServerState {
+            callbacks: sys::ssh_server_callbacks_struct {
+                size: std::mem::size_of::<sys::ssh_server_callbacks_struct>(),
+                userdata: std::ptr::null_mut(),
+                auth_password_function: Some(bridge_auth_password),
+                auth_none_function: Some(bridge_auth_none),
+                auth_gssapi_mic_function: None,
+                auth_pubkey_function: Some(bridge_auth_pubkey),
+                service_request_function: None,
+                channel_open_request_session_function: Some(bridge_channel_open_session),
+                gssapi_select_oid_function: None,
+                gssapi_accept_sec_ctx_function: None,
+                gssapi_verify_mic_function: None,
+            },
+            auth: Mutex::new(AuthState::default()),
+            pending_channels: Mutex::new(VecDeque::new()),
+        });
+        let ptr: *mut ServerState = &mut *state;
+        state.callbacks.userdata = ptr as _;
+
+        unsafe {
+            sys::ssh_set_server_callbacks(**sess, &mut state.callbacks);
+            sys::ssh_set_message_callback(**sess, Some(bridge_server_message), ptr as _);
+        }
+        sess.server.replace(state);
+
+        let res = unsafe { sys::ssh_handle_key_exchange(**sess) };
+        sess.basic_status(res, "ssh_handle_key_exchange failed")
+    }
+
+    fn server_state(sess: &SessionHolder) -> SshResult<&ServerState> {
+        sess.server
+            .as_deref()
+            .ok_or_else(|| Error::fatal("not a server session; use Bind::accept"))
+    }
+
+    /// Sets the handler that decides whether the client of a
+    /// server-side `Session` may log in.
+    ///
+    /// The handler is consulted while the session is processing
+    /// events, such as during
+    /// [wait_for_authentication](#method.wait_for_authentication).
+    /// Requests that arrive before a handler is set are denied.
+    pub fn set_server_auth_handler<H: ServerAuthHandler + 'static>(
+        &self,
+        handler: H,
+    ) -> SshResult<()> {
+        let sess = self.lock_session();
+        let state = Self::server_state(&sess)?;
+        state
+            .auth
+            .lock()
+            .unwrap()
+            .handler
+            .replace(Box::new(handler));
+        Ok(())
+    }
+
+    /// Returns the name of the user that has successfully
+    /// authenticated to this server-side session, if any.
+    pub fn authenticated_user(&self) -> Option<String> {
+        let sess = self.lock_session();
+        let state = Self::server_state(&sess).ok()?;
+        let auth = state.auth.lock().unwrap();
+        auth.authenticated_user.clone()
+    }
+
+    /// Processes events on a server-side session until the client
+    /// has successfully authenticated, returning the user name that
+    /// it authenticated as.
+    ///
+    /// If `timeout` is `None`, then blocks until authentication completes.
+    /// Otherwise, returns `Error::TryAgain` if authentication has not
+    /// completed within the timeout.
+    pub fn wait_for_authentication(&self, timeout: Option<Duration>) -> SshResult<String> {
+        let sess = self.lock_session();
+        Self::server_state(&sess)?;
+        sess.poll_until(timeout, |sess| {
+            sess.server
+                .as_ref()
+                .and_then(|state| state.auth.lock().unwrap().authenticated_user.clone())
+        })
+    }
+
+    /// Processes events on a server-side session until the client
+    /// opens a session channel, and returns that channel.
+    ///
+    /// You will typically then use
+    /// [Channel::set_request_handler](struct.Channel.html#method.set_request_handler)
+    /// to decide which of the client's requests to accept.
+    ///
+    /// If `timeout` is `None`, then blocks until a channel is opened.
+    /// Otherwise, returns `Error::TryAgain` if no channel was opened
+    /// within the timeout.
+    pub fn accept_channel(&self, timeout: Option<Duration>) -> SshResult<Channel> {
+        let pending = {
+            let sess = self.lock_session();
+            Self::server_state(&sess)?;
+            sess.poll_until(timeout, |sess| {
+                sess.server
+                    .as_ref()
+                    .and_then(|state| state.pending_channels.lock().unwrap().pop_front())
+            })?
+        };
+        Ok(pending.into_channel(&self.sess))
+    }
+
+    /// Processes any pending events on the session, dispatching them to
+    /// the callbacks and handlers that have been registered with it.
+    ///
+    /// If `timeout` is `None`, then blocks until at least one event
+    /// has been processed.  Otherwise, returns after the timeout
+    /// if no event arrived.
+    pub fn process_events(&self, timeout: Option<Duration>) -> SshResult<()> {
+        let sess = self.lock_session();
+        let mut polled = false;
+        match sess.poll_until(timeout, |_| {
+            if polled {
+                Some(())
+            } else {
+                polled = true;
+                None
+            }
+        }) {
+            Err(Error::TryAgain) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl SessionHolder {
+    /// Runs the libssh event loop for this session, dispatching any
+    /// callbacks, until `done` returns `Some` value.
+    pub(crate) fn poll_until<T>(
+        &self,
+        timeout: Option<Duration>,
+        mut done: impl FnMut(&Self) -> Option<T>,
+    ) -> SshResult<T> {
+        if let Some(result) = done(self) {
+            return Ok(result);
+        }
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let event = unsafe { sys::ssh_event_new() };
+        if event.is_null() {
+            return Err(Error::fatal("ssh_event_new failed"));
+        }
+
+        let res = unsafe { sys::ssh_event_add_session(event, self.sess) };
+        let result = if res != sys::SSH_OK as i32 {
+            Err(Error::fatal("ssh_event_add_session failed"))
+        } else {
+            loop {
+                if let Some(result) = done(self) {
+                    break Ok(result);
+                }
+
+                let status = unsafe { sys::ssh_get_status(self.sess) } as u32;
+                if status & (sys::SSH_CLOSED | sys::SSH_CLOSED_ERROR) != 0 {
+                    break Err(self
+                        .last_error()
+                        .unwrap_or_else(|| Error::fatal("the connection was closed")));
+                }
+
+                let timeout = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break Err(Error::TryAgain);
+                        }
+                        (deadline - now).as_millis().max(1) as c_int
+                    }
+                    None => -1,
+                };
+
+                let res = unsafe { sys::ssh_event_dopoll(event, timeout) };
+                if res == sys::SSH_ERROR {
+                    break Err(self
+                        .last_error()
+                        .unwrap_or_else(|| Error::fatal("ssh_event_dopoll failed")));
+                }
+            }
+        };
+
+        unsafe {
+            sys::ssh_event_remove_session(event, self.sess);
+            sys::ssh_event_free(event);
+        }
+        result
+    }
+}
+
+/// State used by the callbacks of a server-side session
+pub(crate) struct ServerState {
+    callbacks: sys::ssh_server_callbacks_struct,
+    auth: Mutex<AuthState>,
+    /// Session channels opened by the client that have not yet
+    /// been returned by `Session::accept_channel`.
+    pending_channels: Mutex<VecDeque<PendingChannel>>,
+}
+
+unsafe extern "C" fn bridge_channel_open_session(
+    session: sys::ssh_session,
+    userdata: *mut c_void,
+) -> sys::ssh_channel {
+    let state: &ServerState = &*(userdata as *const ServerState);
+    let chan = sys::ssh_channel_new(session);
+    if !chan.is_null() {
+        state
+            .pending_channels
+            .lock()
+            .unwrap()
+            .push_back(PendingChannel::new(chan));
+    }
+    chan
+}
+
+#[derive(Default)]
+struct AuthState {
+    handler: Option<Box<dyn ServerAuthHandler>>,
+    kbdint_user: Option<String>,
+    authenticated_user: Option<String>,
+}
+
+impl AuthState {
+    /// Calls into the handler, treating a missing handler or a
+    /// panic in the handler as `default`.
+    fn call<R>(&mut self, default: R, func: impl FnOnce(&mut dyn ServerAuthHandler) -> R) -> R {
+        let handler = match self.handler.as_mut() {
+            Some(handler) => handler,
+            None => return default,
+        };
+        match std::panic::catch_unwind(AssertUnwindSafe(|| func(handler.as_mut()))) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("Error in server auth handler: {:?}", err);
+                default
+            }
+        }
+    }
+
+    /// Records the outcome of an authentication attempt, and updates the
+    /// methods that libssh will advertise to the client in its reply.
+    fn conclude(
+        &mut self,
+        session: sys::ssh_session,
+        user: &str,
+        status: ServerAuthStatus,
+    ) -> ServerAuthStatus {
+        if status == ServerAuthStatus::Success {
+            self.authenticated_user.replace(user.to_string());
+        } else {
+            let methods = self.call(AuthMethods::empty(), |h| h.auth_methods(user));
+            unsafe { sys::ssh_set_auth_methods(session, methods.bits() as c_int) };
+        }
+        status
+    }
+
+    fn decide(
+        &mut self,
+        session: sys::ssh_session,
+        user: &str,
+        func: impl FnOnce(&mut dyn ServerAuthHandler) -> ServerAuthStatus,
+    ) -> c_int {
+        let status = self.call(ServerAuthStatus::Denied, func);
+        self.conclude(session, user, status).as_auth_e()
+    }
+}
+
+fn cstr_to_string(cstr: *const c_char) -> String {
+    if cstr.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(cstr) }
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+unsafe extern "C" fn bridge_auth_none(
+    session: sys::ssh_session,
+    user: *const c_char,
+    userdata: *mut c_void,
+) -> c_int {
+    let state: &ServerState = &*(userdata as *const ServerState);
+    let user = cstr_to_string(user);
+    let mut auth = state.auth.lock().unwrap();
+    auth.decide(session, &user, |h| h.auth_none(&user))
+}
+
+unsafe extern "C" fn bridge_auth_password(
+    session: sys::ssh_session,
+    user: *const c_char,
+    password: *const c_char,
+    userdata: *mut c_void,
+) -> c_int {
+    let state: &ServerState = &*(userdata as *const ServerState);
+    let user = cstr_to_string(user);
+    let password = cstr_to_string(password);
+    let mut auth = state.auth.lock().unwrap();
+    auth.decide(session, &user, |h| h.auth_password(&user, &password))
+}
+
+unsafe extern "C" fn bridge_auth_pubkey(
+    session: sys::ssh_session,
+    user: *const c_char,
+    pubkey: *mut sys::ssh_key_struct,
+    signature_state: c_char,
+    userdata: *mut c_void,
+) -> c_int {
+    let state: &ServerState = &*(userdata as *const ServerState);
+    let user = cstr_to_string(user);
+    // The key is owned by the message that libssh is processing
+    let key = ManuallyDrop::new(SshKey { key: pubkey });
+    let mut auth = state.auth.lock().unwrap();
+
+    match signature_state as i32 {
+        // The client is asking whether it may authenticate with this
+        // key before going to the trouble of signing with it.
+        // libssh replies with SSH_MSG_USERAUTH_PK_OK when we return success.
+        s if s == sys::ssh_publickey_state_e::SSH_PUBLICKEY_STATE_NONE as i32 => {
+            if auth.call(false, |h| h.auth_try_publickey(&user, &key)) {
+                sys::ssh_auth_e_SSH_AUTH_SUCCESS
+            } else {
+                auth.conclude(session, &user, ServerAuthStatus::Denied)
+                    .as_auth_e()
+            }
+        }
+        s if s == sys::ssh_publickey_state_e::SSH_PUBLICKEY_STATE_VALID as i32 => {
+            auth.decide(session, &user, |h| h.auth_publickey(&user, &key))
+        }
+        _ => auth
+            .conclude(session, &user, ServerAuthStatus::Denied)
+            .as_auth_e(),
+    }
+}
+
+/// libssh doesn't have a server callback for keyboard-interactive
+/// authentication, so we handle it via the message callback instead.
+/// Returning `1` causes libssh to send its default (failure) reply
+/// to any other messages.
+unsafe extern "C" fn bridge_server_message(
+    session: sys::ssh_session,
+    msg: sys::ssh_message,
+    userdata: *mut c_void,
+) -> c_int {
+    let state: &ServerState = &*(userdata as *const ServerState);
+
+    if sys::ssh_message_type(msg) == sys::ssh_requests_e::SSH_REQUEST_AUTH as c_int
+        && sys::ssh_message_subtype(msg) == sys::SSH_AUTH_METHOD_INTERACTIVE as c_int
+    {
+        let mut auth = state.auth.lock().unwrap();
+        let denied = InteractiveAuthReply::Status(ServerAuthStatus::Denied);
+
+        let (user, reply) = if sys::ssh_message_auth_kbdint_is_response(msg) != 0 {
+            let user = auth.kbdint_user.clone().unwrap_or_default();
+            let n_answers = sys::ssh_userauth_kbdint_getnanswers(session).max(0) as u32;
+            let answers: Vec<String> = (0..n_answers)
+                .map(|i| cstr_to_string(sys::ssh_userauth_kbdint_getanswer(session, i)))
+                .collect();
+            let reply = auth.call(denied, |h| {
+                h.auth_keyboard_interactive_response(&user, &answers)
+            });
+            (user, reply)
+        } else {
+            let user = cstr_to_string(sys::ssh_message_auth_user(msg));
+            auth.kbdint_user.replace(user.clone());
+            let reply = auth.call(denied, |h| h.auth_keyboard_interactive(&user));
+            (user, reply)
+        };
+
+        match reply {
+            InteractiveAuthReply::Prompt(info) => {
+                if send_interactive_request(msg, &info).is_err() {
+                    auth.conclude(session, &user, ServerAuthStatus::Denied);
+                    sys::ssh_message_reply_default(msg);
+                }
+            }
+            InteractiveAuthReply::Status(status) => {
+                match auth.conclude(session, &user, status) {
+                    ServerAuthStatus::Success => sys::ssh_message_auth_reply_success(msg, 0),
+                    ServerAuthStatus::Partial => sys::ssh_message_auth_reply_success(msg, 1),
+                    ServerAuthStatus::Denied => sys::ssh_message_reply_default(msg),
+                };
+            }
+        }
+        return 0;
+    }
+
+    1
+}
+
+fn send_interactive_request(msg: sys::ssh_message, info: &InteractiveAuthInfo) -> SshResult<()> {
+    let name = CString::new(info.name.as_str())?;
+    let instruction = CString::new(info.instruction.as_str())?;
+    let prompts = info
+        .prompts
+        .iter()
+        .map(|p| CString::new(p.prompt.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut prompt_ptrs: Vec<*const c_char> = prompts.iter().map(|p| p.as_ptr()).collect();
+    let mut echo: Vec<c_char> = info
+        .prompts
+        .iter()
+        .map(|p| if p.echo { 1 } else { 0 })
+        .collect();
+
+    let res = unsafe {
+        sys::ssh_message_auth_interactive_request(
+            msg,
+            name.as_ptr(),
+            instruction.as_ptr(),
+            prompt_ptrs.len() as c_uint,
+            prompt_ptrs.as_mut_ptr(),
+            echo.as_mut_ptr(),
+        )
+    };
+    if res == sys::SSH_OK as i32 {
+        Ok(())
+    } else {
+        Err(Error::fatal("ssh_message_auth_interactive_request failed"))
+    }
+}
+
+/// Indicates the decision made by a `ServerAuthHandler`.
+/// This mirrors the client-side `AuthStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerAuthStatus {
+    /// The client is fully authenticated and may now open channels
+    Success,
+    /// This step succeeded, but the client must continue with another
+    /// of the methods returned by `ServerAuthHandler::auth_methods`
+    /// before it is fully authenticated.
+    Partial,
+    /// The authentication attempt failed.
+    Denied,
+}
+
+impl ServerAuthStatus {
+    fn as_auth_e(self) -> c_int {
+        match self {
+            Self::Success => sys::ssh_auth_e_SSH_AUTH_SUCCESS,
+            Self::Partial => sys::ssh_auth_e_SSH_AUTH_PARTIAL,
+            Self::Denied => sys::ssh_auth_e_SSH_AUTH_DENIED,
+        }
+    }
+}
+
+/// The response to a keyboard-interactive authentication step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InteractiveAuthReply {
+    /// Ask the client to answer these prompts.  The answers will be
+    /// passed to `ServerAuthHandler::auth_keyboard_interactive_response`.
+    Prompt(InteractiveAuthInfo),
+    /// Conclude this keyboard-interactive exchange
+    Status(ServerAuthStatus),
+}
+
+/// Decides whether the client of a server-side `Session` may log in.
+///
+/// Each method corresponds to an authentication request made by the
+/// client and, apart from [auth_methods](#tymethod.auth_methods),
+/// defaults to denying that request, so you only need to implement
+/// the methods that your server supports.
+///
+/// Multi-factor flows can be expressed by returning
+/// `ServerAuthStatus::Partial` from one method and then changing the
+/// set of methods returned by `auth_methods` for that user.
+pub trait ServerAuthHandler: Send {
+    /// Returns the authentication methods that the client may continue
+    /// with; this is sent to the client after a failed or partially
+    /// successful authentication attempt.
+    fn auth_methods(&mut self, user: &str) -> AuthMethods;
+
+    /// The client is attempting `"none"` authentication.
+    fn auth_none(&mut self, _user: &str) -> ServerAuthStatus {
+        ServerAuthStatus::Denied
+    }
+
+    /// The client is attempting password authentication.
+    fn auth_password(&mut self, _user: &str, _password: &str) -> ServerAuthStatus {
+        ServerAuthStatus::Denied
+    }
+
+    /// The client is asking whether it would be permitted to
+    /// authenticate with `key`, without yet proving that it holds
+    /// the private key.  Return `true` if the key is acceptable.
+    fn auth_try_publickey(&mut self, _user: &str, _key: &SshKey) -> bool {
+        false
+    }
+
+    /// The client has proven that it holds the private key
+    /// corresponding to `key`.
+    fn auth_publickey(&mut self, _user: &str, _key: &SshKey) -> ServerAuthStatus {
+        ServerAuthStatus::Denied
+    }
+
+    /// The client is starting keyboard-interactive authentication.
+    fn auth_keyboard_interactive(&mut self, _user: &str) -> InteractiveAuthReply {
+        InteractiveAuthReply::Status(ServerAuthStatus::Denied)
+    }
+
+    /// The client has answered the prompts that were previously
+    /// returned via `InteractiveAuthReply::Prompt`.
+    fn auth_keyboard_interactive_response(
+        &mut self,
+        _user: &str,
+        _answers: &[String],
+    ) -> InteractiveAuthReply {
+        InteractiveAuthReply::Status(ServerAuthStatus::Denied)
+    }
+}
+
+/// Allows configuring different aspects of a `Bind`.
+/// You always need to set at least one host key.
+pub enum BindOption {
+    /// The address to listen on
+    BindAddress(String),
+
+    /// The port to listen on
+    BindPort(u16),
+
+    /// The path to a private host key file.
+    /// May be specified multiple times for keys of different types.
+    HostKey(String),
+
+    /// Use an already loaded private key as a host key.
+    ImportKey(SshKey),
+
+    /// The banner sent to clients before authentication
+    Banner(String),
+
+    LogLevel(LogLevel),
+
+    /// Set the key exchange methods to be used. ex:
+    /// ecdh-sha2-nistp256,diffie-hellman-group14-sha1,diffie-hellman-group1-sha1
+    KeyExchange(String),
+    /// Set the host key algorithms offered to clients as a
+    /// comma-separated list. ex:
+    /// ssh-ed25519,rsa-sha2-256,ecdsa-sha2-nistp256
+    HostKeyAlgorithms(String),
+    /// Set the public key algorithms accepted for client
+    /// authentication as a comma-separated list.
+    PublicKeyAcceptedTypes(String),
+    ///Set the symmetric cipher client to server as a comma-separated list.
+    CiphersCS(String),
+    ///Set the symmetric cipher server to client as a comma-separated list.
+    CiphersSC(String),
+    /// Set the MAC algorithm client to server as a comma-separated list.
+    HmacCS(String),
+    /// Set the MAC algorithm server to client as a comma-separated list.
+    HmacSC(String),
+    /// Set it to false to disable automatic processing of the system-wide
+    /// sshd configuration file.
+    ProcessConfig(bool),
+}
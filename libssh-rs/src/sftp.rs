@@ -7,6 +7,32 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
+mod pipeline;
+mod raw;
+mod resume;
+mod sync;
+mod tree;
+
+pub use self::pipeline::*;
+pub use self::resume::*;
+pub use self::sync::*;
+pub use self::tree::*;
+
+/// The `open(2)` flags that libssh translates into sftp open flags
+#[cfg(unix)]
+pub(crate) mod open_flags {
+    pub use libc::{O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY};
+}
+
+#[cfg(windows)]
+pub(crate) mod open_flags {
+    use std::os::raw::c_int;
+    pub const O_RDONLY: c_int = 0x0000;
+    pub const O_WRONLY: c_int = 0x0001;
+    pub const O_CREAT: c_int = 0x0100;
+    pub const O_TRUNC: c_int = 0x0200;
+}
+
 #[derive(Error, Debug, PartialEq, Eq)]
 #[error("Sftp error code {}", .0)]
 pub struct SftpError(u32);
@@ -392,7 +418,12 @@ impl std::io::Seek for SftpFile {
                 }
             }
             std::io::SeekFrom::End(p) => {
-                let end = self.metadata().map_err(|e| e)?.len().ok_or_else(|| {
+                // The session is already locked, so `self.metadata()` can't be used
+                let attr = unsafe { sys::sftp_fstat(file) };
+                if attr.is_null() {
+                    return Err(io_err_from_sftp(self.sftp, "fstat"));
+                }
+                let end = Metadata { attr }.len().ok_or_else(|| {
                     std::io::Error::new(
                         std::io::ErrorKind::Other,
                         "metadata didn't return the length",
@@ -458,6 +489,8 @@ pub struct Metadata {
     attr: sys::sftp_attributes,
 }
 
+unsafe impl Send for Metadata {}
+
 impl Drop for Metadata {
     fn drop(&mut self) {
         unsafe { sys::sftp_attributes_free(self.attr) }
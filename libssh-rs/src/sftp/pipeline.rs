@@ -0,0 +1,552 @@
+use super::raw::{status_result, RawRequests, MAX_REQUEST_LEN};
+use super::{Sftp, SftpError, SftpFile};
+use crate::cert::put_string;
+use crate::{Error, SshResult};
+use libssh_rs_sys as sys;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+
+/// Controls how many requests a pipelined transfer keeps in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineOptions {
+    /// The number of bytes transferred by each request.
+    /// Servers may answer read requests with less data than was
+    /// asked for; the remainder is then requested separately.
+    /// Write requests must fit in a single sftp packet, which
+    /// OpenSSH limits to 256KiB, so they are made smaller as needed.
+    pub chunk_size: u32,
+    /// The maximum number of requests that are awaiting a response
+    pub max_requests: usize,
+}
+
+impl Default for PipelineOptions {
+    /// The same defaults as the OpenSSH sftp client
+    fn default() -> Self {
+        Self {
+            chunk_size: 32 * 1024,
+            max_requests: 64,
+        }
+    }
+}
+
+/// The asynchronous read operations that a pipelined download is
+/// built from, separated from `SftpFile` so that the scheduling
+/// can be tested without a server
+pub(crate) trait AsyncRead {
+    /// Sends a request to read `len` bytes at `offset`,
+    /// returning its id
+    fn begin_read(&mut self, offset: u64, len: u32) -> SshResult<u32>;
+
+    /// Waits for the response to request `id`, storing the data in
+    /// `buf` and returning its length, which is 0 at end of file
+    fn finish_read(&mut self, id: u32, buf: &mut [u8]) -> SshResult<usize>;
+}
+
+impl AsyncRead for SftpFile {
+    fn begin_read(&mut self, offset: u64, len: u32) -> SshResult<u32> {
+        let (_sess, file) = self.lock_session();
+        // Requests are made at the current offset of the file
+        unsafe { sys::sftp_seek64(file, offset) };
+        let id = unsafe { sys::sftp_async_read_begin(file, len) };
+        if id < 0 {
+            Err(Error::Sftp(SftpError::from_session(self.sftp)))
+        } else {
+            Ok(id as u32)
+        }
+    }
+
+    fn finish_read(&mut self, id: u32, buf: &mut [u8]) -> SshResult<usize> {
+        let (_sess, file) = self.lock_session();
+        // Once a response has indicated the end of the file, libssh
+        // returns 0 without waiting for other responses; seeking
+        // clears that state without changing the offset
+        unsafe { sys::sftp_seek64(file, sys::sftp_tell64(file)) };
+        let res =
+            unsafe { sys::sftp_async_read(file, buf.as_mut_ptr() as _, buf.len() as u32, id) };
+        if res >= 0 {
+            Ok(res as usize)
+        } else if res == sys::SSH_AGAIN {
+            Err(Error::TryAgain)
+        } else {
+            Err(Error::Sftp(SftpError::from_session(self.sftp)))
+        }
+    }
+}
+
+struct ReadRequest {
+    id: u32,
+    offset: u64,
+    len: u32,
+}
+
+/// Reads from `start` to the end of the file, keeping up to
+/// `options.max_requests` requests in flight, and writes the data
+/// to `writer` in order. Returns the offset of the end of the file.
+pub(crate) fn pipelined_read<R: AsyncRead, W: Write>(
+    reader: &mut R,
+    start: u64,
+    writer: &mut W,
+    options: &PipelineOptions,
+) -> SshResult<u64> {
+    let chunk_size = options.chunk_size.max(1);
+    let max_requests = options.max_requests.max(1);
+    let mut buf = vec![0u8; chunk_size as usize];
+
+    let mut requests = VecDeque::new();
+    let mut next_offset = start;
+    let mut end_of_file: Option<u64> = None;
+    let mut written = start;
+    // Data that arrived ahead of a gap left by a short read
+    let mut pending: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+
+    loop {
+        while end_of_file.is_none() && requests.len() < max_requests {
+            let id = reader.begin_read(next_offset, chunk_size)?;
+            requests.push_back(ReadRequest {
+                id,
+                offset: next_offset,
+                len: chunk_size,
+            });
+            next_offset += chunk_size as u64;
+        }
+
+        let request = match requests.pop_front() {
+            Some(request) => request,
+            None => break,
+        };
+        let len = reader.finish_read(request.id, &mut buf[..request.len as usize])?;
+        if len == 0 {
+            end_of_file = Some(end_of_file.map_or(request.offset, |end| end.min(request.offset)));
+            continue;
+        }
+
+        if (len as u32) < request.len {
+            let offset = request.offset + len as u64;
+            let remaining = request.len - len as u32;
+            let id = reader.begin_read(offset, remaining)?;
+            requests.push_back(ReadRequest {
+                id,
+                offset,
+                len: remaining,
+            });
+        }
+
+        if request.offset == written {
+            writer.write_all(&buf[..len])?;
+            written += len as u64;
+            while let Some(data) = pending.remove(&written) {
+                writer.write_all(&data)?;
+                written += data.len() as u64;
+            }
+        } else {
+            pending.insert(request.offset, buf[..len].to_vec());
+        }
+    }
+
+    if !pending.is_empty() || Some(written) != end_of_file {
+        return Err(Error::fatal(
+            "the file changed size while it was being read",
+        ));
+    }
+    Ok(written)
+}
+
+impl SftpFile {
+    /// Reads the file from the current position to the end, writing
+    /// the data to `writer`, and returns the number of bytes read.
+    ///
+    /// Unlike the `Read` implementation, which waits for the response
+    /// to each request before sending the next, this keeps several
+    /// requests in flight, which is much faster over links with a
+    /// high latency.
+    /// The file must be in blocking mode, which is the default.
+    pub fn read_pipelined<W: Write>(
+        &mut self,
+        writer: &mut W,
+        options: &PipelineOptions,
+    ) -> SshResult<u64> {
+        let start = {
+            let (_sess, file) = self.lock_session();
+            unsafe { sys::sftp_tell64(file) }
+        };
+        let result = pipelined_read(self, start, writer, options);
+
+        let (_sess, file) = self.lock_session();
+        match result {
+            Ok(end) => {
+                unsafe { sys::sftp_seek64(file, end) };
+                Ok(end - start)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The asynchronous write operations that a pipelined upload is
+/// built from, separated from the sftp channel so that the
+/// scheduling can be tested without a server
+pub(crate) trait AsyncWrite {
+    /// The largest amount of data that a single request can carry
+    fn max_write_len(&self) -> u32;
+
+    /// Sends a request to write `data` at `offset`, returning its id
+    fn begin_write(&mut self, offset: u64, data: &[u8]) -> SshResult<u32>;
+
+    /// Waits for the response to request `id`.
+    /// The server either writes all of the data or fails the request.
+    fn finish_write(&mut self, id: u32) -> SshResult<()>;
+}
+
+/// Sends write requests directly on the sftp channel, as libssh
+/// only provides a write function that waits for each response
+struct ChannelWriter {
+    raw: RawRequests,
+    handle: Vec<u8>,
+}
+
+impl ChannelWriter {
+    /// # Safety
+    /// `sftp` and `file` must be valid, and the session locked
+    unsafe fn new(sftp: sys::sftp_session, file: sys::sftp_file) -> Self {
+        let handle = (*file).handle;
+        let handle = std::slice::from_raw_parts(
+            sys::ssh_string_data(handle) as *const u8,
+            sys::ssh_string_len(handle),
+        )
+        .to_vec();
+        Self {
+            raw: RawRequests::new(sftp),
+            handle,
+        }
+    }
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn max_write_len(&self) -> u32 {
+        // The handle, offset and length precede the data
+        (MAX_REQUEST_LEN - (4 + self.handle.len() + 8 + 4)) as u32
+    }
+
+    fn begin_write(&mut self, offset: u64, data: &[u8]) -> SshResult<u32> {
+        let id = self.raw.next_id();
+        let mut header = Vec::with_capacity(self.handle.len() + 16);
+        put_string(&mut header, &self.handle);
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.raw.send(sys::SSH_FXP_WRITE, id, &[&header, data])?;
+        Ok(id)
+    }
+
+    fn finish_write(&mut self, id: u32) -> SshResult<()> {
+        let (packet_type, body) = self.raw.wait(id)?;
+        status_result(packet_type, &body)
+    }
+}
+
+/// Fills `buf` from `reader`, returning less than its length
+/// only at the end of the input
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Writes the contents of `reader` starting at `start`, keeping up
+/// to `options.max_requests` requests in flight.
+/// Returns the offset up to which the data was written contiguously
+/// from `start`, along with the first error.
+///
+/// The response to every request that was sent is waited for, even
+/// after an error, so that none are left unread on the channel.
+/// Requests that follow a failed one may still have succeeded, so
+/// the file may also contain data beyond the returned offset.
+pub(crate) fn pipelined_write<W: AsyncWrite, R: Read>(
+    writer: &mut W,
+    start: u64,
+    reader: &mut R,
+    options: &PipelineOptions,
+) -> (u64, SshResult<()>) {
+    let chunk_size = options.chunk_size.clamp(1, writer.max_write_len());
+    let max_requests = options.max_requests.max(1);
+    let mut buf = vec![0u8; chunk_size as usize];
+
+    // The id of each outstanding request and the offset of its end
+    let mut requests = VecDeque::new();
+    let mut offset = start;
+    let mut acknowledged = start;
+    let mut result = loop {
+        if requests.len() == max_requests {
+            let (id, end) = requests.pop_front().unwrap();
+            match writer.finish_write(id) {
+                Ok(()) => acknowledged = end,
+                Err(err) => break Err(err),
+            }
+        }
+
+        let len = match read_chunk(reader, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => len,
+            Err(err) => break Err(err.into()),
+        };
+        match writer.begin_write(offset, &buf[..len]) {
+            Ok(id) => requests.push_back((id, offset + len as u64)),
+            Err(err) => break Err(err),
+        }
+        offset += len as u64;
+    };
+
+    // Responses are waited for in the order the requests were sent,
+    // so the data is contiguous up to the first failure
+    for (id, end) in requests {
+        let status = writer.finish_write(id);
+        if result.is_ok() {
+            if status.is_ok() {
+                acknowledged = end;
+            }
+            result = status;
+        }
+    }
+    (acknowledged, result)
+}
+
+impl SftpFile {
+    /// Writes the contents of `reader` to the file, starting at the
+    /// current position, and returns the number of bytes written.
+    ///
+    /// Unlike the `Write` implementation, which waits for the server
+    /// to acknowledge each write before sending the next, this keeps
+    /// several requests in flight, which is much faster over links
+    /// with a high latency.
+    /// The session is locked until the upload has finished, and must
+    /// be in blocking mode, which is the default.
+    ///
+    /// If an error occurs, some of the data may have been written,
+    /// not necessarily contiguously.
+    pub fn write_pipelined<R: Read>(
+        &mut self,
+        reader: &mut R,
+        options: &PipelineOptions,
+    ) -> SshResult<u64> {
+        let (written, result) = self.write_pipelined_contiguous(reader, options);
+        result.map(|()| written)
+    }
+
+    /// Like `write_pipelined`, but returns the number of bytes that
+    /// were written contiguously from the current position even if
+    /// an error occurs, and leaves the position at their end
+    pub(crate) fn write_pipelined_contiguous<R: Read>(
+        &mut self,
+        reader: &mut R,
+        options: &PipelineOptions,
+    ) -> (u64, SshResult<()>) {
+        let (_sess, file) = self.lock_session();
+        let start = unsafe { sys::sftp_tell64(file) };
+        let mut writer = unsafe { ChannelWriter::new(self.sftp, file) };
+        let (end, result) = pipelined_write(&mut writer, start, reader, options);
+        unsafe { sys::sftp_seek64(file, end) };
+        (end - start, result)
+    }
+}
+
+impl Sftp {
+    /// Downloads the file `remote`, writing its contents to `writer`.
+    /// See [SftpFile::read_pipelined](struct.SftpFile.html#method.read_pipelined).
+    pub fn download<W: Write>(
+        &self,
+        remote: &str,
+        writer: &mut W,
+        options: &PipelineOptions,
+    ) -> SshResult<u64> {
+        let mut file = self.open(remote, super::open_flags::O_RDONLY, 0)?;
+        file.read_pipelined(writer, options)
+    }
+
+    /// Creates or truncates the file `remote`, with the permissions
+    /// `mode` if it is created, and writes the contents of `reader`
+    /// to it.
+    /// See [SftpFile::write_pipelined](struct.SftpFile.html#method.write_pipelined).
+    pub fn upload<R: Read>(
+        &self,
+        remote: &str,
+        reader: &mut R,
+        mode: sys::mode_t,
+        options: &PipelineOptions,
+    ) -> SshResult<u64> {
+        use super::open_flags::{O_CREAT, O_TRUNC, O_WRONLY};
+        let mut file = self.open(remote, O_WRONLY | O_CREAT | O_TRUNC, mode)?;
+        file.write_pipelined(reader, options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Serves reads from `data`, limiting each response to
+    /// `max_read` bytes, and answers requests in order
+    struct FakeFile {
+        data: Vec<u8>,
+        max_read: usize,
+        requests: Vec<(u64, u32)>,
+        outstanding: usize,
+        max_outstanding: usize,
+    }
+
+    impl AsyncRead for FakeFile {
+        fn begin_read(&mut self, offset: u64, len: u32) -> SshResult<u32> {
+            self.requests.push((offset, len));
+            self.outstanding += 1;
+            self.max_outstanding = self.max_outstanding.max(self.outstanding);
+            Ok(self.requests.len() as u32 - 1)
+        }
+
+        fn finish_read(&mut self, id: u32, buf: &mut [u8]) -> SshResult<usize> {
+            self.outstanding -= 1;
+            let (offset, len) = self.requests[id as usize];
+            let offset = (offset as usize).min(self.data.len());
+            let end = (offset + (len as usize).min(self.max_read)).min(self.data.len());
+            buf[..end - offset].copy_from_slice(&self.data[offset..end]);
+            Ok(end - offset)
+        }
+    }
+
+    fn read_all(len: usize, max_read: usize, start: u64) -> (Vec<u8>, FakeFile) {
+        let mut file = FakeFile {
+            data: (0..len).map(|i| i as u8).collect(),
+            max_read,
+            requests: vec![],
+            outstanding: 0,
+            max_outstanding: 0,
+        };
+        let options = PipelineOptions {
+            chunk_size: 10,
+            max_requests: 4,
+        };
+        let mut out = vec![];
+        let end = pipelined_read(&mut file, start, &mut out, &options).unwrap();
+        assert_eq!(end, len as u64);
+        (out, file)
+    }
+
+    #[test]
+    fn pipelined() {
+        for len in [0, 1, 10, 11, 95, 100] {
+            for max_read in [3, 10] {
+                let (out, file) = read_all(len, max_read, 0);
+                assert_eq!(out, file.data);
+                assert!(file.max_outstanding <= 4);
+            }
+        }
+        let (out, file) = read_all(100, 10, 42);
+        assert_eq!(out, &file.data[42..]);
+        assert_eq!(file.max_outstanding, 4);
+    }
+
+    /// Stores written data, failing the request with id `fail`
+    struct FakeWriter {
+        data: Vec<u8>,
+        fail: Option<u32>,
+        max_len: u32,
+        outstanding: Vec<(u32, u64, Vec<u8>)>,
+        next_id: u32,
+        max_outstanding: usize,
+    }
+
+    impl AsyncWrite for FakeWriter {
+        fn max_write_len(&self) -> u32 {
+            self.max_len
+        }
+
+        fn begin_write(&mut self, offset: u64, data: &[u8]) -> SshResult<u32> {
+            assert!(data.len() <= self.max_len as usize);
+            self.next_id += 1;
+            self.outstanding.push((self.next_id, offset, data.to_vec()));
+            self.max_outstanding = self.max_outstanding.max(self.outstanding.len());
+            Ok(self.next_id)
+        }
+
+        fn finish_write(&mut self, id: u32) -> SshResult<()> {
+            let index = self.outstanding.iter().position(|r| r.0 == id).unwrap();
+            let (_, offset, data) = self.outstanding.remove(index);
+            if self.fail == Some(id) {
+                return Err(Error::Sftp(SftpError(sys::SSH_FX_FAILURE)));
+            }
+            let offset = offset as usize;
+            if self.data.len() < offset + data.len() {
+                self.data.resize(offset + data.len(), 0);
+            }
+            self.data[offset..offset + data.len()].copy_from_slice(&data);
+            Ok(())
+        }
+    }
+
+    /// Returns at most 7 bytes from each read
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn write_all(
+        len: usize,
+        fail: Option<u32>,
+        max_len: u32,
+    ) -> ((u64, SshResult<()>), FakeWriter, Vec<u8>) {
+        let input: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut writer = FakeWriter {
+            data: vec![],
+            fail,
+            max_len,
+            outstanding: vec![],
+            next_id: 0,
+            max_outstanding: 0,
+        };
+        let options = PipelineOptions {
+            chunk_size: 10,
+            max_requests: 4,
+        };
+        let result = pipelined_write(&mut writer, 0, &mut Trickle(&input), &options);
+        (result, writer, input)
+    }
+
+    #[test]
+    fn pipelined_writes() {
+        for len in [0, 1, 10, 11, 95, 100] {
+            let ((end, result), writer, input) = write_all(len, None, 64);
+            assert_eq!(result, Ok(()));
+            assert_eq!(end, len as u64);
+            assert_eq!(writer.data, input);
+            assert!(writer.outstanding.is_empty());
+            assert!(writer.max_outstanding <= 4);
+        }
+
+        // Every request is answered, even after a failure, and the
+        // data is only reported up to the failed request
+        let ((end, result), writer, input) = write_all(100, Some(3), 64);
+        assert_eq!(result, Err(Error::Sftp(SftpError(sys::SSH_FX_FAILURE))));
+        assert!(writer.outstanding.is_empty());
+        assert_eq!(end, 20);
+        assert_eq!(writer.data[..20], input[..20]);
+        assert_eq!(writer.data[30..60], input[30..60]);
+
+        // Requests are made smaller than the chunk size if they
+        // wouldn't fit in a packet
+        let ((end, result), writer, input) = write_all(100, None, 4);
+        assert_eq!(result, Ok(()));
+        assert_eq!(end, 100);
+        assert_eq!(writer.data, input);
+        assert_eq!(writer.next_id, 25);
+    }
+}
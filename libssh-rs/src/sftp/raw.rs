@@ -0,0 +1,152 @@
+use super::SftpError;
+use crate::cert::Reader;
+use crate::{Error, SshResult};
+use libssh_rs_sys as sys;
+use std::collections::HashMap;
+
+/// The largest response that will be accepted
+const MAX_RESPONSE_LEN: usize = 256 * 1024;
+
+/// The largest request that will be sent, following the id, as
+/// OpenSSH drops the connection for packets longer than 256KiB
+pub(crate) const MAX_REQUEST_LEN: usize = 256 * 1024 - 5;
+
+/// Sends requests directly on the sftp channel, for the parts of the
+/// protocol that libssh has no suitable function for.
+/// The session must remain locked while any request is outstanding,
+/// so that libssh doesn't consume the responses.
+pub(crate) struct RawRequests {
+    sftp: sys::sftp_session,
+    channel: sys::ssh_channel,
+    /// Responses that arrived before the one being waited for,
+    /// as servers may answer requests in any order
+    early: HashMap<u32, (u8, Vec<u8>)>,
+}
+
+impl RawRequests {
+    /// # Safety
+    /// `sftp` must be valid, and the session locked
+    pub(crate) unsafe fn new(sftp: sys::sftp_session) -> Self {
+        Self {
+            sftp,
+            channel: (*sftp).channel,
+            early: HashMap::new(),
+        }
+    }
+
+    /// Allocates the id for a new request, in the same way as libssh
+    pub(crate) fn next_id(&mut self) -> u32 {
+        unsafe {
+            (*self.sftp).id_counter = (*self.sftp).id_counter.wrapping_add(1);
+            (*self.sftp).id_counter
+        }
+    }
+
+    /// Sends a request of `packet_type`, whose body following
+    /// the id is the concatenation of `parts`
+    pub(crate) fn send(&mut self, packet_type: u32, id: u32, parts: &[&[u8]]) -> SshResult<()> {
+        let len = parts.iter().map(|part| part.len()).sum::<usize>();
+        if len > MAX_REQUEST_LEN {
+            return Err(Error::Fatal(format!(
+                "sftp request of {} bytes is too long",
+                len
+            )));
+        }
+        let len = 5 + len;
+        let mut header = Vec::with_capacity(9);
+        header.extend_from_slice(&(len as u32).to_be_bytes());
+        header.push(packet_type as u8);
+        header.extend_from_slice(&id.to_be_bytes());
+        self.write_channel(&header)?;
+        for part in parts {
+            self.write_channel(part)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the response to request `id`, returning its
+    /// type and the data that follows the id
+    pub(crate) fn wait(&mut self, id: u32) -> SshResult<(u8, Vec<u8>)> {
+        if let Some(response) = self.early.remove(&id) {
+            return Ok(response);
+        }
+        loop {
+            let (packet_type, reply, body) = self.read_packet()?;
+            if reply == id {
+                return Ok((packet_type, body));
+            }
+            self.early.insert(reply, (packet_type, body));
+        }
+    }
+
+    fn channel_error(&self, what: &str) -> Error {
+        crate::last_error_of(unsafe { (*self.sftp).session } as _)
+            .unwrap_or_else(|| Error::fatal(what))
+    }
+
+    fn write_channel(&self, mut data: &[u8]) -> SshResult<()> {
+        while !data.is_empty() {
+            let res = unsafe {
+                sys::ssh_channel_write(self.channel, data.as_ptr() as _, data.len() as u32)
+            };
+            if res < 0 {
+                return Err(self.channel_error("failed to write to the sftp channel"));
+            }
+            data = &data[res as usize..];
+        }
+        Ok(())
+    }
+
+    fn read_channel(&self, buf: &mut [u8]) -> SshResult<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let remaining = &mut buf[filled..];
+            let res = unsafe {
+                sys::ssh_channel_read(
+                    self.channel,
+                    remaining.as_mut_ptr() as _,
+                    remaining.len() as u32,
+                    0,
+                )
+            };
+            if res < 0 {
+                return Err(self.channel_error("failed to read from the sftp channel"));
+            }
+            if res == 0 && unsafe { sys::ssh_channel_is_eof(self.channel) } != 0 {
+                return Err(Error::fatal("the sftp channel was closed"));
+            }
+            filled += res as usize;
+        }
+        Ok(())
+    }
+
+    /// Reads the next response, returning its type, id and body
+    fn read_packet(&self) -> SshResult<(u8, u32, Vec<u8>)> {
+        let mut len = [0u8; 4];
+        self.read_channel(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if !(5..=MAX_RESPONSE_LEN).contains(&len) {
+            return Err(Error::Fatal(format!("invalid sftp packet length {}", len)));
+        }
+        let mut packet = vec![0u8; len];
+        self.read_channel(&mut packet)?;
+        let id = u32::from_be_bytes([packet[1], packet[2], packet[3], packet[4]]);
+        let packet_type = packet[0];
+        packet.drain(..5);
+        Ok((packet_type, id, packet))
+    }
+}
+
+/// Interprets a response that is expected to be an `SSH_FXP_STATUS`
+pub(crate) fn status_result(packet_type: u8, body: &[u8]) -> SshResult<()> {
+    if packet_type as u32 != sys::SSH_FXP_STATUS {
+        return Err(Error::Fatal(format!(
+            "unexpected sftp packet type {}",
+            packet_type
+        )));
+    }
+    match Reader::new(body).read_u32()? {
+        sys::SSH_FX_OK => Ok(()),
+        status => Err(Error::Sftp(SftpError(status))),
+    }
+}
@@ -0,0 +1,353 @@
+use super::open_flags::{O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY};
+use super::raw::{status_result, RawRequests};
+use super::sync::{local_sha256, modified_secs};
+use super::{PipelineOptions, Sftp};
+use crate::cert::put_string;
+use crate::{Error, SetAttributes, SshResult};
+use libssh_rs_sys as sys;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Options for [Sftp::upload_resumable](struct.Sftp.html#method.upload_resumable)
+/// and [Sftp::download_resumable](struct.Sftp.html#method.download_resumable)
+#[derive(Debug, Clone, Default)]
+pub struct ResumeOptions {
+    /// Compare the SHA-256 checksums of the source and the completed
+    /// copy before putting it in place. The server computes its
+    /// checksum using the `check-file` extension if it supports it,
+    /// or by running `sha256sum`.
+    pub verify: bool,
+    /// Controls the transfer
+    pub pipeline: PipelineOptions,
+}
+
+/// The hidden file in the same directory as the remote `path` that
+/// a resumable upload is written to. Its name includes the length and
+/// modification time of the source, so that an upload only continues
+/// a partial file of the same version of the source.
+fn partial_name(path: &str, source_len: u64, source_modified: u64) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(index) => (&path[..=index], &path[index + 1..]),
+        None => ("", path),
+    };
+    format!(
+        "{}.{}.{}-{}.partial",
+        dir, name, source_len, source_modified
+    )
+}
+
+/// The hidden file in the same directory as the local `path`
+/// that a resumable download is written to
+fn local_partial_path(path: &Path) -> SshResult<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::Fatal(format!("{} has no file name", path.display())))?;
+    let mut name = OsString::from(".");
+    name.push(file_name);
+    name.push(".partial");
+    Ok(path.with_file_name(name))
+}
+
+/// Returns true if a partial copy of `partial_len` bytes, last modified
+/// at `partial_modified`, can be continued from a source that is
+/// `source_len` bytes long and was last modified at `source_modified`.
+///
+/// The source's modification time is recorded on the partial copy once
+/// it holds only contiguous data, so it must match exactly. The
+/// times are compared in whole seconds, which is all that sftp keeps.
+fn is_resumable(
+    source_len: Option<u64>,
+    source_modified: Option<SystemTime>,
+    partial_len: u64,
+    partial_modified: Option<SystemTime>,
+) -> bool {
+    match modified_secs(source_modified) {
+        Some(source) => {
+            source_len.is_some_and(|len| partial_len <= len)
+                && modified_secs(partial_modified) == Some(source)
+        }
+        None => false,
+    }
+}
+
+/// Returns the offset from which an upload can continue into a partial
+/// file of `partial_len` bytes, for a source that is `source_len`
+/// bytes long.
+///
+/// `clean` is whether the previous attempt cut the partial file back
+/// to the data that the server acknowledged contiguously. Otherwise,
+/// as many as `max_requests` writes of `chunk_size` bytes may have been
+/// in flight when it stopped, and any of them may have failed while
+/// later ones succeeded, so that much of the end is discarded.
+fn upload_resume_offset(
+    source_len: u64,
+    partial_len: u64,
+    clean: bool,
+    options: &PipelineOptions,
+) -> u64 {
+    if partial_len > source_len {
+        0
+    } else if clean {
+        partial_len
+    } else {
+        let in_flight = u64::from(options.chunk_size).saturating_mul(options.max_requests as u64);
+        partial_len.saturating_sub(in_flight)
+    }
+}
+
+impl Sftp {
+    /// Renames `from` to `to`, atomically replacing `to` if the server
+    /// supports the `posix-rename@openssh.com` extension
+    fn rename_replacing(&self, from: &str, to: &str) -> SshResult<()> {
+        if self.supports_extension("posix-rename@openssh.com") {
+            let (_sess, sftp) = self.lock_session();
+            let mut raw = unsafe { RawRequests::new(sftp) };
+            let id = raw.next_id();
+            let mut request = vec![];
+            put_string(&mut request, b"posix-rename@openssh.com");
+            put_string(&mut request, from.as_bytes());
+            put_string(&mut request, to.as_bytes());
+            raw.send(sys::SSH_FXP_EXTENDED, id, &[&request])?;
+            let (packet_type, reply) = raw.wait(id)?;
+            return status_result(packet_type, &reply);
+        }
+        // A plain sftp rename fails if the target exists,
+        // so it is briefly missing
+        if self.symlink_metadata(to).is_ok() {
+            self.remove_file(to)?;
+        }
+        self.rename(from, to)
+    }
+
+    /// Uploads the local file `local` to `remote`, continuing from
+    /// where a previous attempt left off.
+    ///
+    /// The data is written to a hidden `.NAME.LEN-MTIME.partial` file
+    /// next to `remote`, named after the length and modification time
+    /// of `local`, with the permissions `mode` if it is created, which
+    /// is renamed over `remote` once it is complete. Partial files left
+    /// behind by other versions of `local` are not continued.
+    ///
+    /// When an attempt fails, the partial file is cut back to the data
+    /// that the server acknowledged contiguously and is given the
+    /// modification time of `local`. If that isn't possible, for example
+    /// because the connection was lost, then the next attempt discards
+    /// the last `max_requests * chunk_size` bytes of the partial file,
+    /// which may contain gaps, and continues from there. This assumes
+    /// that both attempts use the same [PipelineOptions].
+    ///
+    /// Returns the number of bytes that were transferred by this call.
+    pub fn upload_resumable<P: AsRef<Path>>(
+        &self,
+        local: P,
+        remote: &str,
+        mode: sys::mode_t,
+        options: &ResumeOptions,
+    ) -> SshResult<u64> {
+        let local = local.as_ref();
+        let mut source = File::open(local)?;
+        let source_metadata = source.metadata()?;
+        let modified = source_metadata.modified().ok();
+        let source_secs = modified_secs(modified);
+        let partial = partial_name(remote, source_metadata.len(), source_secs.unwrap_or(0));
+
+        let mut file = self.open(&partial, O_WRONLY | O_CREAT, mode)?;
+        let metadata = file.metadata()?;
+        let partial_len = metadata.len().unwrap_or(0);
+        // Without a modification time, the name doesn't identify the
+        // version of the source, so a partial file can't be trusted
+        let offset = match source_secs {
+            Some(secs) => upload_resume_offset(
+                source_metadata.len(),
+                partial_len,
+                modified_secs(metadata.modified()) == Some(secs),
+                &options.pipeline,
+            ),
+            None => 0,
+        };
+        if offset == 0 {
+            file = self.open(&partial, O_WRONLY | O_CREAT | O_TRUNC, mode)?;
+        } else if offset != partial_len {
+            self.set_metadata(
+                &partial,
+                &SetAttributes {
+                    size: Some(offset),
+                    uid_gid: None,
+                    permissions: None,
+                    atime_mtime: None,
+                },
+            )?;
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        source.seek(SeekFrom::Start(offset))?;
+        let (transferred, result) = file.write_pipelined_contiguous(&mut source, &options.pipeline);
+        if let Err(err) = result {
+            drop(file);
+            // Writes that followed a failed one may have succeeded,
+            // so discard them to leave no gaps for the next attempt.
+            // The modification time tells it that this succeeded.
+            let _ = self.set_metadata(
+                &partial,
+                &SetAttributes {
+                    size: Some(offset + transferred),
+                    uid_gid: None,
+                    permissions: None,
+                    atime_mtime: modified.map(|modified| (modified, modified)),
+                },
+            );
+            return Err(err);
+        }
+        let written = file.metadata()?.len();
+        drop(file);
+        if written != Some(offset + transferred) {
+            return Err(Error::Fatal(format!(
+                "{} is {:?} bytes long rather than {}",
+                partial,
+                written,
+                offset + transferred
+            )));
+        }
+
+        if options.verify && local_sha256(local)? != self.remote_sha256(&partial)? {
+            // Discard it, so that the next attempt starts over
+            let _ = self.remove_file(&partial);
+            return Err(Error::Fatal(format!(
+                "the checksum of {} doesn't match {}",
+                partial,
+                local.display()
+            )));
+        }
+        self.rename_replacing(&partial, remote)?;
+        Ok(transferred)
+    }
+
+    /// Downloads the remote file `remote` to `local`, continuing from
+    /// where a previous attempt left off.
+    ///
+    /// The data is written to a hidden `.NAME.partial` file next to
+    /// `local`, which is renamed over `local` once it is complete.
+    /// When an attempt fails, the partial file is given the modification
+    /// time of `remote`. A partial file that is longer than `remote`,
+    /// or doesn't have its modification time, is assumed to be from a
+    /// different version of the file and is started over.
+    ///
+    /// Returns the number of bytes that were transferred by this call.
+    pub fn download_resumable<P: AsRef<Path>>(
+        &self,
+        remote: &str,
+        local: P,
+        options: &ResumeOptions,
+    ) -> SshResult<u64> {
+        let local = local.as_ref();
+        let partial = local_partial_path(local)?;
+        let mut source = self.open(remote, O_RDONLY, 0)?;
+        let source_metadata = source.metadata()?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&partial)?;
+        let metadata = file.metadata()?;
+        let modified = source_metadata.modified();
+        let mut offset = metadata.len();
+        if !is_resumable(
+            source_metadata.len(),
+            modified,
+            offset,
+            metadata.modified().ok(),
+        ) {
+            offset = 0;
+        }
+        file.set_len(offset)?;
+
+        file.seek(SeekFrom::Start(offset))?;
+        source.seek(SeekFrom::Start(offset))?;
+        let transferred = match source.read_pipelined(&mut file, &options.pipeline) {
+            Ok(transferred) => transferred,
+            Err(err) => {
+                // The data is written in order, so the next
+                // attempt can continue from whatever was written
+                if let Some(modified) = modified {
+                    file.set_modified(modified)?;
+                }
+                return Err(err);
+            }
+        };
+        file.sync_all()?;
+        drop(file);
+        drop(source);
+
+        if options.verify && local_sha256(&partial)? != self.remote_sha256(remote)? {
+            // Discard it, so that the next attempt starts over
+            let _ = std::fs::remove_file(&partial);
+            return Err(Error::Fatal(format!(
+                "the checksum of {} doesn't match {}",
+                partial.display(),
+                remote
+            )));
+        }
+        std::fs::rename(&partial, local)?;
+        Ok(transferred)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn partial_files() {
+        assert_eq!(
+            partial_name("/srv/images/app.tar", 1234, 1_000_000),
+            "/srv/images/.app.tar.1234-1000000.partial"
+        );
+        assert_eq!(partial_name("app.tar", 0, 5), ".app.tar.0-5.partial");
+        assert_eq!(
+            local_partial_path(Path::new("downloads/app.tar")).unwrap(),
+            Path::new("downloads/.app.tar.partial")
+        );
+
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let later = then + Duration::from_secs(1);
+        assert!(is_resumable(Some(10), Some(then), 5, Some(then)));
+        assert!(is_resumable(Some(10), Some(then), 10, Some(then)));
+        assert!(is_resumable(
+            Some(10),
+            Some(then + Duration::from_millis(500)),
+            5,
+            Some(then)
+        ));
+        assert!(!is_resumable(Some(10), Some(then), 11, Some(then)));
+        assert!(!is_resumable(Some(10), Some(later), 5, Some(then)));
+        assert!(!is_resumable(Some(10), Some(then), 5, Some(later)));
+        assert!(!is_resumable(Some(10), None, 5, Some(then)));
+        assert!(!is_resumable(None, Some(then), 5, Some(then)));
+    }
+
+    #[test]
+    fn upload_resume_offsets() {
+        let options = PipelineOptions {
+            chunk_size: 1000,
+            max_requests: 4,
+        };
+        assert_eq!(upload_resume_offset(10_000, 6000, true, &options), 6000);
+        assert_eq!(upload_resume_offset(10_000, 10_000, true, &options), 10_000);
+        assert_eq!(upload_resume_offset(10_000, 6000, false, &options), 2000);
+        assert_eq!(upload_resume_offset(10_000, 4000, false, &options), 0);
+        assert_eq!(upload_resume_offset(10_000, 3999, false, &options), 0);
+        assert_eq!(upload_resume_offset(10_000, 0, false, &options), 0);
+        assert_eq!(upload_resume_offset(10_000, 10_001, true, &options), 0);
+
+        let huge = PipelineOptions {
+            chunk_size: u32::MAX,
+            max_requests: usize::MAX,
+        };
+        assert_eq!(upload_resume_offset(u64::MAX, 6000, false, &huge), 0);
+    }
+}
@@ -0,0 +1,605 @@
+use super::raw::{status_result, RawRequests};
+use super::tree::{join, local_subdir, remote_attributes};
+use super::{FileType, Metadata, PipelineOptions, Sftp};
+use crate::cert::{put_string, Reader};
+use crate::scp::local_mode;
+use crate::{Error, Session, SshResult};
+use libssh_rs_sys as sys;
+use openssl_sys as ffi;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// How [Sftp::sync](struct.Sftp.html#method.sync) decides whether a
+/// file that exists both locally and remotely needs to be uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncCompare {
+    /// Upload if the size or the modification time differs.
+    /// Modification times are compared to the second.
+    #[default]
+    SizeAndTime,
+    /// Upload if the size or the SHA-256 checksum differs.
+    /// The server computes its checksum using the `check-file`
+    /// extension if it supports it, or by running `sha256sum`.
+    Checksum,
+}
+
+/// Options for [Sftp::sync](struct.Sftp.html#method.sync)
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub compare: SyncCompare,
+    /// Remove remote files and directories that don't exist locally,
+    /// and remote entries that are in the way of a local entry of
+    /// a different type
+    pub delete: bool,
+    /// Only plan the actions, without changing anything
+    pub dry_run: bool,
+    /// Controls the uploads
+    pub pipeline: PipelineOptions,
+}
+
+/// Why a file is uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncReason {
+    /// The file doesn't exist remotely
+    New,
+    /// The sizes differ
+    SizeChanged,
+    /// The modification times differ
+    Modified,
+    /// The checksums differ
+    ContentChanged,
+}
+
+impl std::fmt::Display for SyncReason {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(match self {
+            Self::New => "new file",
+            Self::SizeChanged => "size changed",
+            Self::Modified => "modified",
+            Self::ContentChanged => "content changed",
+        })
+    }
+}
+
+/// A change made, or planned, by [Sftp::sync](struct.Sftp.html#method.sync)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    /// Create the remote directory
+    CreateDir(String),
+    /// Upload `local` to `remote`, preserving its permissions
+    /// and modification time
+    Upload {
+        local: PathBuf,
+        remote: String,
+        reason: SyncReason,
+    },
+    /// Remove the remote file
+    Remove(String),
+    /// Remove the remote directory and everything in it
+    RemoveDir(String),
+}
+
+impl std::fmt::Display for SyncAction {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::CreateDir(remote) => write!(fmt, "create directory {}", remote),
+            Self::Upload {
+                local,
+                remote,
+                reason,
+            } => write!(fmt, "upload {} to {} ({})", local.display(), remote, reason),
+            Self::Remove(remote) => write!(fmt, "remove {}", remote),
+            Self::RemoveDir(remote) => write!(fmt, "remove directory {}", remote),
+        }
+    }
+}
+
+/// The outcome of [Sftp::sync](struct.Sftp.html#method.sync)
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The actions that were taken, or that would be taken in a dry run
+    pub actions: Vec<SyncAction>,
+    /// The number of bytes that were uploaded
+    pub bytes: u64,
+    /// The remote paths that could not be synchronized, and why
+    pub errors: Vec<(String, Error)>,
+}
+
+impl SyncReport {
+    /// Returns true if there were no errors
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Computes a SHA-256 digest of the data written to it
+struct Sha256(*mut ffi::EVP_MD_CTX);
+
+impl Sha256 {
+    fn new() -> SshResult<Self> {
+        unsafe {
+            let ctx = Self(ffi::EVP_MD_CTX_new());
+            if ctx.0.is_null()
+                || ffi::EVP_DigestInit_ex(ctx.0, ffi::EVP_sha256(), std::ptr::null_mut()) != 1
+            {
+                return Err(Error::fatal("failed to initialize SHA-256"));
+            }
+            Ok(ctx)
+        }
+    }
+
+    fn finish(self) -> SshResult<Vec<u8>> {
+        let mut digest = vec![0u8; 32];
+        let mut len = 0;
+        if unsafe { ffi::EVP_DigestFinal_ex(self.0, digest.as_mut_ptr(), &mut len) } != 1 {
+            return Err(Error::fatal("failed to compute SHA-256"));
+        }
+        digest.truncate(len as usize);
+        Ok(digest)
+    }
+}
+
+impl std::io::Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if unsafe { ffi::EVP_DigestUpdate(self.0, buf.as_ptr() as _, buf.len()) } != 1 {
+            return Err(std::io::Error::other("failed to compute SHA-256"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_MD_CTX_free(self.0) }
+    }
+}
+
+pub(crate) fn local_sha256(path: &Path) -> SshResult<Vec<u8>> {
+    let mut hasher = Sha256::new()?;
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    hasher.finish()
+}
+
+/// Quotes `arg` for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Extracts the digest from the output of `sha256sum`
+fn parse_sha256sum(output: &str) -> Option<Vec<u8>> {
+    // GNU sha256sum prefixes the line with a backslash when
+    // it has to escape characters in the file name
+    let output = output.strip_prefix('\\').unwrap_or(output);
+    let hex = output.get(..64)?;
+    (0..64)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn modified_secs(time: Option<SystemTime>) -> Option<u64> {
+    time?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+impl Sftp {
+    /// Returns true if the server advertised the extension `name`
+    pub(crate) fn supports_extension(&self, name: &str) -> bool {
+        let (_sess, sftp) = self.lock_session();
+        let count = unsafe { sys::sftp_extensions_get_count(sftp) };
+        (0..count).any(|index| {
+            let ext = unsafe { sys::sftp_extensions_get_name(sftp, index) };
+            !ext.is_null() && unsafe { CStr::from_ptr(ext) }.to_bytes() == name.as_bytes()
+        })
+    }
+
+    /// Asks the server for the SHA-256 checksum of `path`
+    /// using the `check-file-name` extended request.
+    /// Returns None if the server used a different algorithm,
+    /// which it is free to do.
+    fn check_file_sha256(&self, path: &str) -> SshResult<Option<Vec<u8>>> {
+        let (_sess, sftp) = self.lock_session();
+        let mut raw = unsafe { RawRequests::new(sftp) };
+        let id = raw.next_id();
+        let mut request = vec![];
+        put_string(&mut request, b"check-file-name");
+        put_string(&mut request, path.as_bytes());
+        put_string(&mut request, b"sha256");
+        // Hash the whole file as a single block
+        request.extend_from_slice(&0u64.to_be_bytes());
+        request.extend_from_slice(&0u64.to_be_bytes());
+        request.extend_from_slice(&0u32.to_be_bytes());
+        raw.send(sys::SSH_FXP_EXTENDED, id, &[&request])?;
+
+        let (packet_type, reply) = raw.wait(id)?;
+        if packet_type as u32 != sys::SSH_FXP_EXTENDED_REPLY {
+            status_result(packet_type, &reply)?;
+            return Err(Error::fatal("check-file returned no checksum"));
+        }
+        let mut reader = Reader::new(&reply);
+        reader.read_str()?;
+        let algorithm = reader.read_str()?;
+        if algorithm != "sha256" {
+            return Ok(None);
+        }
+        if reader.remaining() != 32 {
+            return Err(Error::Fatal(format!(
+                "check-file returned a {} byte sha256 checksum",
+                reader.remaining()
+            )));
+        }
+        Ok(Some(reply[reply.len() - 32..].to_vec()))
+    }
+
+    /// Computes the SHA-256 checksum of the remote file `path`,
+    /// using the `check-file` extension if the server supports it
+    /// with that algorithm, or by running `sha256sum`
+    pub(crate) fn remote_sha256(&self, path: &str) -> SshResult<Vec<u8>> {
+        if self.supports_extension("check-file") {
+            if let Some(digest) = self.check_file_sha256(path)? {
+                return Ok(digest);
+            }
+        }
+        self.command_sha256(path)
+    }
+
+    /// Computes the SHA-256 checksum of `path` by running `sha256sum`
+    /// in a new channel; relative paths are resolved against the
+    /// directory that the command starts in
+    fn command_sha256(&self, path: &str) -> SshResult<Vec<u8>> {
+        let session = Session {
+            sess: Arc::clone(&self.sess),
+        };
+        let channel = session.new_channel()?;
+        channel.open_session()?;
+        channel.request_exec(&format!("sha256sum -b -- {}", shell_quote(path)))?;
+        let mut output = String::new();
+        channel.stdout().read_to_string(&mut output)?;
+        let _ = channel.close();
+        parse_sha256sum(&output)
+            .ok_or_else(|| Error::Fatal(format!("failed to checksum {} using sha256sum", path)))
+    }
+
+    /// Makes the remote directory `remote_dir` a copy of the local
+    /// directory `local_dir`, uploading only the files that are new
+    /// or have changed. Symlinks are followed, and anything other than
+    /// regular files and directories is skipped. Symlinks that lead
+    /// back to a directory being synchronized are reported as errors.
+    ///
+    /// Returns an error if either directory can't be read or
+    /// `remote_dir` can't be created; failures to synchronize
+    /// individual files are collected in the report.
+    pub fn sync<P: AsRef<Path>>(
+        &self,
+        local_dir: P,
+        remote_dir: &str,
+        options: &SyncOptions,
+    ) -> SshResult<SyncReport> {
+        let local_dir = local_dir.as_ref();
+        let metadata = std::fs::metadata(local_dir)?;
+        if !metadata.is_dir() {
+            return Err(Error::Fatal(format!(
+                "{} is not a directory",
+                local_dir.display()
+            )));
+        }
+
+        let mut syncer = Syncer {
+            sftp: self,
+            options,
+            report: SyncReport::default(),
+        };
+        let remote_exists = match self.metadata(remote_dir) {
+            Ok(remote) if remote.file_type() == Some(FileType::Directory) => true,
+            Ok(_) => return Err(Error::Fatal(format!("{} is not a directory", remote_dir))),
+            Err(_) => {
+                if !options.dry_run {
+                    self.create_dir_all(remote_dir, local_mode(&metadata))?;
+                }
+                syncer
+                    .report
+                    .actions
+                    .push(SyncAction::CreateDir(remote_dir.to_string()));
+                false
+            }
+        };
+        let remote = if remote_exists {
+            syncer.read_remote_dir(remote_dir)?
+        } else {
+            HashMap::new()
+        };
+        let mut ancestors = vec![std::fs::canonicalize(local_dir)?];
+        syncer.sync_dir(local_dir, remote_dir, remote, &mut ancestors)?;
+        Ok(syncer.report)
+    }
+}
+
+struct Syncer<'a> {
+    sftp: &'a Sftp,
+    options: &'a SyncOptions,
+    report: SyncReport,
+}
+
+impl<'a> Syncer<'a> {
+    /// Lists a remote directory, resolving symlinks
+    fn read_remote_dir(&self, remote: &str) -> SshResult<HashMap<String, Option<Metadata>>> {
+        let mut entries = HashMap::new();
+        for metadata in self.sftp.read_dir(remote)? {
+            let name = match metadata.name() {
+                Some("." | "..") | None => continue,
+                Some(name) => name.to_string(),
+            };
+            let metadata = if metadata.file_type() == Some(FileType::Symlink) {
+                // A dangling link is neither a file nor a directory
+                self.sftp.metadata(&join(remote, &name)).ok()
+            } else {
+                Some(metadata)
+            };
+            entries.insert(name, metadata);
+        }
+        Ok(entries)
+    }
+
+    /// Performs `action` unless this is a dry run, and records it
+    /// or the error. Returns true if it succeeded.
+    fn perform(&mut self, action: SyncAction, local: Option<&std::fs::Metadata>) -> bool {
+        if !self.options.dry_run {
+            let (path, result) = match &action {
+                SyncAction::CreateDir(remote) => {
+                    let mode = local.map_or(0o755, local_mode);
+                    (remote, self.sftp.create_dir(remote, mode))
+                }
+                SyncAction::Upload {
+                    local: path,
+                    remote,
+                    ..
+                } => {
+                    let result = File::open(path)
+                        .map_err(Error::from)
+                        .and_then(|mut file| {
+                            self.sftp
+                                .upload(remote, &mut file, 0o600, &self.options.pipeline)
+                        })
+                        .and_then(|len| {
+                            if let Some(local) = local {
+                                self.sftp.set_metadata(remote, &remote_attributes(local))?;
+                            }
+                            self.report.bytes += len;
+                            Ok(())
+                        });
+                    (remote, result)
+                }
+                SyncAction::Remove(remote) => (remote, self.sftp.remove_file(remote)),
+                SyncAction::RemoveDir(remote) => match self.sftp.remove_dir_all(remote) {
+                    Ok(report) if report.is_ok() => (remote, Ok(())),
+                    Ok(report) => {
+                        self.report.errors.extend(report.errors);
+                        return false;
+                    }
+                    Err(err) => (remote, Err(err)),
+                },
+            };
+            if let Err(err) = result {
+                self.report.errors.push((path.clone(), err));
+                return false;
+            }
+        }
+        self.report.actions.push(action);
+        true
+    }
+
+    /// Removes a remote entry that is in the way of a local entry
+    /// of a different type, if deletion is enabled
+    fn remove_conflict(&mut self, remote: &str, metadata: &Option<Metadata>) -> bool {
+        if !self.options.delete {
+            self.report.errors.push((
+                remote.to_string(),
+                Error::fatal("a remote entry of a different type is in the way"),
+            ));
+            return false;
+        }
+        let action = self.removal(remote, metadata);
+        self.perform(action, None)
+    }
+
+    /// Returns the action that removes the remote entry `remote`
+    fn removal(&self, remote: &str, metadata: &Option<Metadata>) -> SyncAction {
+        let is_dir = metadata.as_ref().and_then(|metadata| metadata.file_type())
+            == Some(FileType::Directory);
+        // Symlinks were resolved, so check that this isn't a link to a
+        // directory, which is removed like a file
+        let is_link = || {
+            self.sftp
+                .symlink_metadata(remote)
+                .map(|metadata| metadata.file_type() == Some(FileType::Symlink))
+                .unwrap_or(false)
+        };
+        if is_dir && !is_link() {
+            SyncAction::RemoveDir(remote.to_string())
+        } else {
+            SyncAction::Remove(remote.to_string())
+        }
+    }
+
+    /// Returns why `local` needs to be uploaded over `remote`, if it does
+    fn compare(
+        &self,
+        local: &Path,
+        local_metadata: &std::fs::Metadata,
+        remote: &str,
+        remote_metadata: &Metadata,
+    ) -> SshResult<Option<SyncReason>> {
+        if remote_metadata.len() != Some(local_metadata.len()) {
+            return Ok(Some(SyncReason::SizeChanged));
+        }
+        Ok(match self.options.compare {
+            SyncCompare::SizeAndTime => {
+                if modified_secs(local_metadata.modified().ok())
+                    != modified_secs(remote_metadata.modified())
+                {
+                    Some(SyncReason::Modified)
+                } else {
+                    None
+                }
+            }
+            SyncCompare::Checksum => {
+                if local_sha256(local)? != self.sftp.remote_sha256(remote)? {
+                    Some(SyncReason::ContentChanged)
+                } else {
+                    None
+                }
+            }
+        })
+    }
+
+    fn sync_dir(
+        &mut self,
+        local: &Path,
+        remote: &str,
+        mut remote_entries: HashMap<String, Option<Metadata>>,
+        // The canonical paths of the directories being synchronized,
+        // used to detect loops through symlinks
+        ancestors: &mut Vec<PathBuf>,
+    ) -> SshResult<()> {
+        let mut local_entries = std::fs::read_dir(local)?.collect::<Result<Vec<_>, _>>()?;
+        local_entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in local_entries {
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => {
+                    self.report.errors.push((
+                        remote.to_string(),
+                        Error::Fatal(format!("{} has a non-UTF-8 name", entry.path().display())),
+                    ));
+                    continue;
+                }
+            };
+            let local_path = entry.path();
+            let remote_path = join(remote, name);
+            let local_metadata = match std::fs::metadata(&local_path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.report.errors.push((remote_path, err.into()));
+                    continue;
+                }
+            };
+            let existing = remote_entries.remove(name);
+            let existing_type = existing
+                .as_ref()
+                .and_then(|metadata| metadata.as_ref())
+                .and_then(|metadata| metadata.file_type());
+
+            if local_metadata.is_dir() {
+                let canonical = match local_subdir(&local_path, ancestors) {
+                    Ok(canonical) => canonical,
+                    Err(err) => {
+                        self.report.errors.push((remote_path, err));
+                        continue;
+                    }
+                };
+                let exists = existing_type == Some(FileType::Directory);
+                if !exists {
+                    if let Some(existing) = &existing {
+                        if !self.remove_conflict(&remote_path, existing) {
+                            continue;
+                        }
+                    }
+                    let action = SyncAction::CreateDir(remote_path.clone());
+                    if !self.perform(action, Some(&local_metadata)) {
+                        continue;
+                    }
+                }
+                let children = if exists {
+                    match self.read_remote_dir(&remote_path) {
+                        Ok(children) => children,
+                        Err(err) => {
+                            self.report.errors.push((remote_path, err));
+                            continue;
+                        }
+                    }
+                } else {
+                    HashMap::new()
+                };
+                ancestors.push(canonical);
+                let result = self.sync_dir(&local_path, &remote_path, children, ancestors);
+                ancestors.pop();
+                if let Err(err) = result {
+                    self.report.errors.push((remote_path, err));
+                }
+            } else if local_metadata.is_file() {
+                let reason = match (&existing, existing_type) {
+                    (None, _) => SyncReason::New,
+                    (Some(Some(remote_metadata)), Some(FileType::Regular)) => {
+                        match self.compare(
+                            &local_path,
+                            &local_metadata,
+                            &remote_path,
+                            remote_metadata,
+                        ) {
+                            Ok(Some(reason)) => reason,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                self.report.errors.push((remote_path, err));
+                                continue;
+                            }
+                        }
+                    }
+                    (Some(existing), _) => {
+                        if !self.remove_conflict(&remote_path, existing) {
+                            continue;
+                        }
+                        SyncReason::New
+                    }
+                };
+                let action = SyncAction::Upload {
+                    local: local_path,
+                    remote: remote_path,
+                    reason,
+                };
+                self.perform(action, Some(&local_metadata));
+            }
+        }
+
+        if self.options.delete {
+            let mut extraneous: Vec<_> = remote_entries.into_iter().collect();
+            extraneous.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, metadata) in extraneous {
+                let action = self.removal(&join(remote, &name), &metadata);
+                self.perform(action, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        let output = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *empty\n";
+        let digest = parse_sha256sum(output).unwrap();
+        assert_eq!(digest.len(), 32);
+        assert_eq!(&digest[..4], &[0xe3, 0xb0, 0xc4, 0x42]);
+        assert_eq!(Sha256::new().unwrap().finish().unwrap(), digest);
+        assert!(parse_sha256sum("sha256sum: missing: No such file or directory\n").is_none());
+        let escaped = format!("\\{}", output.replace("empty", "new\\nline"));
+        assert_eq!(parse_sha256sum(&escaped).unwrap(), digest);
+
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
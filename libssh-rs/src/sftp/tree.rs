@@ -0,0 +1,651 @@
+use super::{FileType, Metadata, PipelineOptions, SetAttributes, Sftp, SftpDir};
+use crate::scp::{local_mode, set_local_mode};
+use crate::{Error, SshResult};
+use libssh_rs_sys as sys;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// How [Sftp::walk_dir](struct.Sftp.html#method.walk_dir) treats
+/// symbolic links
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Yield the link itself, without following it
+    #[default]
+    Report,
+    /// Yield the target of the link, descending into it if it is a
+    /// directory. Links that would revisit a directory that is
+    /// being walked are reported as errors.
+    Follow,
+    /// Omit links entirely
+    Skip,
+}
+
+/// An entry found by [Sftp::walk_dir](struct.Sftp.html#method.walk_dir)
+pub struct WalkEntry {
+    path: String,
+    depth: usize,
+    metadata: Metadata,
+    symlink: bool,
+}
+
+impl WalkEntry {
+    /// The path of the entry, starting with the path that was walked
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The final component of the path
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    /// How far below the walked directory the entry is;
+    /// the entries that it contains have a depth of 1
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The metadata of the entry, which describes the target of
+    /// a symlink when following symlinks
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn file_type(&self) -> Option<FileType> {
+        self.metadata.file_type()
+    }
+
+    /// Returns true if the entry is a symlink, even when it has been followed
+    pub fn path_is_symlink(&self) -> bool {
+        self.symlink
+    }
+}
+
+type EntryFilter<'a> = Box<dyn FnMut(&WalkEntry) -> bool + 'a>;
+
+struct WalkLevel {
+    dir: SftpDir,
+    path: String,
+    depth: usize,
+    /// The canonical path, used to detect loops when following symlinks
+    canonical: Option<String>,
+}
+
+/// A lazy, depth-first iterator over the entries beneath a directory.
+/// Directories are yielded before their contents, and only one
+/// directory handle is open for each level of the walk.
+pub struct WalkDir<'a> {
+    sftp: &'a Sftp,
+    stack: Vec<WalkLevel>,
+    /// The directory to open on the next call, and its depth
+    pending: Option<(String, usize)>,
+    max_depth: Option<usize>,
+    symlinks: SymlinkPolicy,
+    filter: Option<EntryFilter<'a>>,
+    /// The path that the most recent error relates to
+    error_path: String,
+}
+
+impl<'a> WalkDir<'a> {
+    /// Don't yield entries deeper than `depth`; a depth of 1 yields
+    /// the same entries as `read_dir`
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Sets how symlinks are treated; the default is `SymlinkPolicy::Report`
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Only yield the entries for which `filter` returns true.
+    /// Directories that are rejected are not descended into.
+    pub fn filter_entry<F: FnMut(&WalkEntry) -> bool + 'a>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Returns the path that the most recent error yielded by the
+    /// iterator relates to, which is otherwise not part of the error
+    pub fn error_path(&self) -> &str {
+        &self.error_path
+    }
+
+    fn fail<T>(&mut self, path: &str, err: Error) -> Option<SshResult<T>> {
+        self.error_path = path.to_string();
+        Some(Err(err))
+    }
+
+    fn open_pending(&mut self, path: String, depth: usize) -> SshResult<()> {
+        let canonical = match self.symlinks {
+            SymlinkPolicy::Follow => {
+                let canonical = self.sftp.canonicalize(&path)?;
+                if self
+                    .stack
+                    .iter()
+                    .any(|level| level.canonical.as_ref() == Some(&canonical))
+                {
+                    return Err(Error::Fatal(format!(
+                        "{} leads back to {}, which is already being walked",
+                        path, canonical
+                    )));
+                }
+                Some(canonical)
+            }
+            _ => None,
+        };
+        let dir = self.sftp.open_dir(&path)?;
+        self.stack.push(WalkLevel {
+            dir,
+            path,
+            depth,
+            canonical,
+        });
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for WalkDir<'a> {
+    type Item = SshResult<WalkEntry>;
+
+    fn next(&mut self) -> Option<SshResult<WalkEntry>> {
+        if let Some((path, depth)) = self.pending.take() {
+            if let Err(err) = self.open_pending(path.clone(), depth) {
+                return self.fail(&path, err);
+            }
+        }
+
+        loop {
+            let level = self.stack.last()?;
+            let metadata = match level.dir.read_dir() {
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+                Some(Err(err)) => {
+                    // Stop reading a directory that failed, rather
+                    // than risk failing the same way forever
+                    let level = self.stack.pop()?;
+                    return self.fail(&level.path, err);
+                }
+                Some(Ok(metadata)) => metadata,
+            };
+            let name = match metadata.name() {
+                Some("." | "..") | None => continue,
+                Some(name) => name,
+            };
+            let path = join(&level.path, name);
+            let depth = level.depth + 1;
+
+            let symlink = metadata.file_type() == Some(FileType::Symlink);
+            let metadata = match (symlink, self.symlinks) {
+                (true, SymlinkPolicy::Skip) => continue,
+                (true, SymlinkPolicy::Follow) => match self.sftp.metadata(&path) {
+                    Ok(target) => target,
+                    Err(err) => return self.fail(&path, err),
+                },
+                _ => metadata,
+            };
+
+            let entry = WalkEntry {
+                path,
+                depth,
+                metadata,
+                symlink,
+            };
+            if let Some(filter) = self.filter.as_mut() {
+                if !filter(&entry) {
+                    continue;
+                }
+            }
+            if entry.file_type() == Some(FileType::Directory)
+                && self.max_depth.is_none_or(|max| depth < max)
+            {
+                self.pending = Some((entry.path.clone(), depth));
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+/// The outcome of a recursive operation, which carries on past
+/// errors that affect individual files
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    /// The number of files that were copied or removed
+    pub files: usize,
+    /// The number of directories that were created or removed
+    pub directories: usize,
+    /// The number of bytes that were copied
+    pub bytes: u64,
+    /// The remote paths that could not be processed, and why
+    pub errors: Vec<(String, Error)>,
+}
+
+impl TreeReport {
+    /// Returns true if there were no errors
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Joins a remote directory path and a name
+pub(crate) fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Returns the parent of a remote path, or None for
+/// the root or a single relative component
+fn parent(path: &str) -> Option<&str> {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(0) if path.len() > 1 => Some("/"),
+        Some(0) | None => None,
+        Some(index) => Some(&path[..index]),
+    }
+}
+
+impl Sftp {
+    /// Returns a lazy iterator over the entries beneath the
+    /// directory `path`, descending into subdirectories
+    pub fn walk_dir(&self, path: &str) -> WalkDir<'_> {
+        WalkDir {
+            sftp: self,
+            stack: vec![],
+            pending: Some((path.to_string(), 0)),
+            max_depth: None,
+            symlinks: SymlinkPolicy::default(),
+            filter: None,
+            error_path: String::new(),
+        }
+    }
+
+    /// Creates the directory `path`, and any of its parents that
+    /// don't exist, with the permissions `mode`.
+    /// Succeeds if the directory already exists.
+    pub fn create_dir_all(&self, path: &str, mode: sys::mode_t) -> SshResult<()> {
+        if let Ok(metadata) = self.metadata(path) {
+            return match metadata.file_type() {
+                Some(FileType::Directory) => Ok(()),
+                _ => Err(Error::Fatal(format!(
+                    "{} exists and is not a directory",
+                    path
+                ))),
+            };
+        }
+        if let Some(parent) = parent(path) {
+            self.create_dir_all(parent, mode)?;
+        }
+        match self.create_dir(path, mode) {
+            Ok(()) => Ok(()),
+            // Another client may have created it in the meantime
+            Err(err) => match self.metadata(path) {
+                Ok(metadata) if metadata.file_type() == Some(FileType::Directory) => Ok(()),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Removes the directory `path` and everything in it.
+    /// Symlinks are removed rather than followed.
+    /// Returns an error if `path` is not a directory; failures to
+    /// remove its contents are collected in the report.
+    pub fn remove_dir_all(&self, path: &str) -> SshResult<TreeReport> {
+        let metadata = self.symlink_metadata(path)?;
+        if metadata.file_type() != Some(FileType::Directory) {
+            return Err(Error::Fatal(format!("{} is not a directory", path)));
+        }
+
+        let mut report = TreeReport::default();
+        let mut dirs = vec![];
+        let mut walk = self.walk_dir(path);
+        while let Some(entry) = walk.next() {
+            match entry {
+                Ok(entry) if entry.file_type() == Some(FileType::Directory) => {
+                    dirs.push(entry.path)
+                }
+                Ok(entry) => match self.remove_file(&entry.path) {
+                    Ok(()) => report.files += 1,
+                    Err(err) => report.errors.push((entry.path, err)),
+                },
+                Err(err) => report.errors.push((walk.error_path().to_string(), err)),
+            }
+        }
+
+        // Directories are found before their contents,
+        // so must be removed in the reverse order
+        dirs.push(path.to_string());
+        for dir in dirs.into_iter().rev() {
+            match self.remove_dir(&dir) {
+                Ok(()) => report.directories += 1,
+                Err(err) => report.errors.push((dir, err)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Copies the contents of the local directory `local` into the
+    /// remote directory `remote`, which is created if necessary.
+    /// Permissions and modification times are preserved.
+    /// Symlinks are followed, except those that lead back to a
+    /// directory that is being copied, which are reported as errors.
+    /// Anything other than regular files and directories is skipped.
+    ///
+    /// Returns an error if `remote` can't be created; failures to
+    /// copy individual files are collected in the report.
+    pub fn upload_tree<P: AsRef<Path>>(
+        &self,
+        local: P,
+        remote: &str,
+        options: &PipelineOptions,
+    ) -> SshResult<TreeReport> {
+        let local = local.as_ref();
+        let metadata = std::fs::metadata(local)?;
+        self.create_dir_all(remote, 0o700)?;
+
+        let mut report = TreeReport::default();
+        // Directory attributes are applied once their contents are
+        // written, as doing that would change their modification time
+        let mut dirs = vec![(remote.to_string(), metadata)];
+        let mut ancestors = vec![std::fs::canonicalize(local)?];
+        self.upload_dir_contents(
+            local,
+            remote,
+            options,
+            &mut report,
+            &mut dirs,
+            &mut ancestors,
+        );
+
+        for (path, metadata) in dirs.into_iter().rev() {
+            if let Err(err) = self.set_metadata(&path, &remote_attributes(&metadata)) {
+                report.errors.push((path, err));
+            }
+        }
+        Ok(report)
+    }
+
+    fn upload_dir_contents(
+        &self,
+        local: &Path,
+        remote: &str,
+        options: &PipelineOptions,
+        report: &mut TreeReport,
+        dirs: &mut Vec<(String, std::fs::Metadata)>,
+        // The canonical paths of the directories being copied,
+        // used to detect loops through symlinks
+        ancestors: &mut Vec<PathBuf>,
+    ) {
+        let entries = match std::fs::read_dir(local) {
+            Ok(entries) => entries,
+            Err(err) => {
+                report.errors.push((remote.to_string(), err.into()));
+                return;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    report.errors.push((remote.to_string(), err.into()));
+                    continue;
+                }
+            };
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => {
+                    report.errors.push((
+                        remote.to_string(),
+                        Error::Fatal(format!("{} has a non-UTF-8 name", entry.path().display())),
+                    ));
+                    continue;
+                }
+            };
+            let path = join(remote, name);
+            let metadata = match std::fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    report.errors.push((path, err.into()));
+                    continue;
+                }
+            };
+
+            if metadata.is_dir() {
+                let canonical = match local_subdir(&entry.path(), ancestors) {
+                    Ok(canonical) => canonical,
+                    Err(err) => {
+                        report.errors.push((path, err));
+                        continue;
+                    }
+                };
+                if let Err(err) = self.create_dir_all(&path, 0o700) {
+                    report.errors.push((path, err));
+                    continue;
+                }
+                report.directories += 1;
+                ancestors.push(canonical);
+                self.upload_dir_contents(&entry.path(), &path, options, report, dirs, ancestors);
+                ancestors.pop();
+                dirs.push((path, metadata));
+            } else if metadata.is_file() {
+                let result = File::open(entry.path())
+                    .map_err(Error::from)
+                    .and_then(|mut file| self.upload(&path, &mut file, 0o600, options))
+                    .and_then(|len| {
+                        self.set_metadata(&path, &remote_attributes(&metadata))?;
+                        Ok(len)
+                    });
+                match result {
+                    Ok(len) => {
+                        report.files += 1;
+                        report.bytes += len;
+                    }
+                    Err(err) => report.errors.push((path, err)),
+                }
+            }
+        }
+    }
+
+    /// Copies the contents of the remote directory `remote` into the
+    /// local directory `local`, which is created if necessary.
+    /// Permissions and modification times are preserved, although
+    /// directory modification times only on unix systems.
+    /// Symlinks are followed, and anything other than regular files
+    /// and directories is skipped.
+    ///
+    /// Returns an error if `local` can't be created; failures to
+    /// copy individual files are collected in the report.
+    pub fn download_tree<P: AsRef<Path>>(
+        &self,
+        remote: &str,
+        local: P,
+        options: &PipelineOptions,
+    ) -> SshResult<TreeReport> {
+        let root = self.metadata(remote)?;
+        let local = local.as_ref();
+        std::fs::create_dir_all(local)?;
+
+        let mut report = TreeReport::default();
+        // The local directory for each depth of the walk
+        let mut parents = vec![local.to_path_buf()];
+        // Directory attributes are applied last, in case they are read-only
+        let mut dirs = vec![(local.to_path_buf(), remote.to_string(), root)];
+
+        let mut walk = self.walk_dir(remote).symlinks(SymlinkPolicy::Follow);
+        while let Some(entry) = walk.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    report.errors.push((walk.error_path().to_string(), err));
+                    continue;
+                }
+            };
+            parents.truncate(entry.depth);
+            let is_dir = entry.file_type() == Some(FileType::Directory);
+            let parent = &parents[entry.depth - 1];
+            if parent.as_os_str().is_empty() {
+                // Beneath a directory that could not be created
+                if is_dir {
+                    parents.push(PathBuf::new());
+                }
+                continue;
+            }
+            let name = entry.file_name();
+            if name.is_empty() || name == "." || name == ".." || name.contains('\\') {
+                report.errors.push((
+                    entry.path.clone(),
+                    Error::Fatal(format!("invalid name {:?}", name)),
+                ));
+                if is_dir {
+                    parents.push(PathBuf::new());
+                }
+                continue;
+            }
+            let path = parent.join(name);
+
+            match entry.file_type() {
+                Some(FileType::Directory) => {
+                    if !path.is_dir() {
+                        if let Err(err) = std::fs::create_dir(&path) {
+                            report.errors.push((entry.path, err.into()));
+                            parents.push(PathBuf::new());
+                            continue;
+                        }
+                    }
+                    report.directories += 1;
+                    parents.push(path.clone());
+                    dirs.push((path, entry.path, entry.metadata));
+                }
+                Some(FileType::Regular) => {
+                    match self.download_file(&entry.path, &path, &entry.metadata, options) {
+                        Ok(len) => {
+                            report.files += 1;
+                            report.bytes += len;
+                        }
+                        Err(err) => report.errors.push((entry.path, err)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (path, remote, metadata) in dirs.into_iter().rev() {
+            if let Err(err) = set_local_dir_attributes(&path, &metadata) {
+                report.errors.push((remote, err.into()));
+            }
+        }
+        Ok(report)
+    }
+
+    fn download_file(
+        &self,
+        remote: &str,
+        local: &Path,
+        metadata: &Metadata,
+        options: &PipelineOptions,
+    ) -> SshResult<u64> {
+        let mut file = File::create(local)?;
+        let len = self.download(remote, &mut file, options)?;
+        if let Some(modified) = metadata.modified() {
+            let times = std::fs::FileTimes::new()
+                .set_modified(modified)
+                .set_accessed(metadata.accessed().unwrap_or(modified));
+            file.set_times(times)?;
+        }
+        drop(file);
+        if let Some(permissions) = metadata.permissions() {
+            set_local_mode(local, permissions & 0o7777)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Returns the canonical path of the local directory `path`, unless it
+/// is one of `ancestors`, the canonical paths of the directories being
+/// copied, as it would then contain itself through a symlink
+pub(crate) fn local_subdir(path: &Path, ancestors: &[PathBuf]) -> SshResult<PathBuf> {
+    let canonical = std::fs::canonicalize(path)?;
+    if ancestors.contains(&canonical) {
+        return Err(Error::Fatal(format!(
+            "{} leads back to {}, which is already being copied",
+            path.display(),
+            canonical.display()
+        )));
+    }
+    Ok(canonical)
+}
+
+/// The attributes that preserve the permissions and times of a local file
+pub(crate) fn remote_attributes(metadata: &std::fs::Metadata) -> SetAttributes {
+    let modified = metadata.modified().ok();
+    let accessed = metadata.accessed().ok().or(modified);
+    SetAttributes {
+        size: None,
+        uid_gid: None,
+        permissions: Some(local_mode(metadata)),
+        atime_mtime: accessed.zip(modified),
+    }
+}
+
+fn set_local_dir_attributes(path: &Path, metadata: &Metadata) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        if let Some(modified) = metadata.modified() {
+            let times = std::fs::FileTimes::new()
+                .set_modified(modified)
+                .set_accessed(metadata.accessed().unwrap_or(modified));
+            File::open(path)?.set_times(times)?;
+        }
+    }
+    match metadata.permissions() {
+        Some(permissions) => set_local_mode(path, permissions & 0o7777),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_paths() {
+        assert_eq!(join("/home/user", "file"), "/home/user/file");
+        assert_eq!(join("/", "etc"), "/etc");
+        assert_eq!(join("relative/", "file"), "relative/file");
+
+        assert_eq!(parent("/home/user/dir"), Some("/home/user"));
+        assert_eq!(parent("/home/user/dir/"), Some("/home/user"));
+        assert_eq!(parent("/home"), Some("/"));
+        assert_eq!(parent("/"), None);
+        assert_eq!(parent("relative/dir"), Some("relative"));
+        assert_eq!(parent("dir"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_symlink_loops() {
+        let dir = std::env::temp_dir().join(format!("libssh-rs-loop-{}", std::process::id()));
+        let sub = dir.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::os::unix::fs::symlink("..", sub.join("loop")).unwrap();
+        std::os::unix::fs::symlink(".", sub.join("self")).unwrap();
+
+        let mut ancestors = vec![std::fs::canonicalize(&dir).unwrap()];
+        let canonical = local_subdir(&sub, &ancestors).unwrap();
+        assert_eq!(canonical, ancestors[0].join("sub"));
+        ancestors.push(canonical);
+        assert!(local_subdir(&sub.join("loop"), &ancestors).is_err());
+        assert!(local_subdir(&sub.join("self"), &ancestors).is_err());
+        ancestors.pop();
+        assert!(local_subdir(&sub.join("self"), &ancestors).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
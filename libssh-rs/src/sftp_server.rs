@@ -0,0 +1,816 @@
+use crate::{Channel, Error, FileType, SetAttributes, SshResult};
+use libssh_rs_sys as sys;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::{CStr, CString};
+use std::io::ErrorKind;
+use std::os::raw::{c_char, c_int};
+use std::time::{Duration, SystemTime};
+
+mod local;
+mod memory;
+
+pub use local::LocalFilesystem;
+pub use memory::{MemoryDir, MemoryFile, MemoryFilesystem};
+
+/// The largest amount of data that we will return for a single
+/// read request, regardless of how much the client asked for.
+const MAX_READ_LEN: usize = 256 * 1024;
+
+bitflags::bitflags! {
+    /// The flags that a client passes when opening a file
+    pub struct OpenFlags : u32 {
+        /// Open the file for reading
+        const READ = sys::SSH_FXF_READ;
+        /// Open the file for writing
+        const WRITE = sys::SSH_FXF_WRITE;
+        /// All writes are to be appended to the end of the file
+        const APPEND = sys::SSH_FXF_APPEND;
+        /// Create the file if it doesn't already exist
+        const CREATE = sys::SSH_FXF_CREAT;
+        /// Truncate an existing file to zero length
+        const TRUNCATE = sys::SSH_FXF_TRUNC;
+        /// Used together with `CREATE`; fail if the file already exists
+        const EXCLUSIVE = sys::SSH_FXF_EXCL;
+    }
+}
+
+/// Describes a file that is exposed by an `SftpFilesystem`.
+/// Fields that are `None` are not reported to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub file_type: FileType,
+    pub size: Option<u64>,
+    pub uid_gid: Option<(u32, u32)>,
+    /// The permission bits, such as `0o644`.
+    /// The file type bits are derived from `file_type` and
+    /// need not be included here.
+    pub permissions: Option<u32>,
+    /// Note that the protocol has 1-second granularity for
+    /// access and mtime
+    pub atime_mtime: Option<(SystemTime, SystemTime)>,
+}
+
+impl FileAttributes {
+    /// Create attributes for a file of the specified type, with
+    /// all of the other fields left unspecified
+    pub fn new(file_type: FileType) -> Self {
+        Self {
+            file_type,
+            size: None,
+            uid_gid: None,
+            permissions: None,
+            atime_mtime: None,
+        }
+    }
+}
+
+/// An entry returned from `SftpFilesystem::readdir`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry within its directory
+    pub name: String,
+    pub attributes: FileAttributes,
+}
+
+/// The storage behind an SFTP server; see
+/// [Channel::serve_sftp](struct.Channel.html#method.serve_sftp).
+///
+/// Paths are passed through exactly as the client sent them; they
+/// may be relative and may contain `.` and `..` components.
+/// [normalize_sftp_path](fn.normalize_sftp_path.html) can be used to
+/// resolve them into absolute paths.
+///
+/// Errors are reported to the client as the SFTP status that most
+/// closely corresponds to their `ErrorKind`, with the error text as
+/// the message.  Methods that have default implementations report
+/// that the operation is unsupported.
+pub trait SftpFilesystem: Send {
+    /// The state for a file opened via `open`
+    type File: Send;
+    /// The state for a directory opened via `opendir`
+    type Dir: Send;
+
+    /// Open the file at `path`.
+    /// `attributes` holds the attributes requested by the client
+    /// for the file, should it be created.
+    fn open(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<Self::File>;
+
+    /// Close a file that was previously opened via `open`
+    fn close(&mut self, file: Self::File) -> std::io::Result<()> {
+        drop(file);
+        Ok(())
+    }
+
+    /// Read up to `buf.len()` bytes from `offset`.
+    /// Returns 0 when `offset` is at or beyond the end of the file.
+    fn read(
+        &mut self,
+        file: &mut Self::File,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize>;
+
+    /// Write all of `data` at `offset`
+    fn write(&mut self, file: &mut Self::File, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Retrieve the attributes of an open file
+    fn fstat(&mut self, file: &mut Self::File) -> std::io::Result<FileAttributes>;
+
+    /// Change the attributes of an open file
+    fn fsetstat(
+        &mut self,
+        _file: &mut Self::File,
+        _attributes: &SetAttributes,
+    ) -> std::io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Retrieve the attributes of `path`, following symlinks
+    fn stat(&mut self, path: &str) -> std::io::Result<FileAttributes>;
+
+    /// Retrieve the attributes of `path` without following a symlink
+    fn lstat(&mut self, path: &str) -> std::io::Result<FileAttributes> {
+        self.stat(path)
+    }
+
+    /// Change the attributes of `path`
+    fn setstat(&mut self, _path: &str, _attributes: &SetAttributes) -> std::io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Open the directory at `path` so that its entries can be read
+    fn opendir(&mut self, path: &str) -> std::io::Result<Self::Dir>;
+
+    /// Return the next batch of entries from the directory.
+    /// An empty batch indicates that there are no more entries.
+    fn readdir(&mut self, dir: &mut Self::Dir) -> std::io::Result<Vec<DirEntry>>;
+
+    /// Close a directory that was previously opened via `opendir`
+    fn closedir(&mut self, dir: Self::Dir) -> std::io::Result<()> {
+        drop(dir);
+        Ok(())
+    }
+
+    /// Rename `from` to `to`.  This should fail if `to` already exists.
+    fn rename(&mut self, from: &str, to: &str) -> std::io::Result<()>;
+
+    /// Remove the file at `path`
+    fn remove(&mut self, path: &str) -> std::io::Result<()>;
+
+    /// Create a directory at `path`
+    fn mkdir(&mut self, path: &str, attributes: &SetAttributes) -> std::io::Result<()>;
+
+    /// Remove the empty directory at `path`
+    fn rmdir(&mut self, path: &str) -> std::io::Result<()>;
+
+    /// Create a symlink at `link` that points to `target`
+    fn symlink(&mut self, _target: &str, _link: &str) -> std::io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Return the target of the symlink at `path`
+    fn readlink(&mut self, _path: &str) -> std::io::Result<String> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Return the canonical absolute form of `path`.
+    /// Clients use this to resolve `"."` into their initial
+    /// working directory.
+    fn realpath(&mut self, path: &str) -> std::io::Result<String>;
+}
+
+/// Lexically resolves an SFTP path into an absolute path that has
+/// no `.` or `..` components.  Relative paths are taken to be relative
+/// to `/`, and `..` components never ascend above `/`.
+pub fn normalize_sftp_path(path: &str) -> String {
+    let mut components: Vec<&str> = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+impl Channel {
+    /// Serve the SFTP protocol on this channel, dispatching the
+    /// requests made by the client to `filesystem`.
+    /// This is typically used after accepting a `"sftp"` subsystem
+    /// request on a server-side channel.
+    ///
+    /// This method blocks until the client closes the channel.
+    pub fn serve_sftp<F: SftpFilesystem>(&self, filesystem: F) -> SshResult<()> {
+        SftpServer::new(self, filesystem)?.run()
+    }
+}
+
+enum Handle<F: SftpFilesystem> {
+    File(F::File),
+    Dir(F::Dir),
+}
+
+struct SftpServer<'a, F: SftpFilesystem> {
+    channel: &'a Channel,
+    sftp: sys::sftp_session,
+    fs: F,
+    handles: HashMap<u32, Handle<F>>,
+    next_handle: u32,
+}
+
+impl<'a, F: SftpFilesystem> Drop for SftpServer<'a, F> {
+    fn drop(&mut self) {
+        let (_sess, _chan) = self.channel.lock_session();
+        unsafe {
+            // sftp_free would otherwise also free the channel,
+            // which belongs to self.channel
+            (*self.sftp).channel = std::ptr::null_mut();
+            sys::sftp_free(self.sftp);
+        }
+    }
+}
+
+impl<'a, F: SftpFilesystem> SftpServer<'a, F> {
+    fn new(channel: &'a Channel, fs: F) -> SshResult<Self> {
+        let sftp = {
+            let (sess, chan) = channel.lock_session();
+            let sftp = unsafe { sys::sftp_server_new(**sess, chan) };
+            if sftp.is_null() {
+                return Err(sess
+                    .last_error()
+                    .unwrap_or_else(|| Error::fatal("failed to allocate sftp session")));
+            }
+            sftp
+        };
+        let server = Self {
+            channel,
+            sftp,
+            fs,
+            handles: HashMap::new(),
+            next_handle: 0,
+        };
+
+        let (sess, _chan) = channel.lock_session();
+        let res = unsafe { sys::sftp_server_init(sftp) };
+        if res != 0 {
+            return Err(sess
+                .last_error()
+                .unwrap_or_else(|| Error::fatal("sftp_server_init failed")));
+        }
+        drop(sess);
+
+        Ok(server)
+    }
+
+    fn run(&mut self) -> SshResult<()> {
+        loop {
+            let msg = {
+                let (_sess, _chan) = self.channel.lock_session();
+                unsafe { sys::sftp_get_client_message(self.sftp) }
+            };
+            if msg.is_null() {
+                if self.channel.is_eof() || self.channel.is_closed() {
+                    return Ok(());
+                }
+                let (sess, _chan) = self.channel.lock_session();
+                return Err(sess
+                    .last_error()
+                    .unwrap_or_else(|| Error::fatal("sftp_get_client_message failed")));
+            }
+            let msg = ClientMessage { msg };
+
+            let reply = self.dispatch(&msg);
+            let res = self.reply(&msg, reply);
+
+            if res != 0 {
+                let (sess, _chan) = self.channel.lock_session();
+                return Err(sess
+                    .last_error()
+                    .unwrap_or_else(|| Error::fatal("failed to send sftp reply")));
+            }
+        }
+    }
+
+    fn dispatch(&mut self, msg: &ClientMessage) -> std::io::Result<Reply> {
+        match msg.msg().type_ as u32 {
+            sys::SSH_FXP_OPEN => {
+                let flags = OpenFlags::from_bits_truncate(msg.msg().flags);
+                let file = self
+                    .fs
+                    .open(msg.filename()?, flags, &msg.set_attributes())?;
+                Ok(Reply::Handle(self.alloc_handle(Handle::File(file))))
+            }
+            sys::SSH_FXP_OPENDIR => {
+                let dir = self.fs.opendir(msg.filename()?)?;
+                Ok(Reply::Handle(self.alloc_handle(Handle::Dir(dir))))
+            }
+            sys::SSH_FXP_CLOSE => {
+                let id = msg.handle()?;
+                match self.handles.remove(&id) {
+                    Some(Handle::File(file)) => self.fs.close(file)?,
+                    Some(Handle::Dir(dir)) => self.fs.closedir(dir)?,
+                    None => return Err(invalid_handle()),
+                }
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_READ => {
+                let offset = msg.msg().offset;
+                let len = (msg.msg().len as usize).min(MAX_READ_LEN);
+                let file = file_handle(&mut self.handles, msg)?;
+                let mut buf = vec![0u8; len];
+                let n = self.fs.read(file, offset, &mut buf)?;
+                if n == 0 && len > 0 {
+                    return Ok(Reply::Eof);
+                }
+                buf.truncate(n);
+                Ok(Reply::Data(buf))
+            }
+            sys::SSH_FXP_WRITE => {
+                let offset = msg.msg().offset;
+                let file = file_handle(&mut self.handles, msg)?;
+                self.fs.write(file, offset, msg.data())?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_FSTAT => {
+                let file = file_handle(&mut self.handles, msg)?;
+                Ok(Reply::Attributes(self.fs.fstat(file)?))
+            }
+            sys::SSH_FXP_FSETSTAT => {
+                let attributes = msg.set_attributes();
+                let file = file_handle(&mut self.handles, msg)?;
+                self.fs.fsetstat(file, &attributes)?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_READDIR => {
+                let id = msg.handle()?;
+                let dir = match self.handles.get_mut(&id) {
+                    Some(Handle::Dir(dir)) => dir,
+                    _ => return Err(invalid_handle()),
+                };
+                let entries = self.fs.readdir(dir)?;
+                if entries.is_empty() {
+                    Ok(Reply::Eof)
+                } else {
+                    Ok(Reply::Names(entries))
+                }
+            }
+            sys::SSH_FXP_STAT => Ok(Reply::Attributes(self.fs.stat(msg.filename()?)?)),
+            sys::SSH_FXP_LSTAT => Ok(Reply::Attributes(self.fs.lstat(msg.filename()?)?)),
+            sys::SSH_FXP_SETSTAT => {
+                self.fs.setstat(msg.filename()?, &msg.set_attributes())?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_REMOVE => {
+                self.fs.remove(msg.filename()?)?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_MKDIR => {
+                self.fs.mkdir(msg.filename()?, &msg.set_attributes())?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_RMDIR => {
+                self.fs.rmdir(msg.filename()?)?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_RENAME => {
+                self.fs.rename(msg.filename()?, msg.str_data()?)?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_SYMLINK => {
+                // OpenSSH, and libssh when talking to it, sends the
+                // target first, which is the reverse of the order
+                // given in the draft specification
+                self.fs.symlink(msg.filename()?, msg.str_data()?)?;
+                Ok(Reply::Ok)
+            }
+            sys::SSH_FXP_READLINK => Ok(Reply::Name(self.fs.readlink(msg.filename()?)?)),
+            sys::SSH_FXP_REALPATH => Ok(Reply::Name(self.fs.realpath(msg.filename()?)?)),
+            _ => Err(ErrorKind::Unsupported.into()),
+        }
+    }
+
+    fn alloc_handle(&mut self, handle: Handle<F>) -> u32 {
+        while self.handles.contains_key(&self.next_handle) {
+            self.next_handle = self.next_handle.wrapping_add(1);
+        }
+        let id = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(id, handle);
+        id
+    }
+
+    fn reply(&self, msg: &ClientMessage, reply: std::io::Result<Reply>) -> c_int {
+        let _sess = self.channel.lock_session();
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => return msg.reply_status(status_code(&err), &err.to_string()),
+        };
+        match reply {
+            Reply::Ok => msg.reply_status(sys::SSH_FX_OK, "Success"),
+            Reply::Eof => msg.reply_status(sys::SSH_FX_EOF, "End of file"),
+            Reply::Handle(id) => unsafe {
+                let bytes = id.to_be_bytes();
+                let handle = sys::ssh_string_new(bytes.len());
+                if handle.is_null() {
+                    return sys::SSH_ERROR;
+                }
+                sys::ssh_string_fill(handle, bytes.as_ptr() as _, bytes.len());
+                let res = sys::sftp_reply_handle(msg.msg, handle);
+                sys::ssh_string_free(handle);
+                res
+            },
+            Reply::Data(data) => unsafe {
+                sys::sftp_reply_data(msg.msg, data.as_ptr() as _, data.len() as c_int)
+            },
+            Reply::Attributes(attributes) => {
+                let mut attr = sys_attributes(&attributes);
+                unsafe { sys::sftp_reply_attr(msg.msg, &mut attr) }
+            }
+            Reply::Name(name) => {
+                let name = match CString::new(name) {
+                    Ok(name) => name,
+                    Err(err) => return msg.reply_status(sys::SSH_FX_FAILURE, &err.to_string()),
+                };
+                let mut attr: sys::sftp_attributes_struct = unsafe { std::mem::zeroed() };
+                unsafe { sys::sftp_reply_name(msg.msg, name.as_ptr(), &mut attr) }
+            }
+            Reply::Names(entries) => {
+                for entry in entries {
+                    let (name, long_name) = match (
+                        CString::new(entry.name.as_str()),
+                        CString::new(long_name(&entry)),
+                    ) {
+                        (Ok(name), Ok(long_name)) => (name, long_name),
+                        _ => continue,
+                    };
+                    let mut attr = sys_attributes(&entry.attributes);
+                    let res = unsafe {
+                        sys::sftp_reply_names_add(
+                            msg.msg,
+                            name.as_ptr(),
+                            long_name.as_ptr(),
+                            &mut attr,
+                        )
+                    };
+                    if res != 0 {
+                        return res;
+                    }
+                }
+                unsafe { sys::sftp_reply_names(msg.msg) }
+            }
+        }
+    }
+}
+
+fn file_handle<'h, F: SftpFilesystem>(
+    handles: &'h mut HashMap<u32, Handle<F>>,
+    msg: &ClientMessage,
+) -> std::io::Result<&'h mut F::File> {
+    match handles.get_mut(&msg.handle()?) {
+        Some(Handle::File(file)) => Ok(file),
+        _ => Err(invalid_handle()),
+    }
+}
+
+enum Reply {
+    Ok,
+    Eof,
+    Handle(u32),
+    Data(Vec<u8>),
+    Attributes(FileAttributes),
+    Name(String),
+    Names(Vec<DirEntry>),
+}
+
+struct ClientMessage {
+    msg: sys::sftp_client_message,
+}
+
+impl Drop for ClientMessage {
+    fn drop(&mut self) {
+        unsafe { sys::sftp_client_message_free(self.msg) }
+    }
+}
+
+impl ClientMessage {
+    fn msg(&self) -> &sys::sftp_client_message_struct {
+        unsafe { &*self.msg }
+    }
+
+    fn c_str(&self, s: *const c_char) -> std::io::Result<&str> {
+        if s.is_null() {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "missing path"));
+        }
+        unsafe { CStr::from_ptr(s) }
+            .to_str()
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn filename(&self) -> std::io::Result<&str> {
+        self.c_str(self.msg().filename)
+    }
+
+    /// The second path of a rename or symlink request
+    fn str_data(&self) -> std::io::Result<&str> {
+        self.c_str(unsafe { sys::sftp_client_message_get_data(self.msg) })
+    }
+
+    fn data(&self) -> &[u8] {
+        ssh_string_bytes(self.msg().data)
+    }
+
+    fn handle(&self) -> std::io::Result<u32> {
+        let bytes: [u8; 4] = ssh_string_bytes(self.msg().handle)
+            .try_into()
+            .map_err(|_| invalid_handle())?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn set_attributes(&self) -> SetAttributes {
+        let mut result = SetAttributes {
+            size: None,
+            uid_gid: None,
+            permissions: None,
+            atime_mtime: None,
+        };
+        if self.msg().attr.is_null() {
+            return result;
+        }
+        let attr = unsafe { &*self.msg().attr };
+        if attr.flags & sys::SSH_FILEXFER_ATTR_SIZE != 0 {
+            result.size = Some(attr.size);
+        }
+        if attr.flags & sys::SSH_FILEXFER_ATTR_UIDGID != 0 {
+            result.uid_gid = Some((attr.uid, attr.gid));
+        }
+        if attr.flags & sys::SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            result.permissions = Some(attr.permissions & 0o7777);
+        }
+        if attr.flags & sys::SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            result.atime_mtime = Some((
+                SystemTime::UNIX_EPOCH + Duration::from_secs(attr.atime.into()),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(attr.mtime.into()),
+            ));
+        }
+        result
+    }
+
+    fn reply_status(&self, status: u32, message: &str) -> c_int {
+        let message =
+            CString::new(message.replace('\0', "")).expect("nul bytes to have been removed");
+        unsafe { sys::sftp_reply_status(self.msg, status, message.as_ptr()) }
+    }
+}
+
+fn ssh_string_bytes<'a>(s: sys::ssh_string) -> &'a [u8] {
+    if s.is_null() {
+        return &[];
+    }
+    unsafe {
+        std::slice::from_raw_parts(sys::ssh_string_data(s) as *const u8, sys::ssh_string_len(s))
+    }
+}
+
+fn invalid_handle() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, "invalid handle")
+}
+
+/// Maps an error to the closest status code defined by version 3
+/// of the protocol, which is the version that libssh implements
+fn status_code(err: &std::io::Error) -> u32 {
+    match err.kind() {
+        ErrorKind::NotFound => sys::SSH_FX_NO_SUCH_FILE,
+        ErrorKind::PermissionDenied => sys::SSH_FX_PERMISSION_DENIED,
+        ErrorKind::UnexpectedEof => sys::SSH_FX_EOF,
+        ErrorKind::InvalidData => sys::SSH_FX_BAD_MESSAGE,
+        ErrorKind::Unsupported => sys::SSH_FX_OP_UNSUPPORTED,
+        _ => sys::SSH_FX_FAILURE,
+    }
+}
+
+fn type_bits(file_type: FileType) -> u32 {
+    match file_type {
+        FileType::Regular => 0o100000,
+        FileType::Directory => 0o040000,
+        FileType::Symlink => 0o120000,
+        FileType::Special | FileType::Unknown => 0,
+    }
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn sys_attributes(attributes: &FileAttributes) -> sys::sftp_attributes_struct {
+    let mut attr: sys::sftp_attributes_struct = unsafe { std::mem::zeroed() };
+
+    attr.type_ = match attributes.file_type {
+        FileType::Regular => sys::SSH_FILEXFER_TYPE_REGULAR,
+        FileType::Directory => sys::SSH_FILEXFER_TYPE_DIRECTORY,
+        FileType::Symlink => sys::SSH_FILEXFER_TYPE_SYMLINK,
+        FileType::Special => sys::SSH_FILEXFER_TYPE_SPECIAL,
+        FileType::Unknown => sys::SSH_FILEXFER_TYPE_UNKNOWN,
+    } as u8;
+
+    if let Some(size) = attributes.size {
+        attr.size = size;
+        attr.flags |= sys::SSH_FILEXFER_ATTR_SIZE;
+    }
+
+    if let Some((uid, gid)) = attributes.uid_gid {
+        attr.uid = uid;
+        attr.gid = gid;
+        attr.flags |= sys::SSH_FILEXFER_ATTR_UIDGID;
+    }
+
+    if let Some(perms) = attributes.permissions {
+        attr.permissions = (perms & 0o7777) | type_bits(attributes.file_type);
+        attr.flags |= sys::SSH_FILEXFER_ATTR_PERMISSIONS;
+    }
+
+    if let Some((atime, mtime)) = attributes.atime_mtime {
+        attr.atime64 = unix_time(atime);
+        attr.atime = attr.atime64.try_into().unwrap_or(u32::MAX);
+        attr.mtime64 = unix_time(mtime);
+        attr.mtime = attr.mtime64.try_into().unwrap_or(u32::MAX);
+        attr.flags |= sys::SSH_FILEXFER_ATTR_ACMODTIME;
+    }
+
+    attr
+}
+
+/// Produces an `ls -l` style description of a directory entry,
+/// which some clients display verbatim
+fn long_name(entry: &DirEntry) -> String {
+    let attributes = &entry.attributes;
+    let mut mode = String::with_capacity(10);
+    mode.push(match attributes.file_type {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::Regular => '-',
+        FileType::Special | FileType::Unknown => '?',
+    });
+    let perms = attributes.permissions.unwrap_or(0);
+    for shift in [6, 3, 0].iter() {
+        let bits = (perms >> shift) & 0o7;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    let (uid, gid) = attributes.uid_gid.unwrap_or((0, 0));
+    format!(
+        "{} 1 {:<8} {:<8} {:>8} {}",
+        mode,
+        uid,
+        gid,
+        attributes.size.unwrap_or(0),
+        entry.name
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn no_attributes() -> SetAttributes {
+        SetAttributes {
+            size: None,
+            uid_gid: None,
+            permissions: None,
+            atime_mtime: None,
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_sftp_path(""), "/");
+        assert_eq!(normalize_sftp_path("."), "/");
+        assert_eq!(normalize_sftp_path("foo/./bar//baz/"), "/foo/bar/baz");
+        assert_eq!(normalize_sftp_path("/foo/../../bar"), "/bar");
+        assert_eq!(normalize_sftp_path("../../.."), "/");
+    }
+
+    #[test]
+    fn long_names() {
+        let mut attributes = FileAttributes::new(FileType::Directory);
+        attributes.permissions = Some(0o755);
+        attributes.size = Some(4096);
+        let entry = DirEntry {
+            name: "src".to_string(),
+            attributes,
+        };
+        assert_eq!(
+            long_name(&entry),
+            "drwxr-xr-x 1 0        0            4096 src"
+        );
+    }
+
+    #[test]
+    fn memory_filesystem() {
+        let mut fs = MemoryFilesystem::new();
+        fs.create_dir_all("/a/b").unwrap();
+        fs.write_file("/a/b/file", b"hello").unwrap();
+
+        fs.rename("/a", "/c").unwrap();
+        assert_eq!(fs.read_file("/c/b/file").unwrap(), b"hello");
+        assert_eq!(
+            fs.stat("/a/b/file").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        let mut dir = fs.opendir("/c/b").unwrap();
+        let entries = fs.readdir(&mut dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "file");
+        assert_eq!(entries[0].attributes.size, Some(5));
+        assert!(fs.readdir(&mut dir).unwrap().is_empty());
+
+        assert!(fs.rmdir("/c/b").is_err());
+    }
+
+    #[test]
+    fn memory_file_size() {
+        let mut fs = MemoryFilesystem::new().with_max_file_size(16);
+        fs.write_file("/file", b"hello").unwrap();
+        let mut file = fs
+            .open("/file", OpenFlags::WRITE, &no_attributes())
+            .unwrap();
+        fs.write(&mut file, 11, b"world").unwrap();
+        assert_eq!(
+            fs.write(&mut file, 12, b"world").unwrap_err().kind(),
+            ErrorKind::OutOfMemory
+        );
+        assert!(fs.write(&mut file, u64::MAX, b"world").is_err());
+
+        let mut truncate = no_attributes();
+        truncate.size = Some(u64::MAX);
+        assert!(fs.fsetstat(&mut file, &truncate).is_err());
+        truncate.size = Some(2);
+        fs.setstat("/file", &truncate).unwrap();
+        assert_eq!(fs.read_file("/file").unwrap(), b"he");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_symlinks() {
+        let dir = std::env::temp_dir().join(format!("libssh-rs-local-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(dir.join("secret"), b"secret").unwrap();
+        std::fs::write(root.join("file"), b"file").unwrap();
+
+        let mut fs = LocalFilesystem::new(&root);
+        let denied = |result: std::io::Result<FileAttributes>| {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied)
+        };
+        for (target, link) in [
+            ("..", "/esc"),
+            ("../../..", "/sub/esc"),
+            ("sub/../..", "/esc"),
+        ] {
+            assert_eq!(
+                fs.symlink(target, link).unwrap_err().kind(),
+                ErrorKind::PermissionDenied
+            );
+        }
+        fs.symlink("../file", "/sub/up").unwrap();
+        fs.symlink("/sub", "/abs").unwrap();
+        assert_eq!(fs.stat("/sub/up").unwrap().size, Some(4));
+        assert_eq!(fs.readlink("/abs").unwrap(), "/sub");
+        assert!(fs.stat("/abs/up").is_ok());
+
+        // Links that were created by other means are not followed
+        // outside of the root
+        std::os::unix::fs::symlink(&dir, root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing"), root.join("dangling")).unwrap();
+        denied(fs.stat("/escape/secret"));
+        denied(fs.stat("/escape"));
+        assert!(fs.lstat("/escape").is_ok());
+        assert_eq!(
+            fs.open(
+                "/dangling",
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                &no_attributes()
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert!(!dir.join("missing").exists());
+        fs.remove("/escape").unwrap();
+        assert!(dir.join("secret").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+use super::{normalize_sftp_path, DirEntry, FileAttributes, OpenFlags, SftpFilesystem};
+use crate::{FileType, SetAttributes};
+use std::fs::{File, Metadata, OpenOptions, ReadDir};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The number of entries returned by each `readdir` call
+const READDIR_BATCH: usize = 128;
+
+/// An `SftpFilesystem` that serves a directory on the local disk.
+///
+/// The directory is presented to the client as `/`, and the client
+/// cannot name anything outside of it: paths are resolved lexically,
+/// and any path that reaches outside of the directory through a
+/// symlink is refused with a permission error.
+pub struct LocalFilesystem {
+    root: PathBuf,
+}
+
+impl LocalFilesystem {
+    /// Create a filesystem rooted at `root`
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Resolves the client's `path` to a location on the local disk.
+    /// If `follow` is true a symlink in the final component is followed,
+    /// otherwise only those in the parent directories are.
+    /// Fails if any of the symlinks that are followed lead outside
+    /// of the root.
+    fn resolve(&self, path: &str, follow: bool) -> std::io::Result<PathBuf> {
+        let path = normalize_sftp_path(path);
+        let root = self.root.canonicalize()?;
+        let local = self.root.join(&path[1..]);
+        let (parent, name) = match (local.parent(), local.file_name()) {
+            (Some(parent), Some(name)) if path != "/" => (parent, name),
+            _ => return Ok(root),
+        };
+        if follow {
+            match local.canonicalize() {
+                Ok(real) => return within(&root, real),
+                // A dangling symlink, which could otherwise be used to
+                // create a file wherever it points
+                Err(_) if std::fs::symlink_metadata(&local).is_ok() => {
+                    return Err(ErrorKind::PermissionDenied.into());
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(within(&root, parent.canonicalize()?)?.join(name))
+    }
+
+    fn apply(
+        &self,
+        path: &Path,
+        file: Option<&File>,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<()> {
+        if let Some(size) = attributes.size {
+            match file {
+                Some(file) => file.set_len(size)?,
+                None => OpenOptions::new().write(true).open(path)?.set_len(size)?,
+            }
+        }
+        if let Some(permissions) = attributes.permissions {
+            set_permissions(path, file, permissions)?;
+        }
+        if let Some((uid, gid)) = attributes.uid_gid {
+            chown(path, uid, gid)?;
+        }
+        if let Some((atime, mtime)) = attributes.atime_mtime {
+            set_times(path, atime, mtime)?;
+        }
+        Ok(())
+    }
+}
+
+impl SftpFilesystem for LocalFilesystem {
+    type File = (File, PathBuf);
+    type Dir = ReadDir;
+
+    fn open(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<Self::File> {
+        let path = self.resolve(path, true)?;
+        let mut options = OpenOptions::new();
+        options
+            .read(flags.contains(OpenFlags::READ))
+            .write(flags.contains(OpenFlags::WRITE))
+            .append(flags.contains(OpenFlags::APPEND))
+            .truncate(flags.contains(OpenFlags::TRUNCATE));
+        if flags.contains(OpenFlags::CREATE) {
+            if flags.contains(OpenFlags::EXCLUSIVE) {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options
+                .mode(attributes.permissions.unwrap_or(0o666))
+                .custom_flags(libc::O_NOFOLLOW);
+        }
+        #[cfg(not(unix))]
+        let _ = attributes;
+        let file = options.open(&path)?;
+        Ok((file, path))
+    }
+
+    fn read(
+        &mut self,
+        (file, _path): &mut Self::File,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileExt;
+            file.read_at(buf, offset)
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::FileExt;
+            file.seek_read(buf, offset)
+        }
+    }
+
+    fn write(
+        &mut self,
+        (file, _path): &mut Self::File,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileExt;
+            file.write_all_at(data, offset)
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::FileExt;
+            let mut data = data;
+            let mut offset = offset;
+            while !data.is_empty() {
+                let n = file.seek_write(data, offset)?;
+                if n == 0 {
+                    return Err(ErrorKind::WriteZero.into());
+                }
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Ok(())
+        }
+    }
+
+    fn fstat(&mut self, (file, _path): &mut Self::File) -> std::io::Result<FileAttributes> {
+        Ok(attributes(&file.metadata()?))
+    }
+
+    fn fsetstat(
+        &mut self,
+        (file, path): &mut Self::File,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<()> {
+        self.apply(path, Some(file), attributes)
+    }
+
+    fn stat(&mut self, path: &str) -> std::io::Result<FileAttributes> {
+        Ok(attributes(&std::fs::metadata(self.resolve(path, true)?)?))
+    }
+
+    fn lstat(&mut self, path: &str) -> std::io::Result<FileAttributes> {
+        Ok(attributes(&std::fs::symlink_metadata(
+            self.resolve(path, false)?,
+        )?))
+    }
+
+    fn setstat(&mut self, path: &str, attributes: &SetAttributes) -> std::io::Result<()> {
+        let path = self.resolve(path, true)?;
+        self.apply(&path, None, attributes)
+    }
+
+    fn opendir(&mut self, path: &str) -> std::io::Result<Self::Dir> {
+        std::fs::read_dir(self.resolve(path, true)?)
+    }
+
+    fn readdir(&mut self, dir: &mut Self::Dir) -> std::io::Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for entry in dir.take(READDIR_BATCH) {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "non-UTF-8 filename"))?;
+            entries.push(DirEntry {
+                name,
+                attributes: attributes(&entry.metadata()?),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> std::io::Result<()> {
+        let to = self.resolve(to, false)?;
+        if std::fs::symlink_metadata(&to).is_ok() {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        std::fs::rename(self.resolve(from, false)?, to)
+    }
+
+    fn remove(&mut self, path: &str) -> std::io::Result<()> {
+        std::fs::remove_file(self.resolve(path, false)?)
+    }
+
+    fn mkdir(&mut self, path: &str, attributes: &SetAttributes) -> std::io::Result<()> {
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(attributes.permissions.unwrap_or(0o777));
+        }
+        #[cfg(not(unix))]
+        let _ = attributes;
+        builder.create(self.resolve(path, false)?)
+    }
+
+    fn rmdir(&mut self, path: &str) -> std::io::Result<()> {
+        std::fs::remove_dir(self.resolve(path, false)?)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, target: &str, link: &str) -> std::io::Result<()> {
+        // Absolute targets are relative to our root, so that the
+        // link resolves the same way for the client as it does here
+        let target = if target.starts_with('/') {
+            self.root.join(&normalize_sftp_path(target)[1..])
+        } else if escapes_root(&normalize_sftp_path(link), target) {
+            return Err(ErrorKind::PermissionDenied.into());
+        } else {
+            PathBuf::from(target)
+        };
+        std::os::unix::fs::symlink(target, self.resolve(link, false)?)
+    }
+
+    fn readlink(&mut self, path: &str) -> std::io::Result<String> {
+        let target = std::fs::read_link(self.resolve(path, false)?)?;
+        let target = match target.strip_prefix(&self.root) {
+            Ok(relative) if target.is_absolute() => Path::new("/").join(relative),
+            _ => target,
+        };
+        target
+            .into_os_string()
+            .into_string()
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "non-UTF-8 link target"))
+    }
+
+    fn realpath(&mut self, path: &str) -> std::io::Result<String> {
+        Ok(normalize_sftp_path(path))
+    }
+}
+
+/// Checks that the canonical path `real` is inside of `root`
+fn within(root: &Path, real: PathBuf) -> std::io::Result<PathBuf> {
+    if real.starts_with(root) {
+        Ok(real)
+    } else {
+        Err(ErrorKind::PermissionDenied.into())
+    }
+}
+
+/// Returns true if the relative symlink `target`, when placed at the
+/// normalized path `link`, refers to something above the root
+fn escapes_root(link: &str, target: &str) -> bool {
+    // The number of directories below the root that `link` is in
+    let mut depth = link.matches('/').count() as isize - 1;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                depth -= 1;
+                if depth < 0 {
+                    return true;
+                }
+            }
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+fn attributes(metadata: &Metadata) -> FileAttributes {
+    let file_type = metadata.file_type();
+    let mut attributes = FileAttributes::new(if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_file() {
+        FileType::Regular
+    } else {
+        FileType::Special
+    });
+    attributes.size = Some(metadata.len());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        attributes.permissions = Some(metadata.mode() & 0o7777);
+        attributes.uid_gid = Some((metadata.uid(), metadata.gid()));
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o666
+        };
+        if metadata.is_dir() {
+            permissions |= 0o111;
+        }
+        attributes.permissions = Some(permissions);
+    }
+
+    if let (Ok(atime), Ok(mtime)) = (metadata.accessed(), metadata.modified()) {
+        attributes.atime_mtime = Some((atime, mtime));
+    }
+    attributes
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, file: Option<&File>, permissions: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let permissions = std::fs::Permissions::from_mode(permissions);
+    match file {
+        Some(file) => file.set_permissions(permissions),
+        None => std::fs::set_permissions(path, permissions),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_permissions(path: &Path, file: Option<&File>, permissions: u32) -> std::io::Result<()> {
+    let mut perms = match file {
+        Some(file) => file.metadata()?.permissions(),
+        None => std::fs::metadata(path)?.permissions(),
+    };
+    perms.set_readonly(permissions & 0o222 == 0);
+    match file {
+        Some(file) => file.set_permissions(perms),
+        None => std::fs::set_permissions(path, perms),
+    }
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> std::io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+#[cfg(unix)]
+fn chown(path: &Path, uid: u32, gid: u32) -> std::io::Result<()> {
+    let path = c_path(path)?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path, _uid: u32, _gid: u32) -> std::io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn set_times(
+    path: &Path,
+    atime: std::time::SystemTime,
+    mtime: std::time::SystemTime,
+) -> std::io::Result<()> {
+    fn timeval(t: std::time::SystemTime) -> libc::timeval {
+        let secs = t
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        libc::timeval {
+            tv_sec: secs as libc::time_t,
+            tv_usec: 0,
+        }
+    }
+    let path = c_path(path)?;
+    let times = [timeval(atime), timeval(mtime)];
+    if unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_times(
+    _path: &Path,
+    _atime: std::time::SystemTime,
+    _mtime: std::time::SystemTime,
+) -> std::io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
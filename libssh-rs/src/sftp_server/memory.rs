@@ -0,0 +1,523 @@
+use super::{normalize_sftp_path, DirEntry, FileAttributes, OpenFlags, SftpFilesystem};
+use crate::{FileType, SetAttributes};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The maximum number of symlinks that will be traversed when
+/// resolving a path
+const MAX_SYMLINK_DEPTH: usize = 32;
+
+/// The default limit on the size of each file, which stops a client
+/// from exhausting the memory of the server
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// An `SftpFilesystem` that is held entirely in memory.
+///
+/// Clones of a `MemoryFilesystem` share the same contents, so
+/// one clone can be passed to
+/// [Channel::serve_sftp](struct.Channel.html#method.serve_sftp)
+/// while another is used to prepare or inspect the files,
+/// which makes it a convenient hermetic target for tests.
+///
+/// Symlinks are followed only when they are the final component
+/// of a path.
+///
+/// Writes and size changes that would make a file larger than
+/// 1GiB fail; the limit can be changed with
+/// [with_max_file_size](#method.with_max_file_size).
+#[derive(Clone)]
+pub struct MemoryFilesystem {
+    nodes: Arc<Mutex<BTreeMap<String, Node>>>,
+    max_file_size: u64,
+}
+
+/// A file opened on a `MemoryFilesystem`
+pub struct MemoryFile {
+    contents: Arc<Mutex<FileContents>>,
+    flags: OpenFlags,
+}
+
+/// A directory opened on a `MemoryFilesystem`
+pub struct MemoryDir {
+    entries: Vec<DirEntry>,
+}
+
+struct FileContents {
+    data: Vec<u8>,
+    permissions: u32,
+    mtime: SystemTime,
+}
+
+enum Node {
+    File(Arc<Mutex<FileContents>>),
+    Directory { permissions: u32, mtime: SystemTime },
+    Symlink { target: String, mtime: SystemTime },
+}
+
+impl Node {
+    fn attributes(&self) -> FileAttributes {
+        let (file_type, size, permissions, mtime) = match self {
+            Node::File(contents) => {
+                let contents = contents.lock().unwrap();
+                (
+                    FileType::Regular,
+                    contents.data.len() as u64,
+                    contents.permissions,
+                    contents.mtime,
+                )
+            }
+            Node::Directory { permissions, mtime } => {
+                (FileType::Directory, 0, *permissions, *mtime)
+            }
+            Node::Symlink { target, mtime } => {
+                (FileType::Symlink, target.len() as u64, 0o777, *mtime)
+            }
+        };
+        let mut attributes = FileAttributes::new(file_type);
+        attributes.size = Some(size);
+        attributes.permissions = Some(permissions);
+        attributes.atime_mtime = Some((mtime, mtime));
+        attributes
+    }
+}
+
+impl Default for MemoryFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFilesystem {
+    /// Create a filesystem that holds only an empty root directory
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            "/".to_string(),
+            Node::Directory {
+                permissions: 0o755,
+                mtime: SystemTime::now(),
+            },
+        );
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        }
+    }
+
+    /// Limit the size of each file to `max_file_size` bytes.
+    /// The limit applies to this value and to clones that are
+    /// made from it afterwards.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Create or replace the file at `path` with the specified contents.
+    /// The parent directory must already exist.
+    pub fn write_file(&self, path: &str, data: &[u8]) -> std::io::Result<()> {
+        let mut fs = self.clone();
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mut file = fs.open(path, flags, &no_attributes())?;
+        fs.write(&mut file, 0, data)
+    }
+
+    /// Returns the contents of the file at `path`
+    pub fn read_file(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let nodes = self.nodes.lock().unwrap();
+        let path = resolve(&nodes, path)?;
+        match nodes.get(&path) {
+            Some(Node::File(contents)) => Ok(contents.lock().unwrap().data.clone()),
+            Some(_) => Err(std::io::Error::other("not a file")),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Create the directory at `path`, along with any missing parents
+    pub fn create_dir_all(&self, path: &str) -> std::io::Result<()> {
+        let path = normalize_sftp_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut current = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current.push('/');
+            current.push_str(component);
+            match nodes.get(&current) {
+                Some(Node::Directory { .. }) => {}
+                Some(_) => return Err(ErrorKind::AlreadyExists.into()),
+                None => {
+                    nodes.insert(
+                        current.clone(),
+                        Node::Directory {
+                            permissions: 0o755,
+                            mtime: SystemTime::now(),
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn no_attributes() -> SetAttributes {
+    SetAttributes {
+        size: None,
+        uid_gid: None,
+        permissions: None,
+        atime_mtime: None,
+    }
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(idx) => &path[..idx],
+    }
+}
+
+/// Returns the prefix shared by the paths of the children of `dir`
+fn child_prefix(dir: &str) -> String {
+    if dir == "/" {
+        dir.to_string()
+    } else {
+        format!("{}/", dir)
+    }
+}
+
+/// Returns the length of a file once `len` bytes are stored at
+/// `offset`, failing if that would exceed `max`
+fn checked_end(offset: u64, len: usize, max: u64) -> std::io::Result<usize> {
+    offset
+        .checked_add(len as u64)
+        .filter(|end| *end <= max)
+        .and_then(|end| usize::try_from(end).ok())
+        .ok_or_else(|| std::io::Error::new(ErrorKind::OutOfMemory, "file too large"))
+}
+
+fn has_children(nodes: &BTreeMap<String, Node>, dir: &str) -> bool {
+    let prefix = child_prefix(dir);
+    nodes
+        .range(prefix.clone()..)
+        .next()
+        .map(|(path, _)| path.starts_with(&prefix))
+        .unwrap_or(false)
+}
+
+/// Normalizes `path` and follows it if it names a symlink
+fn resolve(nodes: &BTreeMap<String, Node>, path: &str) -> std::io::Result<String> {
+    let mut path = normalize_sftp_path(path);
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match nodes.get(&path) {
+            Some(Node::Symlink { target, .. }) => {
+                path = if target.starts_with('/') {
+                    normalize_sftp_path(target)
+                } else {
+                    normalize_sftp_path(&format!("{}/{}", parent(&path), target))
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(std::io::Error::other("too many levels of symbolic links"))
+}
+
+/// Checks that `path` does not exist and that its parent is a directory
+fn check_creatable(nodes: &BTreeMap<String, Node>, path: &str) -> std::io::Result<()> {
+    if nodes.contains_key(path) {
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+    match nodes.get(parent(path)) {
+        Some(Node::Directory { .. }) => Ok(()),
+        Some(_) => Err(std::io::Error::other("not a directory")),
+        None => Err(ErrorKind::NotFound.into()),
+    }
+}
+
+fn apply(node: &mut Node, attributes: &SetAttributes, max_file_size: u64) -> std::io::Result<()> {
+    match node {
+        Node::File(contents) => {
+            let mut contents = contents.lock().unwrap();
+            if let Some(size) = attributes.size {
+                let size = checked_end(size, 0, max_file_size)?;
+                contents.data.resize(size, 0);
+            }
+            if let Some(permissions) = attributes.permissions {
+                contents.permissions = permissions & 0o7777;
+            }
+            if let Some((_atime, mtime)) = attributes.atime_mtime {
+                contents.mtime = mtime;
+            }
+        }
+        Node::Directory { permissions, mtime } => {
+            if let Some(p) = attributes.permissions {
+                *permissions = p & 0o7777;
+            }
+            if let Some((_atime, m)) = attributes.atime_mtime {
+                *mtime = m;
+            }
+        }
+        Node::Symlink { mtime, .. } => {
+            if let Some((_atime, m)) = attributes.atime_mtime {
+                *mtime = m;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl SftpFilesystem for MemoryFilesystem {
+    type File = MemoryFile;
+    type Dir = MemoryDir;
+
+    fn open(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<MemoryFile> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let path = resolve(&nodes, path)?;
+        let contents = match nodes.get(&path) {
+            Some(Node::File(contents)) => {
+                if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) {
+                    return Err(ErrorKind::AlreadyExists.into());
+                }
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    let mut locked = contents.lock().unwrap();
+                    locked.data.clear();
+                    locked.mtime = SystemTime::now();
+                }
+                Arc::clone(contents)
+            }
+            Some(_) => return Err(std::io::Error::other("is a directory")),
+            None if flags.contains(OpenFlags::CREATE) => {
+                check_creatable(&nodes, &path)?;
+                let contents = Arc::new(Mutex::new(FileContents {
+                    data: vec![],
+                    permissions: attributes.permissions.unwrap_or(0o644) & 0o7777,
+                    mtime: SystemTime::now(),
+                }));
+                nodes.insert(path, Node::File(Arc::clone(&contents)));
+                contents
+            }
+            None => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(MemoryFile { contents, flags })
+    }
+
+    fn read(
+        &mut self,
+        file: &mut MemoryFile,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let contents = file.contents.lock().unwrap();
+        let offset = match usize::try_from(offset) {
+            Ok(offset) if offset < contents.data.len() => offset,
+            _ => return Ok(0),
+        };
+        let n = buf.len().min(contents.data.len() - offset);
+        buf[..n].copy_from_slice(&contents.data[offset..offset + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, file: &mut MemoryFile, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let mut contents = file.contents.lock().unwrap();
+        let offset = if file.flags.contains(OpenFlags::APPEND) {
+            contents.data.len() as u64
+        } else {
+            offset
+        };
+        let end = checked_end(offset, data.len(), self.max_file_size)?;
+        let offset = end - data.len();
+        if end > contents.data.len() {
+            contents.data.resize(end, 0);
+        }
+        contents.data[offset..end].copy_from_slice(data);
+        contents.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    fn fstat(&mut self, file: &mut MemoryFile) -> std::io::Result<FileAttributes> {
+        Ok(Node::File(Arc::clone(&file.contents)).attributes())
+    }
+
+    fn fsetstat(
+        &mut self,
+        file: &mut MemoryFile,
+        attributes: &SetAttributes,
+    ) -> std::io::Result<()> {
+        apply(
+            &mut Node::File(Arc::clone(&file.contents)),
+            attributes,
+            self.max_file_size,
+        )
+    }
+
+    fn stat(&mut self, path: &str) -> std::io::Result<FileAttributes> {
+        let nodes = self.nodes.lock().unwrap();
+        let path = resolve(&nodes, path)?;
+        nodes
+            .get(&path)
+            .map(Node::attributes)
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn lstat(&mut self, path: &str) -> std::io::Result<FileAttributes> {
+        let nodes = self.nodes.lock().unwrap();
+        nodes
+            .get(&normalize_sftp_path(path))
+            .map(Node::attributes)
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn setstat(&mut self, path: &str, attributes: &SetAttributes) -> std::io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let path = resolve(&nodes, path)?;
+        let node = nodes.get_mut(&path).ok_or(ErrorKind::NotFound)?;
+        apply(node, attributes, self.max_file_size)
+    }
+
+    fn opendir(&mut self, path: &str) -> std::io::Result<MemoryDir> {
+        let nodes = self.nodes.lock().unwrap();
+        let path = resolve(&nodes, path)?;
+        match nodes.get(&path) {
+            Some(Node::Directory { .. }) => {}
+            Some(_) => return Err(std::io::Error::other("not a directory")),
+            None => return Err(ErrorKind::NotFound.into()),
+        }
+        let prefix = child_prefix(&path);
+        let entries = nodes
+            .range(prefix.clone()..)
+            .take_while(|(child, _)| child.starts_with(&prefix))
+            .filter_map(|(child, node)| {
+                let name = &child[prefix.len()..];
+                if name.is_empty() || name.contains('/') {
+                    None
+                } else {
+                    Some(DirEntry {
+                        name: name.to_string(),
+                        attributes: node.attributes(),
+                    })
+                }
+            })
+            .collect();
+        Ok(MemoryDir { entries })
+    }
+
+    fn readdir(&mut self, dir: &mut MemoryDir) -> std::io::Result<Vec<DirEntry>> {
+        Ok(std::mem::take(&mut dir.entries))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> std::io::Result<()> {
+        let from = normalize_sftp_path(from);
+        let to = normalize_sftp_path(to);
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(&from) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if from == "/" || to.starts_with(&child_prefix(&from)) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "cannot move a directory into itself",
+            ));
+        }
+        check_creatable(&nodes, &to)?;
+
+        let prefix = child_prefix(&from);
+        let descendants: Vec<String> = nodes
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in descendants {
+            let node = nodes.remove(&path).expect("path to exist");
+            nodes.insert(format!("{}{}", to, &path[from.len()..]), node);
+        }
+        let node = nodes.remove(&from).expect("path to exist");
+        nodes.insert(to, node);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> std::io::Result<()> {
+        let path = normalize_sftp_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Directory { .. }) => Err(std::io::Error::other("is a directory")),
+            Some(_) => {
+                nodes.remove(&path);
+                Ok(())
+            }
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn mkdir(&mut self, path: &str, attributes: &SetAttributes) -> std::io::Result<()> {
+        let path = normalize_sftp_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        check_creatable(&nodes, &path)?;
+        nodes.insert(
+            path,
+            Node::Directory {
+                permissions: attributes.permissions.unwrap_or(0o755) & 0o7777,
+                mtime: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> std::io::Result<()> {
+        let path = normalize_sftp_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Directory { .. }) if path == "/" => Err(ErrorKind::PermissionDenied.into()),
+            Some(Node::Directory { .. }) if has_children(&nodes, &path) => {
+                Err(std::io::Error::other("directory not empty"))
+            }
+            Some(Node::Directory { .. }) => {
+                nodes.remove(&path);
+                Ok(())
+            }
+            Some(_) => Err(std::io::Error::other("not a directory")),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn symlink(&mut self, target: &str, link: &str) -> std::io::Result<()> {
+        let link = normalize_sftp_path(link);
+        let mut nodes = self.nodes.lock().unwrap();
+        check_creatable(&nodes, &link)?;
+        nodes.insert(
+            link,
+            Node::Symlink {
+                target: target.to_string(),
+                mtime: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn readlink(&mut self, path: &str) -> std::io::Result<String> {
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&normalize_sftp_path(path)) {
+            Some(Node::Symlink { target, .. }) => Ok(target.clone()),
+            Some(_) => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "not a symlink",
+            )),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn realpath(&mut self, path: &str) -> std::io::Result<String> {
+        Ok(normalize_sftp_path(path))
+    }
+}